@@ -95,8 +95,9 @@ class VM {
         registers.stackPointer -= const Word(8);
         memory.data.setWord(registers.stackPointer, registers[reg]);
       case PopInstruction(:final reg):
-        registers[reg] = memory.data.getWord(registers.stackPointer);
+        final value = memory.data.getWord(registers.stackPointer);
         registers.stackPointer += const Word(8);
+        registers[reg] = value;
       case JumpInstruction(:final to):
         programCounter = to;
       case CjumpInstruction(:final to):
@@ -0,0 +1,193 @@
+use std::str::FromStr;
+
+// The parsed command line. All flags are parsed here, so a flag that takes a
+// value can't be mistaken for the path of the binary.
+#[derive(Debug, Default, PartialEq)]
+pub struct Args {
+    // The binary is read from stdin if there's no path or it's "-".
+    pub path: Option<String>,
+    // `soil diff old.soil new.soil` compares two binaries. The old one is
+    // path.
+    pub diff: bool,
+    pub new_path: Option<String>,
+    pub output: Option<String>,
+    pub map: Option<String>,
+    pub align_functions: Option<usize>,
+    pub memory_size: Option<usize>,
+    pub stack_size: Option<usize>,
+    pub function: Option<String>,
+    pub target: Option<String>,
+    pub emit: Option<String>,
+    pub safe: bool,
+    pub breakpoints: bool,
+    pub check: bool,
+    pub disasm: bool,
+    // Makes --disasm show the bytes of each instruction.
+    pub bytes: bool,
+    pub call_graph: bool,
+    #[cfg(feature = "run-fasm")]
+    pub run: bool,
+    #[cfg(feature = "run-fasm")]
+    pub check_backends: bool,
+
+    // Interpreter options.
+    pub interpret: bool,
+    pub profile: bool,
+    pub fuel: Option<u64>,
+    pub trace: bool,
+    pub trace_from: Option<String>,
+    pub track_memory: bool,
+    pub check_uninitialized_reads: bool,
+    pub dump_memory_on_exit: Option<String>,
+    // Everything after --interpret is passed to the program.
+    pub program_args: Vec<String>,
+}
+
+impl Args {
+    // Expects the arguments without the name of the executable.
+    pub fn parse(args: &[String]) -> Result<Args, String> {
+        let mut parsed = Args::default();
+        let mut args = args.iter().peekable();
+        if args.next_if(|arg| *arg == "diff").is_some() {
+            parsed.diff = true;
+        }
+        while let Some(arg) = args.next() {
+            let mut value = |description: &str| {
+                args.next()
+                    .cloned()
+                    .ok_or_else(|| format!("{} needs {}", arg, description))
+            };
+            match arg.as_str() {
+                "-o" => parsed.output = Some(value("a path")?),
+                "--map" => parsed.map = Some(value("a path")?),
+                "--align-functions" => {
+                    parsed.align_functions = Some(number(arg, value("a number of bytes")?)?)
+                }
+                "--memory-size" => {
+                    parsed.memory_size = Some(number(arg, value("a number of bytes")?)?)
+                }
+                "--stack-size" => {
+                    parsed.stack_size = Some(number(arg, value("a number of bytes")?)?)
+                }
+                "--function" => parsed.function = Some(value("a label name")?),
+                "--target" => parsed.target = Some(value("a target")?),
+                "--fuel" => parsed.fuel = Some(number(arg, value("a number of instructions")?)?),
+                "--safe" => parsed.safe = true,
+                "--breakpoints" => parsed.breakpoints = true,
+                "--check" => parsed.check = true,
+                "--disasm" => parsed.disasm = true,
+                "--bytes" => parsed.bytes = true,
+                "--call-graph" => parsed.call_graph = true,
+                #[cfg(feature = "run-fasm")]
+                "--run" => parsed.run = true,
+                #[cfg(feature = "run-fasm")]
+                "--check-backends" => parsed.check_backends = true,
+                "--profile" => parsed.profile = true,
+                "--trace" => parsed.trace = true,
+                "--track-memory" => parsed.track_memory = true,
+                "--check-uninitialized-reads" => parsed.check_uninitialized_reads = true,
+                "--interpret" => {
+                    parsed.interpret = true;
+                    parsed.program_args = args.cloned().collect();
+                    break;
+                }
+                _ => {
+                    if let Some(emit) = arg.strip_prefix("--emit=") {
+                        parsed.emit = Some(emit.to_string());
+                    } else if let Some(label) = arg.strip_prefix("--trace-from=") {
+                        parsed.trace_from = Some(label.to_string());
+                    } else if let Some(path) = arg.strip_prefix("--dump-memory-on-exit=") {
+                        parsed.dump_memory_on_exit = Some(path.to_string());
+                    } else if arg.starts_with("--") {
+                        return Err(format!("unknown flag {}", arg));
+                    } else if parsed.path.is_none() {
+                        parsed.path = Some(arg.clone());
+                    } else if parsed.diff && parsed.new_path.is_none() {
+                        parsed.new_path = Some(arg.clone());
+                    } else {
+                        return Err(format!("unexpected argument {}", arg));
+                    }
+                }
+            }
+        }
+        Ok(parsed)
+    }
+}
+
+fn number<T: FromStr>(flag: &str, value: String) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("{} needs a number, but got {}", flag, value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Args, String> {
+        let args: Vec<_> = args.iter().map(|arg| arg.to_string()).collect();
+        Args::parse(&args)
+    }
+
+    #[test]
+    fn flag_values_are_not_the_path() {
+        let args = parse(&[
+            "--memory-size",
+            "64",
+            "-o",
+            "out.s",
+            "program.soil",
+            "--safe",
+        ])
+        .unwrap();
+        assert_eq!(args.path.as_deref(), Some("program.soil"));
+        assert_eq!(args.memory_size, Some(64));
+        assert_eq!(args.output.as_deref(), Some("out.s"));
+        assert!(args.safe);
+    }
+
+    #[test]
+    fn arguments_after_interpret_belong_to_the_program() {
+        let args = parse(&["program.soil", "--fuel", "9", "--interpret", "--fuel", "x"]).unwrap();
+        assert_eq!(args.fuel, Some(9));
+        assert_eq!(args.program_args, ["--fuel", "x"]);
+    }
+
+    #[test]
+    fn bad_arguments_are_errors() {
+        assert_eq!(
+            parse(&["--memory-size"]),
+            Err("--memory-size needs a number of bytes".to_string())
+        );
+        assert_eq!(
+            parse(&["--memory-size", "abc"]),
+            Err("--memory-size needs a number, but got abc".to_string())
+        );
+        assert_eq!(
+            parse(&["--frobnicate", "a.soil"]),
+            Err("unknown flag --frobnicate".to_string())
+        );
+        assert_eq!(
+            parse(&["a.soil", "b.soil"]),
+            Err("unexpected argument b.soil".to_string())
+        );
+    }
+
+    #[test]
+    fn breakpoints_are_opt_in() {
+        assert!(!parse(&["a.soil"]).unwrap().breakpoints);
+        assert!(parse(&["a.soil", "--breakpoints"]).unwrap().breakpoints);
+    }
+
+    #[test]
+    fn diff_takes_two_paths() {
+        let args = parse(&["diff", "old.soil", "new.soil"]).unwrap();
+        assert!(args.diff);
+        assert_eq!(args.path.as_deref(), Some("old.soil"));
+        assert_eq!(args.new_path.as_deref(), Some("new.soil"));
+        assert_eq!(
+            parse(&["old.soil", "new.soil"]),
+            Err("unexpected argument new.soil".to_string())
+        );
+    }
+}
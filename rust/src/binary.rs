@@ -1,87 +1,938 @@
-use crate::utils::WordFromByteSlice;
+use alloc::{collections::BTreeSet, string::String, vec, vec::Vec};
+use core::fmt::{self, Display, Formatter};
+#[cfg(feature = "std")]
+use std::{fs, io, path::Path};
 
+use crate::{
+    bytecode::{ByteCode, DecodeError, Instruction, Reg},
+    utils::WordFromByteSlice,
+};
+
+#[derive(Debug, Default, PartialEq)]
 pub struct Binary {
+    pub name: Option<String>,
     pub memory: Vec<u8>,
     pub byte_code: Vec<u8>,
     pub labels: Vec<(usize, String)>,
+    pub source_map: Vec<(usize, SourceLocation)>,
+    // Key-value pairs attached to byte code offsets. They only serve tooling
+    // and don't affect how the program runs.
+    pub annotations: Vec<(usize, String, String)>,
+    pub arithmetic_mode: ArithmeticMode,
+    // Registers that don't start with their default value.
+    pub initial_registers: Vec<(Reg, i64)>,
+}
+
+// The sections a binary can contain, with their type bytes. The README
+// documents their content.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SectionType {
+    ByteCode = 0,
+    InitialMemory = 1,
+    Name = 2,
+    DebugInfo = 3, // labels
+    Description = 4,
+    SourceMap = 5,
+    ArithmeticMode = 6,
+    InitialRegisters = 7,
+    Annotations = 8,
+}
+
+impl SectionType {
+    // Returns None for section types this version doesn't know about.
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        Some(match byte {
+            0 => SectionType::ByteCode,
+            1 => SectionType::InitialMemory,
+            2 => SectionType::Name,
+            3 => SectionType::DebugInfo,
+            4 => SectionType::Description,
+            5 => SectionType::SourceMap,
+            6 => SectionType::ArithmeticMode,
+            7 => SectionType::InitialRegisters,
+            8 => SectionType::Annotations,
+            _ => return None,
+        })
+    }
+}
+
+impl Display for SectionType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            SectionType::ByteCode => "byte code",
+            SectionType::InitialMemory => "initial memory",
+            SectionType::Name => "name",
+            SectionType::DebugInfo => "labels",
+            SectionType::Description => "description",
+            SectionType::SourceMap => "source map",
+            SectionType::ArithmeticMode => "arithmetic mode",
+            SectionType::InitialRegisters => "initial registers",
+            SectionType::Annotations => "annotations",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+// How add, sub and mul behave if the result doesn't fit into 64 bits.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ArithmeticMode {
+    #[default]
+    Wrapping,
+    Trapping,
 }
 
+// A position in one of the source files the byte code was compiled from.
+#[derive(Debug, PartialEq)]
+pub struct SourceLocation {
+    pub file: String,
+    pub line: usize,
+    pub column: usize,
+}
+
+#[derive(Debug)]
+pub enum ParseError {
+    // The input doesn't start with "soil".
+    BadMagic,
+    // The input ended in the middle of something at this offset.
+    UnexpectedEof {
+        offset: usize,
+    },
+    // The section starting at offset is too short for its content.
+    BadSectionLength {
+        offset: usize,
+        len: usize,
+    },
+    // The section starting at offset claims to be longer than the rest of the
+    // input. The section is the raw type byte, so unknown sections can be
+    // reported too.
+    SectionExceedsInput {
+        offset: usize,
+        section: u8,
+        len: usize,
+        available: usize,
+    },
+    // The binary or one of its sections is larger than ParseOptions allow.
+    TooLarge {
+        size: usize,
+        limit: usize,
+    },
+    UnknownArithmeticMode(u8),
+    InvalidRegister(u8),
+    // A source map entry refers to a file index that doesn't exist.
+    UnknownSourceFile(usize),
+    // The string starting at offset is not valid UTF-8.
+    InvalidUtf8 {
+        offset: usize,
+    },
+    // A section that may only appear once appears again at this offset.
+    DuplicateSection {
+        offset: usize,
+        section: SectionType,
+    },
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::BadMagic => write!(f, "magic bytes don't match, this is not a soil binary"),
+            ParseError::UnexpectedEof { offset } => {
+                write!(
+                    f,
+                    "binary incomplete, it ends unexpectedly at byte {}",
+                    offset
+                )
+            }
+            ParseError::BadSectionLength { offset, len } => {
+                write!(
+                    f,
+                    "the section at byte {} has an invalid length of {} bytes",
+                    offset, len
+                )
+            }
+            ParseError::SectionExceedsInput {
+                offset,
+                section,
+                len,
+                available,
+            } => {
+                match SectionType::from_byte(*section) {
+                    Some(section) => write!(f, "the {} section", section)?,
+                    None => write!(f, "the unknown section of type {}", section)?,
+                }
+                write!(
+                    f,
+                    " at byte {} exceeds the file size: it declares {} bytes, but only {} are left",
+                    offset, len, available
+                )
+            }
+            ParseError::TooLarge { size, limit } => {
+                write!(
+                    f,
+                    "binary or section too large ({} bytes, limit is {} bytes)",
+                    size, limit
+                )
+            }
+            ParseError::UnknownArithmeticMode(mode) => {
+                write!(f, "unknown arithmetic mode {}", mode)
+            }
+            ParseError::InvalidRegister(reg) => write!(f, "invalid register {}", reg),
+            ParseError::UnknownSourceFile(file) => {
+                write!(f, "source map refers to unknown file {}", file)
+            }
+            ParseError::InvalidUtf8 { offset } => {
+                write!(f, "the string at byte {} is not valid UTF-8", offset)
+            }
+            ParseError::DuplicateSection { offset, section } => {
+                write!(
+                    f,
+                    "the section at byte {} is a second {} section",
+                    offset, section
+                )
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ValidationError {
+    InvalidInstruction(DecodeError),
+    // The jump, cjump, or call at offset targets a position after the end of
+    // the byte code.
+    TargetOutOfBounds { offset: usize, target: usize },
+    // The jump, cjump, or call at offset targets the middle of an instruction.
+    TargetNotAnInstruction { offset: usize, target: usize },
+    // The initial sp doesn't point into the memory.
+    InitialSpOutsideOfMemory { sp: i64, memory_size: usize },
+}
+
+impl ValidationError {
+    // The byte code offset of the offending instruction, if the problem is
+    // in the byte code.
+    pub fn offset(&self) -> Option<usize> {
+        match self {
+            ValidationError::InvalidInstruction(error) => Some(error.offset()),
+            ValidationError::TargetOutOfBounds { offset, .. } => Some(*offset),
+            ValidationError::TargetNotAnInstruction { offset, .. } => Some(*offset),
+            ValidationError::InitialSpOutsideOfMemory { .. } => None,
+        }
+    }
+}
+
+impl Display for ValidationError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ValidationError::InvalidInstruction(error) => write!(f, "{}", error),
+            ValidationError::TargetOutOfBounds { offset, target } => write!(
+                f,
+                "the instruction at byte {:x} jumps to {:x}, which is outside of the byte code",
+                offset, target
+            ),
+            ValidationError::TargetNotAnInstruction { offset, target } => write!(
+                f,
+                "the instruction at byte {:x} jumps to {:x}, which is not the start of an instruction",
+                offset, target
+            ),
+            ValidationError::InitialSpOutsideOfMemory { sp, memory_size } => write!(
+                f,
+                "the initial sp {} is outside of the memory, which is {} bytes large",
+                sp, memory_size
+            ),
+        }
+    }
+}
+
+// Why a binary couldn't be loaded from a file.
+#[cfg(feature = "std")]
+#[derive(Debug)]
+pub enum LoadError {
+    Io(io::Error),
+    Parse(ParseError),
+}
+
+#[cfg(feature = "std")]
+impl Display for LoadError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::Io(error) => write!(f, "couldn't read the binary: {}", error),
+            LoadError::Parse(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<io::Error> for LoadError {
+    fn from(error: io::Error) -> Self {
+        LoadError::Io(error)
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<ParseError> for LoadError {
+    fn from(error: ParseError) -> Self {
+        LoadError::Parse(error)
+    }
+}
+
+const SOURCE_MAP_VERSION: usize = 1;
+
 struct Parser<'a> {
     input: &'a [u8],
+    offset: usize,
+    // The offset and length of the section whose content this parses, if any.
+    section: Option<(usize, usize)>,
 }
 impl<'a> Parser<'a> {
     fn done(&self) -> bool {
         self.input.is_empty()
     }
-    fn advance_by(&mut self, n: usize) {
-        self.input = &self.input[n..];
+    // Running out of input inside a section means the section is too short
+    // for its content.
+    fn eof(&self) -> ParseError {
+        match self.section {
+            Some((offset, len)) => ParseError::BadSectionLength { offset, len },
+            None => ParseError::UnexpectedEof {
+                offset: self.offset + self.input.len(),
+            },
+        }
+    }
+    // Content left over after a section's structure means its length is
+    // wrong too.
+    fn expect_done(&self) -> Result<(), ParseError> {
+        match self.section {
+            Some((offset, len)) if !self.done() => {
+                Err(ParseError::BadSectionLength { offset, len })
+            }
+            _ => Ok(()),
+        }
     }
-    fn eat_byte(&mut self) -> u8 {
-        if self.done() {
-            panic!("binary incomplete");
+    fn advance_by(&mut self, n: usize) -> Result<(), ParseError> {
+        if n > self.input.len() {
+            return Err(self.eof());
         }
-        let byte = self.input[0];
-        self.advance_by(1);
-        byte
+        self.input = &self.input[n..];
+        self.offset += n;
+        Ok(())
+    }
+    fn eat_byte(&mut self) -> Result<u8, ParseError> {
+        let byte = *self.input.first().ok_or_else(|| self.eof())?;
+        self.advance_by(1)?;
+        Ok(byte)
     }
-    fn eat_usize(&mut self) -> usize {
+    fn eat_usize(&mut self) -> Result<usize, ParseError> {
         if self.input.len() < 8 {
-            panic!("binary incomplete");
+            return Err(self.eof());
         }
         let word = self.input.word_at(0);
-        self.advance_by(8);
-        word as usize
+        self.advance_by(8)?;
+        Ok(word as usize)
+    }
+    fn eat_bytes(&mut self, len: usize) -> Result<&'a [u8], ParseError> {
+        let input = self.input;
+        self.advance_by(len)?;
+        Ok(&input[..len])
+    }
+    fn eat_string(&mut self) -> Result<String, ParseError> {
+        let len = self.eat_usize()?;
+        self.eat_utf8(len)
+    }
+    fn eat_utf8(&mut self, len: usize) -> Result<String, ParseError> {
+        let offset = self.offset;
+        String::from_utf8(self.eat_bytes(len)?.to_vec())
+            .map_err(|_| ParseError::InvalidUtf8 { offset })
+    }
+}
+
+#[derive(Default)]
+struct Writer {
+    output: Vec<u8>,
+}
+impl Writer {
+    fn write_byte(&mut self, byte: u8) {
+        self.output.push(byte);
+    }
+    fn write_usize(&mut self, value: usize) {
+        self.output.extend_from_slice(&(value as u64).to_le_bytes());
+    }
+    fn write_bytes(&mut self, bytes: &[u8]) {
+        self.output.extend_from_slice(bytes);
+    }
+    fn write_string(&mut self, string: &str) {
+        self.write_usize(string.len());
+        self.write_bytes(string.as_bytes());
+    }
+    fn write_section(&mut self, section: SectionType, content: Writer) {
+        self.write_byte(section as u8);
+        self.write_usize(content.output.len());
+        self.write_bytes(&content.output);
+    }
+}
+
+pub struct ParseOptions {
+    // Binaries (and sections) larger than this are rejected before parsing
+    // them, so that huge or malicious inputs don't use up all the memory.
+    pub max_size: usize,
+}
+
+impl Default for ParseOptions {
+    fn default() -> Self {
+        Self { max_size: 1 << 30 }
     }
 }
 
 impl Binary {
-    pub fn parse(bytes: &[u8]) -> Self {
+    pub fn parse(bytes: &[u8]) -> Result<Self, ParseError> {
+        Self::parse_with_options(bytes, &ParseOptions::default())
+    }
+
+    // Reads the file at path and parses it.
+    #[cfg(feature = "std")]
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, LoadError> {
+        let bytes = fs::read(path)?;
+        Ok(Self::parse(&bytes)?)
+    }
+
+    pub fn parse_with_options(bytes: &[u8], options: &ParseOptions) -> Result<Self, ParseError> {
+        if bytes.len() > options.max_size {
+            return Err(ParseError::TooLarge {
+                size: bytes.len(),
+                limit: options.max_size,
+            });
+        }
         let mut binary = Self {
+            name: None,
             memory: vec![],
             byte_code: vec![],
             labels: vec![],
+            source_map: vec![],
+            annotations: vec![],
+            arithmetic_mode: ArithmeticMode::Wrapping,
+            initial_registers: vec![],
+        };
+        if !bytes.starts_with(b"soil") {
+            return Err(ParseError::BadMagic);
+        }
+        let mut parser = Parser {
+            input: &bytes[4..],
+            offset: 4,
+            section: None,
         };
-        let mut parser = Parser { input: bytes };
-        assert_eq!(parser.eat_byte(), 's' as u8, "magic bytes don't match");
-        assert_eq!(parser.eat_byte(), 'o' as u8, "magic bytes don't match");
-        assert_eq!(parser.eat_byte(), 'i' as u8, "magic bytes don't match");
-        assert_eq!(parser.eat_byte(), 'l' as u8, "magic bytes don't match");
 
+        let mut seen = vec![];
         while !parser.done() {
-            let section_type = parser.eat_byte();
-            let section_len = parser.eat_usize();
+            let section_start = parser.offset;
+            let type_byte = parser.eat_byte()?;
+            let section_type = SectionType::from_byte(type_byte);
+            let section_len = parser.eat_usize()?;
+            if section_len > options.max_size {
+                return Err(ParseError::TooLarge {
+                    size: section_len,
+                    limit: options.max_size,
+                });
+            }
+            if section_len > parser.input.len() {
+                return Err(ParseError::SectionExceedsInput {
+                    offset: section_start,
+                    section: type_byte,
+                    len: section_len,
+                    available: parser.input.len(),
+                });
+            }
+            // The content is parsed on its own, so that counts inside it can't
+            // make the parser read into the following sections.
+            let content_start = parser.offset;
+            let content = parser.eat_bytes(section_len)?;
+            let mut section = Parser {
+                input: content,
+                offset: content_start,
+                section: Some((section_start, section_len)),
+            };
+            if let Some(section) = section_type {
+                let unique = matches!(
+                    section,
+                    SectionType::ByteCode | SectionType::InitialMemory | SectionType::Name
+                );
+                if unique && seen.contains(&section) {
+                    return Err(ParseError::DuplicateSection {
+                        offset: section_start,
+                        section,
+                    });
+                }
+                seen.push(section);
+            }
             match section_type {
-                0 => {
-                    // machine code
-                    for _ in 0..section_len {
-                        binary.byte_code.push(parser.eat_byte());
+                Some(SectionType::ByteCode) => binary.byte_code = content.to_vec(),
+                Some(SectionType::InitialMemory) => binary.memory = content.to_vec(),
+                Some(SectionType::Name) => {
+                    binary.name = Some(section.eat_utf8(section_len)?);
+                }
+                Some(SectionType::DebugInfo) => {
+                    let num_labels = section.eat_usize()?;
+                    for _ in 0..num_labels {
+                        let pos = section.eat_usize()?;
+                        let label = section.eat_string()?;
+                        binary.labels.push((pos, label));
                     }
+                    section.expect_done()?;
                 }
-                1 => {
-                    // initial memory
-                    for _ in 0..section_len {
-                        binary.memory.push(parser.eat_byte());
+                Some(SectionType::SourceMap) => {
+                    let version = section.eat_usize()?;
+                    if version != SOURCE_MAP_VERSION {
+                        // The source map is optional, so we just ignore
+                        // versions we don't understand.
+                        continue;
+                    }
+                    let num_files = section.eat_usize()?;
+                    let mut files = vec![];
+                    for _ in 0..num_files {
+                        files.push(section.eat_string()?);
+                    }
+                    let num_entries = section.eat_usize()?;
+                    for _ in 0..num_entries {
+                        let pos = section.eat_usize()?;
+                        let file = section.eat_usize()?;
+                        let line = section.eat_usize()?;
+                        let column = section.eat_usize()?;
+                        let file = files.get(file).ok_or(ParseError::UnknownSourceFile(file))?;
+                        binary.source_map.push((
+                            pos,
+                            SourceLocation {
+                                file: file.clone(),
+                                line,
+                                column,
+                            },
+                        ));
                     }
+                    section.expect_done()?;
                 }
-                3 => {
-                    // debug info
-                    let num_labels = parser.eat_usize();
-                    for _ in 0..num_labels {
-                        let pos = parser.eat_usize();
-                        let len = parser.eat_usize();
-                        let mut label = String::new();
-                        for _ in 0..len {
-                            label.push(parser.eat_byte() as char);
-                        }
-                        binary.labels.push((pos, label));
+                Some(SectionType::ArithmeticMode) => {
+                    binary.arithmetic_mode = match section.eat_byte()? {
+                        0 => ArithmeticMode::Wrapping,
+                        1 => ArithmeticMode::Trapping,
+                        mode => return Err(ParseError::UnknownArithmeticMode(mode)),
+                    };
+                    section.expect_done()?;
+                }
+                Some(SectionType::InitialRegisters) => {
+                    // Each entry is a register byte followed by its value.
+                    if section_len % 9 != 0 {
+                        return Err(ParseError::BadSectionLength {
+                            offset: section_start,
+                            len: section_len,
+                        });
+                    }
+                    for _ in 0..section_len / 9 {
+                        let byte = section.eat_byte()?;
+                        let reg =
+                            Reg::try_from(byte).map_err(|_| ParseError::InvalidRegister(byte))?;
+                        let value = section.eat_usize()? as i64;
+                        binary.initial_registers.push((reg, value));
                     }
                 }
-                _ => {
-                    parser.advance_by(section_len);
+                Some(SectionType::Annotations) => {
+                    let num_annotations = section.eat_usize()?;
+                    for _ in 0..num_annotations {
+                        let pos = section.eat_usize()?;
+                        let key = section.eat_string()?;
+                        let value = section.eat_string()?;
+                        binary.annotations.push((pos, key, value));
+                    }
+                    section.expect_done()?;
                 }
+                // The description and unknown sections are skipped.
+                Some(SectionType::Description) | None => {}
+            }
+        }
+
+        Ok(binary)
+    }
+
+    // Turns the binary back into bytes that parse to the same binary. Sections
+    // that would only contain their default value are left out.
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut writer = Writer::default();
+        writer.write_bytes(b"soil");
+
+        let mut byte_code = Writer::default();
+        byte_code.write_bytes(&self.byte_code);
+        writer.write_section(SectionType::ByteCode, byte_code);
+
+        let mut memory = Writer::default();
+        memory.write_bytes(&self.memory);
+        writer.write_section(SectionType::InitialMemory, memory);
+
+        if let Some(name) = &self.name {
+            let mut section = Writer::default();
+            section.write_bytes(name.as_bytes());
+            writer.write_section(SectionType::Name, section);
+        }
+
+        if !self.labels.is_empty() {
+            let mut section = Writer::default();
+            section.write_usize(self.labels.len());
+            for (pos, label) in &self.labels {
+                section.write_usize(*pos);
+                section.write_string(label);
+            }
+            writer.write_section(SectionType::DebugInfo, section);
+        }
+
+        if !self.source_map.is_empty() {
+            let mut files: Vec<&str> = vec![];
+            for (_, location) in &self.source_map {
+                if !files.contains(&location.file.as_str()) {
+                    files.push(&location.file);
+                }
+            }
+            let mut section = Writer::default();
+            section.write_usize(SOURCE_MAP_VERSION);
+            section.write_usize(files.len());
+            for file in &files {
+                section.write_string(file);
+            }
+            section.write_usize(self.source_map.len());
+            for (pos, location) in &self.source_map {
+                let file = files.iter().position(|file| *file == location.file);
+                section.write_usize(*pos);
+                section.write_usize(file.unwrap());
+                section.write_usize(location.line);
+                section.write_usize(location.column);
+            }
+            writer.write_section(SectionType::SourceMap, section);
+        }
+
+        if self.arithmetic_mode != ArithmeticMode::Wrapping {
+            let mut section = Writer::default();
+            section.write_byte(match self.arithmetic_mode {
+                ArithmeticMode::Wrapping => 0,
+                ArithmeticMode::Trapping => 1,
+            });
+            writer.write_section(SectionType::ArithmeticMode, section);
+        }
+
+        if !self.initial_registers.is_empty() {
+            let mut section = Writer::default();
+            for (reg, value) in &self.initial_registers {
+                section.write_byte(reg.index() as u8);
+                section.write_usize(*value as usize);
+            }
+            writer.write_section(SectionType::InitialRegisters, section);
+        }
+
+        if !self.annotations.is_empty() {
+            let mut section = Writer::default();
+            section.write_usize(self.annotations.len());
+            for (pos, key, value) in &self.annotations {
+                section.write_usize(*pos);
+                section.write_string(key);
+                section.write_string(value);
+            }
+            writer.write_section(SectionType::Annotations, section);
+        }
+
+        writer.output
+    }
+}
+
+impl Binary {
+    // The offset in the byte code where execution starts. By convention,
+    // that's always the first instruction. Compilers that want to start
+    // somewhere else put a jump there.
+    pub fn entry_point(&self) -> usize {
+        0
+    }
+
+    // The name of the label with the greatest position at or before offset,
+    // usually the function the offset belongs to.
+    pub fn label_for(&self, offset: usize) -> Option<&str> {
+        self.labels
+            .iter()
+            .filter(|(pos, _)| *pos <= offset)
+            .max_by_key(|(pos, _)| *pos)
+            .map(|(_, label)| label.as_str())
+    }
+
+    // The offsets in the byte code at which instructions start.
+    pub fn instruction_offsets(&self) -> BTreeSet<usize> {
+        let mut offsets = BTreeSet::new();
+        let mut parser = self.byte_code.byte_code();
+        loop {
+            let offset = parser.cursor;
+            match parser.next_instruction() {
+                Some(Ok(_)) => offsets.insert(offset),
+                Some(Err(_)) => continue,
+                None => break,
+            };
+        }
+        offsets
+    }
+
+    // Checks that an initial sp points into a memory of the given size.
+    pub fn check_initial_registers(&self, memory_size: usize) -> Result<(), ValidationError> {
+        for (reg, value) in &self.initial_registers {
+            if *reg == Reg::SP && (*value < 0 || *value as usize > memory_size) {
+                return Err(ValidationError::InitialSpOutsideOfMemory {
+                    sp: *value,
+                    memory_size,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    // Checks that the initial registers fit a memory of the given size, all
+    // instructions are valid, and all jump and call targets are the start of
+    // an instruction. Returns the first problem.
+    pub fn validate(&self, memory_size: usize) -> Result<(), ValidationError> {
+        self.check_initial_registers(memory_size)?;
+        let offsets = self.instruction_offsets();
+        let mut parser = self.byte_code.byte_code();
+        loop {
+            let offset = parser.cursor;
+            let target = match parser.next_instruction() {
+                Some(Ok(
+                    Instruction::Jump(target)
+                    | Instruction::Cjump(target)
+                    | Instruction::Call(target)
+                    | Instruction::Jumpr(target)
+                    | Instruction::Cjumpr(target)
+                    | Instruction::Callr(target),
+                )) => target,
+                Some(Ok(_)) => continue,
+                Some(Err(error)) => return Err(ValidationError::InvalidInstruction(error)),
+                None => break,
+            };
+            if target >= self.byte_code.len() {
+                return Err(ValidationError::TargetOutOfBounds { offset, target });
+            }
+            if !offsets.contains(&target) {
+                return Err(ValidationError::TargetNotAnInstruction { offset, target });
             }
         }
+        Ok(())
+    }
+}
+
+impl TryFrom<&[u8]> for Binary {
+    type Error = ParseError;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        Self::parse(bytes)
+    }
+}
+
+impl Display for SourceLocation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.file, self.line, self.column)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn binary(sections: &[(u8, &[u8])]) -> Vec<u8> {
+        let mut bytes = b"soil".to_vec();
+        for (section, content) in sections {
+            bytes.push(*section);
+            bytes.extend((content.len() as u64).to_le_bytes());
+            bytes.extend(*content);
+        }
+        bytes
+    }
+
+    #[test]
+    fn initial_registers_must_be_nine_bytes_each() {
+        let register = [&[2][..], &5i64.to_le_bytes()].concat();
+        let binary_bytes = binary(&[(7, &register)]);
+        let parsed = Binary::parse(&binary_bytes).unwrap();
+        assert_eq!(parsed.initial_registers, [(Reg::A, 5)]);
+
+        let register = [&register[..], &[0]].concat();
+        assert!(matches!(
+            Binary::parse(&binary(&[(7, &register)])),
+            Err(ParseError::BadSectionLength { offset: 4, len: 10 })
+        ));
+    }
+
+    #[test]
+    fn initial_sp_must_be_in_the_memory() {
+        for (sp, valid) in [(0, true), (1024, true), (-8, false), (1032, false)] {
+            let binary = Binary {
+                initial_registers: vec![(Reg::SP, sp)],
+                ..Default::default()
+            };
+            assert_eq!(binary.validate(1024).is_ok(), valid);
+        }
+    }
+
+    #[test]
+    fn structured_sections_must_not_have_leftover_bytes() {
+        let labels = [
+            &1u64.to_le_bytes()[..],
+            &0u64.to_le_bytes(),
+            &1u64.to_le_bytes(),
+            b"f",
+        ]
+        .concat();
+        let source_map = [1u64, 0, 0].map(u64::to_le_bytes).concat();
+        let annotations = 0u64.to_le_bytes().to_vec();
+        for (section, content) in [(3, labels), (5, source_map), (6, vec![1]), (8, annotations)] {
+            assert!(Binary::parse(&binary(&[(section, &content)])).is_ok());
+            let content = [&content[..], &[0]].concat();
+            assert!(matches!(
+                Binary::parse(&binary(&[(section, &content)])),
+                Err(ParseError::BadSectionLength { offset: 4, .. })
+            ));
+        }
+    }
+
+    // A xorshift generator, so that the property test below is reproducible.
+    struct Random(u64);
+    impl Random {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+        fn below(&mut self, n: usize) -> usize {
+            (self.next() % n as u64) as usize
+        }
+        fn bytes(&mut self) -> Vec<u8> {
+            (0..self.below(32)).map(|_| self.next() as u8).collect()
+        }
+        fn string(&mut self) -> String {
+            let chars = ['a', 'Z', '_', ' ', '.', 'ä', '→', '🌱'];
+            (0..self.below(12))
+                .map(|_| chars[self.below(chars.len())])
+                .collect()
+        }
+        fn list<T>(&mut self, mut item: impl FnMut(&mut Self) -> T) -> Vec<T> {
+            (0..self.below(5)).map(|_| item(self)).collect()
+        }
+        fn binary(&mut self) -> Binary {
+            Binary {
+                name: if self.below(2) == 0 {
+                    None
+                } else {
+                    Some(self.string())
+                },
+                memory: self.bytes(),
+                byte_code: self.bytes(),
+                labels: self.list(|random| (random.next() as usize, random.string())),
+                source_map: self.list(|random| {
+                    let file = random.string();
+                    let location = SourceLocation {
+                        file,
+                        line: random.next() as usize,
+                        column: random.next() as usize,
+                    };
+                    (random.next() as usize, location)
+                }),
+                annotations: self
+                    .list(|random| (random.next() as usize, random.string(), random.string())),
+                arithmetic_mode: if self.below(2) == 0 {
+                    ArithmeticMode::Wrapping
+                } else {
+                    ArithmeticMode::Trapping
+                },
+                initial_registers: self.list(|random| {
+                    let reg = Reg::ALL[random.below(Reg::ALL.len())];
+                    (reg, random.next() as i64)
+                }),
+            }
+        }
+    }
+
+    #[test]
+    fn parsing_a_serialized_binary_returns_the_same_binary() {
+        let mut random = Random(0x5eed);
+        let mut with_annotations = 0;
+        for _ in 0..1000 {
+            let binary = random.binary();
+            assert_eq!(Binary::parse(&binary.serialize()).unwrap(), binary);
+            with_annotations += !binary.annotations.is_empty() as usize;
+        }
+        // Every section should have been covered.
+        assert!(with_annotations > 0);
+    }
+
+    #[test]
+    fn binaries_and_sections_above_the_limit_are_rejected() {
+        let options = ParseOptions { max_size: 64 };
+        let bytes = binary(&[(0, &[0; 100])]);
+        assert!(matches!(
+            Binary::parse_with_options(&bytes, &options),
+            Err(ParseError::TooLarge {
+                size: 113,
+                limit: 64
+            })
+        ));
+
+        // The section claims to be huge, but the binary is small.
+        let mut bytes = b"soil\0".to_vec();
+        bytes.extend(u64::MAX.to_le_bytes());
+        assert!(matches!(
+            Binary::parse_with_options(&bytes, &options),
+            Err(ParseError::TooLarge { size, limit: 64 }) if size == u64::MAX as usize
+        ));
+    }
+
+    #[test]
+    fn labels_are_utf8() {
+        let label = "extract_title_and_subtitle_für_Überschrift";
+        let labels = |label: &[u8]| {
+            [
+                &1u64.to_le_bytes()[..],
+                &0u64.to_le_bytes(),
+                &(label.len() as u64).to_le_bytes(),
+                label,
+            ]
+            .concat()
+        };
+        let parsed = Binary::parse(&binary(&[(3, &labels(label.as_bytes()))])).unwrap();
+        assert_eq!(parsed.labels, [(0, label.to_string())]);
+
+        // The label starts after the section header (9 bytes), the number of
+        // labels, the position and the length (8 bytes each).
+        assert!(matches!(
+            Binary::parse(&binary(&[(3, &labels(&[b'f', 0xff]))])),
+            Err(ParseError::InvalidUtf8 { offset: 37 })
+        ));
+    }
+
+    #[test]
+    fn execution_starts_at_the_first_instruction() {
+        let binary = Binary {
+            byte_code: vec![0x00, 0xf3],
+            labels: vec![(1, "main".to_string())],
+            ..Default::default()
+        };
+        assert_eq!(binary.entry_point(), 0);
+    }
+
+    #[test]
+    fn binaries_can_be_converted_from_bytes() {
+        let bytes = binary(&[(0, &[0xf3])]);
+        let parsed = Binary::try_from(&bytes[..]).unwrap();
+        assert_eq!(parsed.byte_code, [0xf3]);
+        assert!(matches!(
+            Binary::try_from(&b"nope"[..]),
+            Err(ParseError::BadMagic)
+        ));
+    }
 
-        binary
+    #[cfg(feature = "std")]
+    #[test]
+    fn binaries_can_be_loaded_from_files() {
+        let path = std::env::temp_dir().join(format!("soil-{}-load.soil", std::process::id()));
+        fs::write(&path, binary(&[(0, &[0xf3])])).unwrap();
+        let loaded = Binary::from_file(&path);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.unwrap().byte_code, [0xf3]);
+        assert!(matches!(
+            Binary::from_file(&path),
+            Err(LoadError::Io(error)) if error.kind() == io::ErrorKind::NotFound
+        ));
     }
 }
@@ -0,0 +1,597 @@
+use core::fmt::{self, Display, Formatter};
+
+use extension_trait::extension_trait;
+
+use crate::utils::WordFromByteSlice;
+
+#[extension_trait]
+pub impl ByteCode for [u8] {
+    fn byte_code(&self) -> ByteCodeParser<'_> {
+        ByteCodeParser {
+            input: self,
+            cursor: 0,
+        }
+    }
+}
+
+pub struct ByteCodeParser<'a> {
+    input: &'a [u8],
+    pub cursor: usize,
+}
+impl<'a> ByteCodeParser<'a> {
+    fn done(&self) -> bool {
+        self.cursor >= self.input.len()
+    }
+    fn advance_by(&mut self, n: usize) {
+        self.cursor += n;
+    }
+    fn eat_byte(&mut self) -> Option<u8> {
+        if self.done() {
+            return None;
+        }
+        let byte = self.input[self.cursor];
+        self.advance_by(1);
+        Some(byte)
+    }
+    fn eat_operand_byte(&mut self) -> Result<u8, Malformed> {
+        self.eat_byte().ok_or(Malformed::Truncated)
+    }
+    fn eat_i64(&mut self) -> Result<i64, Malformed> {
+        let bytes = self
+            .input
+            .get(self.cursor..self.cursor + 8)
+            .ok_or(Malformed::Truncated)?;
+        let word = bytes.word_at(0);
+        self.advance_by(8);
+        Ok(word)
+    }
+    fn eat_usize(&mut self) -> Result<usize, Malformed> {
+        self.eat_i64().map(|word| word as usize)
+    }
+    // Reads a 4-byte offset relative to the end of it and returns the
+    // absolute target. Targets before the start wrap around, so they end up
+    // out of bounds.
+    fn eat_relative_target(&mut self) -> Result<usize, Malformed> {
+        let bytes = self
+            .input
+            .get(self.cursor..self.cursor + 4)
+            .ok_or(Malformed::Truncated)?;
+        let offset = i32::from_le_bytes(bytes.try_into().unwrap());
+        self.advance_by(4);
+        Ok((self.cursor as i64 + offset as i64) as usize)
+    }
+    fn eat_reg(&mut self) -> Result<Reg, Malformed> {
+        let byte = self.eat_operand_byte()?;
+        Reg::try_from(byte & 0x0f).map_err(|_| Malformed::InvalidRegister(byte & 0x0f))
+    }
+    fn eat_regs(&mut self) -> Result<(Reg, Reg), Malformed> {
+        let byte = self.eat_operand_byte()?;
+        let reg = |nibble| Reg::try_from(nibble).map_err(|_| Malformed::InvalidRegister(nibble));
+        Ok((reg(byte & 0x0f)?, reg(byte >> 4 & 0x0f)?))
+    }
+}
+
+// What's wrong with an instruction, before it's turned into a DecodeError
+// that knows where the instruction is.
+enum Malformed {
+    UnknownOpcode,
+    Truncated,
+    InvalidRegister(u8),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Instruction {
+    Nop,
+    Panic,
+    Breakpoint,
+    Move_(Reg, Reg),
+    Movei(Reg, i64),
+    Moveib(Reg, u8),
+    Moveisb(Reg, i8),
+    Swap(Reg, Reg),
+    // Move the second register into the first if st is zero or not zero.
+    Movz(Reg, Reg),
+    Movnz(Reg, Reg),
+    Load(Reg, Reg),
+    Loadb(Reg, Reg),
+    Store(Reg, Reg),
+    Storeb(Reg, Reg),
+    // 16-bit (half word) and 32-bit accesses. Loads zero the upper bits.
+    Loadh(Reg, Reg),
+    Loadw(Reg, Reg),
+    Storeh(Reg, Reg),
+    Storew(Reg, Reg),
+    Push(Reg),
+    Pop(Reg),
+    Jump(usize),
+    Cjump(usize),
+    Call(usize),
+    // Like Jump, Cjump, and Call, but encoded with a 4-byte offset relative
+    // to the next instruction. The decoder resolves it to an absolute target.
+    Jumpr(usize),
+    Cjumpr(usize),
+    Callr(usize),
+    Ret,
+    Syscall(u8),
+    Cmp(Reg, Reg),
+    Cmpz(Reg),
+    Isequal,
+    Isless,
+    Isgreater,
+    Islessequal,
+    Isgreaterequal,
+    Inttofloat(Reg),
+    Floattoint(Reg),
+    Add(Reg, Reg),
+    Sub(Reg, Reg),
+    Mul(Reg, Reg),
+    Div(Reg, Reg),
+    Rem(Reg, Reg),
+    Udiv(Reg, Reg),
+    Urem(Reg, Reg),
+    // Like add, sub, and mul, but st reports whether they overflowed.
+    Addc(Reg, Reg),
+    Subc(Reg, Reg),
+    Mulc(Reg, Reg),
+    And(Reg, Reg),
+    Or(Reg, Reg),
+    Xor(Reg, Reg),
+    // Bitwise not (b3), like in the spec and the other implementations. Not
+    // to be confused with two's complement negation.
+    Negate(Reg),
+    // Shifts by the second register, masked to 0 to 63. Shr fills with zeros,
+    // Sar with the sign bit.
+    Shl(Reg, Reg),
+    Shr(Reg, Reg),
+    Sar(Reg, Reg),
+    // Rotates by the second register modulo 64.
+    Rotl(Reg, Reg),
+    Rotr(Reg, Reg),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Reg {
+    SP,
+    ST,
+    A,
+    B,
+    C,
+    D,
+    E,
+    F,
+}
+
+impl Reg {
+    pub const ALL: [Reg; 8] = [
+        Reg::SP,
+        Reg::ST,
+        Reg::A,
+        Reg::B,
+        Reg::C,
+        Reg::D,
+        Reg::E,
+        Reg::F,
+    ];
+
+    // The register's number in the byte code encoding.
+    pub fn index(self) -> usize {
+        self as usize
+    }
+}
+
+// Uses the same syntax as the assembler. Jump targets are hex offsets. Unlike
+// in the assembler, the targets of relative jumps and calls are absolute too,
+// because the decoder already resolved them.
+impl Display for Instruction {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Instruction::Nop => write!(f, "nop"),
+            Instruction::Panic => write!(f, "panic"),
+            Instruction::Breakpoint => write!(f, "breakpoint"),
+            Instruction::Move_(a, b) => write!(f, "move {} {}", a, b),
+            Instruction::Movei(a, value) => write!(f, "movei {} {}", a, value),
+            Instruction::Moveib(a, value) => write!(f, "moveib {} {}", a, value),
+            Instruction::Moveisb(a, value) => write!(f, "moveisb {} {}", a, value),
+            Instruction::Swap(a, b) => write!(f, "swap {} {}", a, b),
+            Instruction::Movz(a, b) => write!(f, "movz {} {}", a, b),
+            Instruction::Movnz(a, b) => write!(f, "movnz {} {}", a, b),
+            Instruction::Load(a, b) => write!(f, "load {} {}", a, b),
+            Instruction::Loadb(a, b) => write!(f, "loadb {} {}", a, b),
+            Instruction::Store(a, b) => write!(f, "store {} {}", a, b),
+            Instruction::Storeb(a, b) => write!(f, "storeb {} {}", a, b),
+            Instruction::Loadh(a, b) => write!(f, "loadh {} {}", a, b),
+            Instruction::Loadw(a, b) => write!(f, "loadw {} {}", a, b),
+            Instruction::Storeh(a, b) => write!(f, "storeh {} {}", a, b),
+            Instruction::Storew(a, b) => write!(f, "storew {} {}", a, b),
+            Instruction::Push(a) => write!(f, "push {}", a),
+            Instruction::Pop(a) => write!(f, "pop {}", a),
+            Instruction::Jump(target) => write!(f, "jump {:x}", target),
+            Instruction::Cjump(target) => write!(f, "cjump {:x}", target),
+            Instruction::Call(target) => write!(f, "call {:x}", target),
+            Instruction::Jumpr(target) => write!(f, "jumpr {:x}", target),
+            Instruction::Cjumpr(target) => write!(f, "cjumpr {:x}", target),
+            Instruction::Callr(target) => write!(f, "callr {:x}", target),
+            Instruction::Ret => write!(f, "ret"),
+            Instruction::Syscall(number) => write!(f, "syscall {}", number),
+            Instruction::Cmp(a, b) => write!(f, "cmp {} {}", a, b),
+            Instruction::Cmpz(a) => write!(f, "cmpz {}", a),
+            Instruction::Isequal => write!(f, "isequal"),
+            Instruction::Isless => write!(f, "isless"),
+            Instruction::Isgreater => write!(f, "isgreater"),
+            Instruction::Islessequal => write!(f, "islessequal"),
+            Instruction::Isgreaterequal => write!(f, "isgreaterequal"),
+            Instruction::Inttofloat(a) => write!(f, "inttofloat {}", a),
+            Instruction::Floattoint(a) => write!(f, "floattoint {}", a),
+            Instruction::Add(a, b) => write!(f, "add {} {}", a, b),
+            Instruction::Sub(a, b) => write!(f, "sub {} {}", a, b),
+            Instruction::Mul(a, b) => write!(f, "mul {} {}", a, b),
+            Instruction::Div(a, b) => write!(f, "div {} {}", a, b),
+            Instruction::Rem(a, b) => write!(f, "rem {} {}", a, b),
+            Instruction::Udiv(a, b) => write!(f, "udiv {} {}", a, b),
+            Instruction::Urem(a, b) => write!(f, "urem {} {}", a, b),
+            Instruction::Addc(a, b) => write!(f, "addc {} {}", a, b),
+            Instruction::Subc(a, b) => write!(f, "subc {} {}", a, b),
+            Instruction::Mulc(a, b) => write!(f, "mulc {} {}", a, b),
+            Instruction::And(a, b) => write!(f, "and {} {}", a, b),
+            Instruction::Or(a, b) => write!(f, "or {} {}", a, b),
+            Instruction::Xor(a, b) => write!(f, "xor {} {}", a, b),
+            Instruction::Negate(a) => write!(f, "not {}", a),
+            Instruction::Shl(a, b) => write!(f, "shl {} {}", a, b),
+            Instruction::Shr(a, b) => write!(f, "shr {} {}", a, b),
+            Instruction::Sar(a, b) => write!(f, "sar {} {}", a, b),
+            Instruction::Rotl(a, b) => write!(f, "rotl {} {}", a, b),
+            Instruction::Rotr(a, b) => write!(f, "rotr {} {}", a, b),
+        }
+    }
+}
+
+impl Display for Reg {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Reg::SP => "sp",
+            Reg::ST => "st",
+            Reg::A => "a",
+            Reg::B => "b",
+            Reg::C => "c",
+            Reg::D => "d",
+            Reg::E => "e",
+            Reg::F => "f",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl TryFrom<u8> for Reg {
+    type Error = ();
+
+    fn try_from(value: u8) -> Result<Self, ()> {
+        Reg::ALL.get(value as usize).copied().ok_or(())
+    }
+}
+
+// Why the byte code at an offset isn't a valid instruction.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DecodeError {
+    // The opcode doesn't exist.
+    UnknownOpcode {
+        offset: usize,
+        opcode: u8,
+    },
+    // The byte code ends before the operands of the instruction do.
+    Truncated {
+        offset: usize,
+        opcode: u8,
+    },
+    // A register operand isn't one of the eight registers.
+    InvalidRegister {
+        offset: usize,
+        opcode: u8,
+        register: u8,
+    },
+}
+
+impl DecodeError {
+    // The byte code offset of the instruction.
+    pub fn offset(&self) -> usize {
+        match *self {
+            DecodeError::UnknownOpcode { offset, .. }
+            | DecodeError::Truncated { offset, .. }
+            | DecodeError::InvalidRegister { offset, .. } => offset,
+        }
+    }
+}
+
+impl Display for DecodeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::UnknownOpcode { offset, opcode } => {
+                write!(f, "unknown opcode 0x{:02x} at byte 0x{:x}", opcode, offset)
+            }
+            DecodeError::Truncated { offset, opcode } => write!(
+                f,
+                "the byte code ends in the instruction 0x{:02x} at byte 0x{:x}",
+                opcode, offset
+            ),
+            DecodeError::InvalidRegister {
+                offset,
+                opcode,
+                register,
+            } => write!(
+                f,
+                "the instruction 0x{:02x} at byte 0x{:x} uses the invalid register {}",
+                opcode, offset, register
+            ),
+        }
+    }
+}
+
+impl<'a> Iterator for ByteCodeParser<'a> {
+    type Item = Instruction;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_instruction()
+            .map(|instruction| instruction.unwrap_or_else(|error| panic!("{}", error)))
+    }
+}
+
+impl<'a> ByteCodeParser<'a> {
+    // Like next, but returns an error instead of panicking if the instruction
+    // is invalid. In that case, only the opcode byte is consumed.
+    pub fn next_instruction(&mut self) -> Option<Result<Instruction, DecodeError>> {
+        let offset = self.cursor;
+        let opcode = self.eat_byte()?;
+        Some(self.decode(opcode).map_err(|malformed| {
+            self.cursor = offset + 1;
+            match malformed {
+                Malformed::UnknownOpcode => DecodeError::UnknownOpcode { offset, opcode },
+                Malformed::Truncated => DecodeError::Truncated { offset, opcode },
+                Malformed::InvalidRegister(register) => DecodeError::InvalidRegister {
+                    offset,
+                    opcode,
+                    register,
+                },
+            }
+        }))
+    }
+
+    fn decode(&mut self, opcode: u8) -> Result<Instruction, Malformed> {
+        Ok(match opcode {
+            0x00 => Instruction::Nop,
+            0xe0 => Instruction::Panic,
+            0xe1 => Instruction::Breakpoint,
+            0xd0 => {
+                let (a, b) = self.eat_regs()?;
+                Instruction::Move_(a, b)
+            }
+            0xd1 => {
+                let reg = self.eat_reg()?;
+                let value = self.eat_i64()?;
+                Instruction::Movei(reg, value)
+            }
+            0xd2 => {
+                let reg = self.eat_reg()?;
+                let value = self.eat_operand_byte()?;
+                Instruction::Moveib(reg, value)
+            }
+            0xd3 => {
+                let (a, b) = self.eat_regs()?;
+                Instruction::Load(a, b)
+            }
+            0xd4 => {
+                let (a, b) = self.eat_regs()?;
+                Instruction::Loadb(a, b)
+            }
+            0xd5 => {
+                let (a, b) = self.eat_regs()?;
+                Instruction::Store(a, b)
+            }
+            0xd6 => {
+                let (a, b) = self.eat_regs()?;
+                Instruction::Storeb(a, b)
+            }
+            0xd7 => Instruction::Push(self.eat_reg()?),
+            0xd8 => Instruction::Pop(self.eat_reg()?),
+            0xd9 => {
+                let reg = self.eat_reg()?;
+                let value = self.eat_operand_byte()? as i8;
+                Instruction::Moveisb(reg, value)
+            }
+            0xda => {
+                let (a, b) = self.eat_regs()?;
+                Instruction::Swap(a, b)
+            }
+            0xdb => {
+                let (a, b) = self.eat_regs()?;
+                Instruction::Movz(a, b)
+            }
+            0xdc => {
+                let (a, b) = self.eat_regs()?;
+                Instruction::Movnz(a, b)
+            }
+            0xdd => {
+                let (a, b) = self.eat_regs()?;
+                Instruction::Loadh(a, b)
+            }
+            0xde => {
+                let (a, b) = self.eat_regs()?;
+                Instruction::Loadw(a, b)
+            }
+            0xdf => {
+                let (a, b) = self.eat_regs()?;
+                Instruction::Storeh(a, b)
+            }
+            0xe2 => {
+                let (a, b) = self.eat_regs()?;
+                Instruction::Storew(a, b)
+            }
+            0xf0 => Instruction::Jump(self.eat_usize()?),
+            0xf1 => Instruction::Cjump(self.eat_usize()?),
+            0xf2 => Instruction::Call(self.eat_usize()?),
+            0xf3 => Instruction::Ret,
+            0xf4 => Instruction::Syscall(self.eat_operand_byte()?),
+            0xf5 => Instruction::Jumpr(self.eat_relative_target()?),
+            0xf6 => Instruction::Cjumpr(self.eat_relative_target()?),
+            0xf7 => Instruction::Callr(self.eat_relative_target()?),
+            0xc0 => {
+                let (a, b) = self.eat_regs()?;
+                Instruction::Cmp(a, b)
+            }
+            0x90 => Instruction::Cmpz(self.eat_reg()?),
+            0xc1 => Instruction::Isequal,
+            0xc2 => Instruction::Isless,
+            0xc3 => Instruction::Isgreater,
+            0xc4 => Instruction::Islessequal,
+            0xc5 => Instruction::Isgreaterequal,
+            0xce => Instruction::Inttofloat(self.eat_reg()?),
+            0xcf => Instruction::Floattoint(self.eat_reg()?),
+            0xa0 => {
+                let (a, b) = self.eat_regs()?;
+                Instruction::Add(a, b)
+            }
+            0xa1 => {
+                let (a, b) = self.eat_regs()?;
+                Instruction::Sub(a, b)
+            }
+            0xa2 => {
+                let (a, b) = self.eat_regs()?;
+                Instruction::Mul(a, b)
+            }
+            0xa3 => {
+                let (a, b) = self.eat_regs()?;
+                Instruction::Div(a, b)
+            }
+            0xa4 => {
+                let (a, b) = self.eat_regs()?;
+                Instruction::Rem(a, b)
+            }
+            0xa9 => {
+                let (a, b) = self.eat_regs()?;
+                Instruction::Udiv(a, b)
+            }
+            0xaa => {
+                let (a, b) = self.eat_regs()?;
+                Instruction::Urem(a, b)
+            }
+            0xab => {
+                let (a, b) = self.eat_regs()?;
+                Instruction::Addc(a, b)
+            }
+            0xac => {
+                let (a, b) = self.eat_regs()?;
+                Instruction::Subc(a, b)
+            }
+            0xad => {
+                let (a, b) = self.eat_regs()?;
+                Instruction::Mulc(a, b)
+            }
+            0xb0 => {
+                let (a, b) = self.eat_regs()?;
+                Instruction::And(a, b)
+            }
+            0xb1 => {
+                let (a, b) = self.eat_regs()?;
+                Instruction::Or(a, b)
+            }
+            0xb2 => {
+                let (a, b) = self.eat_regs()?;
+                Instruction::Xor(a, b)
+            }
+            0xb3 => Instruction::Negate(self.eat_reg()?),
+            0xb4 => {
+                let (a, b) = self.eat_regs()?;
+                Instruction::Shl(a, b)
+            }
+            0xb5 => {
+                let (a, b) = self.eat_regs()?;
+                Instruction::Shr(a, b)
+            }
+            0xb6 => {
+                let (a, b) = self.eat_regs()?;
+                Instruction::Sar(a, b)
+            }
+            0xb7 => {
+                let (a, b) = self.eat_regs()?;
+                Instruction::Rotl(a, b)
+            }
+            0xb8 => {
+                let (a, b) = self.eat_regs()?;
+                Instruction::Rotr(a, b)
+            }
+            _ => return Err(Malformed::UnknownOpcode),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(byte_code: &[u8]) -> (Option<Result<Instruction, DecodeError>>, usize) {
+        let mut parser = byte_code.byte_code();
+        let instruction = parser.next_instruction();
+        (instruction, parser.cursor)
+    }
+
+    #[test]
+    fn decodes_instructions() {
+        assert_eq!(
+            decode(&[0xd2, 0x02, 7]),
+            (Some(Ok(Instruction::Moveib(Reg::A, 7))), 3)
+        );
+        assert_eq!(decode(&[]), (None, 0));
+    }
+
+    #[test]
+    fn unknown_opcodes_are_errors() {
+        let error = DecodeError::UnknownOpcode {
+            offset: 0,
+            opcode: 0xff,
+        };
+        assert_eq!(decode(&[0xff, 0]), (Some(Err(error)), 1));
+    }
+
+    #[test]
+    fn truncated_operands_are_errors() {
+        for byte_code in [
+            &[0xd0][..],
+            &[0xd1, 0x02, 1, 2, 3],
+            &[0xd2, 0x02],
+            &[0xf0, 1, 2],
+            &[0xf4],
+            &[0xf5, 1, 2, 3],
+        ] {
+            let error = DecodeError::Truncated {
+                offset: 0,
+                opcode: byte_code[0],
+            };
+            assert_eq!(decode(byte_code), (Some(Err(error)), 1));
+        }
+    }
+
+    #[test]
+    fn invalid_registers_are_errors() {
+        let error = DecodeError::InvalidRegister {
+            offset: 0,
+            opcode: 0xa0,
+            register: 8,
+        };
+        assert_eq!(decode(&[0xa0, 0x82]), (Some(Err(error)), 1));
+        let error = DecodeError::InvalidRegister {
+            offset: 0,
+            opcode: 0xd7,
+            register: 15,
+        };
+        assert_eq!(decode(&[0xd7, 0x0f]), (Some(Err(error)), 1));
+    }
+
+    #[test]
+    fn instructions_compare_by_value() {
+        let movei = |value: u64| [&[0xd1, 0x02][..], &value.to_le_bytes()].concat();
+        let (first, _) = decode(&movei(1));
+        let (second, _) = decode(&movei(1));
+        assert_eq!(first, second);
+        let (other, _) = decode(&movei(2));
+        assert_ne!(first, other);
+        assert_ne!(Instruction::Movei(Reg::A, 1), Instruction::Movei(Reg::B, 1));
+    }
+}
@@ -0,0 +1,107 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::{
+    binary::Binary,
+    bytecode::{ByteCode, Instruction},
+};
+
+// Emits a JSON object that maps each function (a debug label) to the
+// functions it may call. This is purely static: it looks at the targets of
+// all Call instructions in the function's body, which reaches from its
+// label to the next one.
+pub fn call_graph(binary: &Binary) -> String {
+    let mut labels = binary.labels.clone();
+    labels.sort_by_key(|(pos, _)| *pos);
+    let function_at = |pos: usize| -> &str {
+        labels
+            .iter()
+            .rev()
+            .find(|(label_pos, _)| *label_pos <= pos)
+            .map_or("(no label)", |(_, label)| label)
+    };
+
+    let mut graph: BTreeMap<&str, BTreeSet<&str>> = BTreeMap::new();
+    for (_, label) in &labels {
+        graph.entry(label).or_default();
+    }
+    let mut byte_code = binary.byte_code.byte_code();
+    loop {
+        let cursor = byte_code.cursor;
+        let instruction = match byte_code.next() {
+            Some(instruction) => instruction,
+            None => break,
+        };
+        if let Instruction::Call(target) | Instruction::Callr(target) = instruction {
+            graph
+                .entry(function_at(cursor))
+                .or_default()
+                .insert(function_at(target));
+        }
+    }
+
+    let mut out = String::new();
+    out.push_str("{\n");
+    for (i, (caller, callees)) in graph.iter().enumerate() {
+        out.push_str(&format!("  {}: [", json_string(caller)));
+        for (j, callee) in callees.iter().enumerate() {
+            if j > 0 {
+                out.push_str(", ");
+            }
+            out.push_str(&json_string(callee));
+        }
+        out.push(']');
+        if i + 1 < graph.len() {
+            out.push(',');
+        }
+        out.push('\n');
+    }
+    out.push('}');
+    out
+}
+
+fn json_string(string: &str) -> String {
+    let mut out = String::from("\"");
+    for c in string.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn call(target: u64) -> Vec<u8> {
+        [&[0xf2][..], &target.to_le_bytes()].concat()
+    }
+
+    #[test]
+    fn maps_functions_to_their_callees() {
+        // The unlabelled start calls main, main calls helper twice.
+        let byte_code = [
+            call(11),
+            vec![0xf4, 0],
+            call(30),
+            call(30),
+            vec![0xf3],
+            vec![0xf3],
+        ]
+        .concat();
+        let binary = Binary {
+            byte_code,
+            labels: vec![(30, "helper".to_string()), (11, "main".to_string())],
+            ..Default::default()
+        };
+        assert_eq!(
+            call_graph(&binary),
+            "{\n  \"(no label)\": [\"main\"],\n  \"helper\": [],\n  \"main\": [\"helper\"]\n}"
+        );
+    }
+}
@@ -0,0 +1,58 @@
+use alloc::vec::Vec;
+
+use crate::utils::WordFromByteSlice;
+
+// Describes how native backends lay out the call stack in their data
+// segment, so that debuggers can decode it from a running (or crashed)
+// program:
+//
+// - capacity entries, each a return offset into the byte code
+// - the number of entries in use (8 bytes)
+pub struct CallStackLayout {
+    pub capacity: usize,
+    pub entry_size: usize,
+}
+
+impl CallStackLayout {
+    pub const NATIVE: CallStackLayout = CallStackLayout {
+        capacity: 1024,
+        entry_size: 8,
+    };
+
+    pub fn len_offset(&self) -> usize {
+        self.capacity * self.entry_size
+    }
+
+    // Given the bytes of a whole call stack region, returns the saved return
+    // offsets, outermost first.
+    pub fn decode(&self, region: &[u8]) -> Vec<usize> {
+        let len = (region.word_at(self.len_offset()) as usize).min(self.capacity);
+        (0..len)
+            .map(|i| region.word_at(i * self.entry_size) as usize)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::WordFromByteSlice;
+    use alloc::vec;
+
+    #[test]
+    fn decodes_the_used_entries() {
+        let layout = CallStackLayout::NATIVE;
+        let mut region = vec![0; layout.len_offset() + 8];
+        for (i, offset) in [0x12, 0x34, 0x56].into_iter().enumerate() {
+            region.set_word_at(i * layout.entry_size, offset);
+        }
+        // A stale entry from a call that already returned.
+        region.set_word_at(3 * layout.entry_size, 0x78);
+        region.set_word_at(layout.len_offset(), 3);
+        assert_eq!(layout.decode(&region), vec![0x12, 0x34, 0x56]);
+
+        // A corrupted length doesn't read past the region.
+        region.set_word_at(layout.len_offset(), i64::MAX);
+        assert_eq!(layout.decode(&region).len(), layout.capacity);
+    }
+}
@@ -1,32 +1,145 @@
+use std::fmt::{self, Display, Formatter};
+
 use extension_trait::extension_trait;
 
-use crate::{binary::Binary, utils::WordFromByteSlice};
+use crate::{
+    binary::{ArithmeticMode, Binary},
+    bytecode::{ByteCode, Instruction, Reg},
+    call_stack::CallStackLayout,
+    exit_status::PANIC_EXIT_STATUS,
+};
 
-const MEMORY_SIZE: usize = 1000;
+// Used if the memory size isn't given explicitly.
+pub const DEFAULT_MEMORY_SIZE: usize = 16 << 20;
+// Programs start with the slice of their arguments on the stack, which takes
+// 16 bytes.
+pub const MIN_MEMORY_SIZE: usize = 16;
 
-// Compiles the program into a function with the following signature:
-//
-// ```
-// program(u8* memory, i64 memory_len, i64* call_stack) -> u8
-// ```
-//
-// The return value indicates what the program did:
-// 0: exit
-// 1: panicked
-pub fn compile(binary: Binary) -> String {
+#[derive(Debug, PartialEq, Eq)]
+pub enum CompileError {
+    // The initial memory doesn't fit into the memory.
+    InitialMemoryTooLarge { len: usize, memory_size: usize },
+}
+
+impl Display for CompileError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            CompileError::InitialMemoryTooLarge { len, memory_size } => write!(
+                f,
+                "the initial memory is {} bytes, but the memory size is only {} bytes",
+                len, memory_size
+            ),
+        }
+    }
+}
+
+// All backends reserve the memory up front, so the initial memory has to fit.
+pub fn check_memory_size(binary: &Binary, options: &CompileOptions) -> Result<(), CompileError> {
+    if binary.memory.len() > options.memory_size {
+        return Err(CompileError::InitialMemoryTooLarge {
+            len: binary.memory.len(),
+            memory_size: options.memory_size,
+        });
+    }
+    Ok(())
+}
+
+pub struct CompileOptions {
+    // If set, the code of every function (every label in the label table)
+    // starts at a multiple of this many bytes. Must be a power of two.
+    pub align_functions: Option<usize>,
+    // Size of the memory in bytes. The stack starts at its end.
+    pub memory_size: usize,
+    // If set, load and store panic instead of accessing an address outside
+    // of the memory.
+    pub check_bounds: bool,
+    // If set, the stack may only use this many bytes at the end of the
+    // memory. Otherwise, it may grow down to the end of the initial memory.
+    // Pushing beyond that panics.
+    pub stack_size: Option<usize>,
+    // If set, breakpoints trap so that a native debugger stops there. Without
+    // a debugger, the trap kills the program, so they do nothing by default.
+    pub breakpoints: bool,
+    pub emit: Emit,
+}
+
+impl Default for CompileOptions {
+    fn default() -> Self {
+        Self {
+            align_functions: None,
+            memory_size: DEFAULT_MEMORY_SIZE,
+            check_bounds: false,
+            stack_size: None,
+            breakpoints: false,
+            emit: Emit::Executable,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Emit {
+    // A static Linux executable that runs the program and exits.
+    Executable,
+    // An object file exporting the program as a function that C code can
+    // call and that returns the exit status (or 1 if the program panicked):
+    //
+    // ```
+    // int64_t soil_main(void);
+    // ```
+    //
+    // The code addresses the memory absolutely, so link with -no-pie. The
+    // memory isn't reset between calls, and the arg syscall has no arguments
+    // to return.
+    Object,
+}
+
+// The registers the System V ABI requires soil_main to preserve.
+const CALLEE_SAVED: [&str; 6] = ["rbx", "rbp", "r12", "r13", "r14", "r15"];
+
+pub fn compile_with_options(
+    binary: Binary,
+    options: &CompileOptions,
+) -> Result<String, CompileError> {
+    check_memory_size(&binary, options)?;
     let mut out = String::new();
 
     out.push_str("; fasm\n");
-    out.push_str("format ELF64 executable\n");
-    out.push_str("segment readable executable\n");
+    match options.emit {
+        Emit::Executable => {
+            out.push_str("format ELF64 executable\n");
+            out.push_str("segment readable executable\n");
 
-    for reg in REGS {
-        out.push_str(&format!("{:7}mov {}, {}\n", "", reg.to_asm(), match reg {
-            Reg::SP => MEMORY_SIZE,
-            _ => 0,
-        }));
+            // Linux puts argc at the top of the stack, followed by the argv
+            // pointers.
+            out.push_str(&format!("{:7}mov rax, [rsp]\n", ""));
+            out.push_str(&format!("{:7}mov [saved_argc], rax\n", ""));
+            out.push_str(&format!("{:7}lea rax, [rsp + 8]\n", ""));
+            out.push_str(&format!("{:7}mov [saved_argv], rax\n", ""));
+        }
+        Emit::Object => {
+            out.push_str("format ELF64\n");
+            out.push_str("public soil_main\n");
+            out.push_str("section '.text' executable\n");
+            out.push_str("soil_main:\n");
+            for reg in CALLEE_SAVED {
+                out.push_str(&format!("{:7}push {}\n", "", reg));
+            }
+            out.push_str(&format!("{:7}mov [saved_rsp], rsp\n", ""));
+            out.push_str(&format!("{:7}mov qword [call_stack.len], 0\n", ""));
+        }
     }
 
+    for reg in Reg::ALL {
+        let value = initial_value(&binary, reg, options.memory_size);
+        out.push_str(&format!("{:7}mov {}, {}\n", "", reg.to_asm(), value));
+    }
+    if binary.entry_point() != 0 {
+        out.push_str(&format!("{:7}jmp i{}\n", "", binary.entry_point()));
+    }
+
+    // The offsets that calls save in the call_stack, for the backtrace.
+    let mut return_offsets = vec![];
+
     let mut byte_code = binary.byte_code.byte_code();
     loop {
         let cursor = byte_code.cursor;
@@ -34,126 +147,92 @@ pub fn compile(binary: Binary) -> String {
             Some(instruction) => instruction,
             None => break,
         };
+        if let Instruction::Call(_) | Instruction::Callr(_) = instruction {
+            return_offsets.push(byte_code.cursor);
+        }
 
-        out.push_str(&format!("{:7}", format!("i{}: ", cursor)));
-        match instruction {
-            Instruction::Nop => {}
-            Instruction::Panic => out.push_str("call panic\n"),
-            Instruction::Move_(a, b) => {
-                out.push_str(&format!("mov {}, {}\n", a.to_asm(), b.to_asm()))
-            }
-            Instruction::Movei(a, value) => {
-                out.push_str(&format!("mov {}, {}\n", a.to_asm(), value))
-            }
-            Instruction::Moveib(a, value) => {
-                out.push_str(&format!("mov {}, {}\n", a.to_asm(), value))
-            }
-            Instruction::Load(a, b) => {
-                out.push_str(&format!("mov {}, [memory + {}]\n", a.to_asm(), b.to_asm()))
-            }
-            Instruction::Loadb(a, b) => {
-                out.push_str(&format!("mov {}b, [memory + {}]\n", a.to_asm(), b.to_asm()))
-            }
-            Instruction::Store(a, b) => {
-                out.push_str(&format!("mov [memory + {}], {}\n", a.to_asm(), b.to_asm()))
-            }
-            Instruction::Storeb(a, b) => {
-                out.push_str(&format!("mov [memory + {}], {}b\n", a.to_asm(), b.to_asm()))
-            }
-            Instruction::Push(a) => out.push_str(&format!("push {}\n", a.to_asm())),
-            Instruction::Pop(a) => out.push_str(&format!("pop {}\n", a.to_asm())),
-            Instruction::Jump(target) => out.push_str(&format!("jmp i{}\n", target)),
-            Instruction::Cjump(target) => {
-                out.push_str(&format!("cmp r9, 0\n"));
-                out.push_str(&format!("{:7}jnz i{}\n", "", target))
-            }
-            Instruction::Call(target) => out.push_str(&format!("call i{}\n", target)),
-            Instruction::Ret => out.push_str("ret\n"),
-            Instruction::Syscall(number) => out.push_str(&format!("call syscall_{}\n", number)),
-            Instruction::Cmp(a, b) => {
-                out.push_str(&format!("mov r9, {}\n", a.to_asm()));
-                out.push_str(&format!("{:7}sub r9, {}\n", "", b.to_asm()))
-            }
-            Instruction::Isequal => {
-                out.push_str("mov rax, 0\n");
-                out.push_str(&format!("{:7}mov rbx, 1\n", ""));
-                out.push_str(&format!("{:7}cmove r9, rbx\n", ""));
-                out.push_str(&format!("{:7}cmovne r9, rax\n", ""))
-            }
-            Instruction::Isless => {
-                out.push_str("mov rax, 0\n");
-                out.push_str(&format!("{:7}mov rbx, 1\n", ""));
-                out.push_str(&format!("{:7}cmovb r9, rbx\n", ""));
-                out.push_str(&format!("{:7}cmovnb r9, rax\n", ""))
-            }
-            Instruction::Isgreater => {
-                out.push_str("mov rax, 0\n");
-                out.push_str(&format!("{:7}mov rbx, 1\n", ""));
-                out.push_str(&format!("{:7}cmova r9, rbx\n", ""));
-                out.push_str(&format!("{:7}cmovna r9, rax\n", ""))
-            }
-            Instruction::Islessequal => {
-                out.push_str("mov rax, 0\n");
-                out.push_str(&format!("{:7}mov rbx, 1\n", ""));
-                out.push_str(&format!("{:7}cmovbe r9, rbx\n", ""));
-                out.push_str(&format!("{:7}cmovnbe r9, rax\n", ""))
-            }
-            Instruction::Isgreaterequal => {
-                out.push_str("mov rax, 0\n");
-                out.push_str(&format!("{:7}mov rbx, 1\n", ""));
-                out.push_str(&format!("{:7}cmovae r9, rbx\n", ""));
-                out.push_str(&format!("{:7}cmovnae r9, rax\n", ""))
+        if let Some(alignment) = options.align_functions {
+            if binary.labels.iter().any(|(pos, _)| *pos == cursor) {
+                out.push_str(&format!("{:7}align {}\n", "", alignment));
             }
-            Instruction::Add(a, b) => {
-                out.push_str(&format!("add {}, {}\n", a.to_asm(), b.to_asm()))
-            }
-            Instruction::Sub(a, b) => {
-                out.push_str(&format!("sub {}, {}\n", a.to_asm(), b.to_asm()))
-            }
-            Instruction::Mul(a, b) => {
-                out.push_str(&format!("mul {}, {}\n", a.to_asm(), b.to_asm()))
-            }
-            Instruction::Div(a, b) => {
-                out.push_str(&format!("div {}, {}\n", a.to_asm(), b.to_asm()))
-            }
-            Instruction::Rem(a, b) => {
-                out.push_str(&format!("div {}, {}\n", a.to_asm(), b.to_asm()))
-            }
-            Instruction::And(a, b) => {
-                out.push_str(&format!("and {}, {}\n", a.to_asm(), b.to_asm()))
-            }
-            Instruction::Or(a, b) => out.push_str(&format!("or {}, {}\n", a.to_asm(), b.to_asm())),
-            Instruction::Xor(a, b) => {
-                out.push_str(&format!("xor {}, {}\n", a.to_asm(), b.to_asm()))
+        }
+        annotate(&mut out, cursor, &binary);
+        out.push_str(&format!("{:7}", format!("i{}: ", cursor)));
+        compile_instruction(&mut out, cursor, instruction, &binary, options);
+    }
+
+    // Ends the program with the exit status in rdi.
+    out.push_str("exit:\n");
+    match options.emit {
+        Emit::Executable => {
+            out.push_str(&format!("{:7}mov rax, 60\n", ""));
+            out.push_str(&format!("{:7}syscall\n", ""));
+        }
+        Emit::Object => {
+            out.push_str(&format!("{:7}mov rax, rdi\n", ""));
+            out.push_str(&format!("{:7}mov rsp, [saved_rsp]\n", ""));
+            for reg in CALLEE_SAVED.iter().rev() {
+                out.push_str(&format!("{:7}pop {}\n", "", reg));
             }
-            Instruction::Negate(a) => out.push_str(&format!("neg {}\n", a.to_asm())),
+            out.push_str(&format!("{:7}ret\n", ""));
         }
     }
 
+    // Prints the call stack to stderr like the interpreter does, outermost
+    // call first. The lines for all return offsets are prepared at compile
+    // time in the backtrace table, so this only needs to look them up.
     out.push_str(&format!("{:7}", "panic:"));
-    out.push_str(&format!("mov rax, 60\n"));
-    out.push_str(&format!("{:7}mov rdi, 1\n", ""));
+    out.push_str("mov rax, 1\n");
+    out.push_str(&format!("{:7}mov rdi, 2\n", ""));
+    out.push_str(&format!("{:7}mov rsi, panic_message\n", ""));
+    out.push_str(&format!("{:7}mov rdx, {}\n", "", PANIC_MESSAGE.len()));
     out.push_str(&format!("{:7}syscall\n", ""));
-    out.push_str(&format!("{:7}ret\n", ""));
+    out.push_str(&format!("{:7}xor rbx, rbx\n", ""));
+    out.push_str(".frame:\n");
+    out.push_str(&format!("{:7}cmp rbx, [call_stack.len]\n", ""));
+    out.push_str(&format!("{:7}jae .done\n", ""));
+    out.push_str(&format!("{:7}mov rax, [call_stack + rbx * 8]\n", ""));
+    out.push_str(&format!("{:7}mov rsi, backtrace_table\n", ""));
+    out.push_str(".search:\n");
+    out.push_str(&format!("{:7}cmp rsi, backtrace_table.end\n", ""));
+    out.push_str(&format!("{:7}jae .next\n", ""));
+    out.push_str(&format!("{:7}cmp rax, [rsi]\n", ""));
+    out.push_str(&format!("{:7}je .found\n", ""));
+    out.push_str(&format!("{:7}add rsi, 24\n", ""));
+    out.push_str(&format!("{:7}jmp .search\n", ""));
+    out.push_str(".found:\n");
+    out.push_str(&format!("{:7}mov rax, 1\n", ""));
+    out.push_str(&format!("{:7}mov rdi, 2\n", ""));
+    out.push_str(&format!("{:7}mov rdx, [rsi + 16]\n", ""));
+    out.push_str(&format!("{:7}mov rsi, [rsi + 8]\n", ""));
+    out.push_str(&format!("{:7}syscall\n", ""));
+    out.push_str(".next:\n");
+    out.push_str(&format!("{:7}inc rbx\n", ""));
+    out.push_str(&format!("{:7}jmp .frame\n", ""));
+    out.push_str(".done:\n");
+    let status = match options.emit {
+        Emit::Executable => PANIC_EXIT_STATUS,
+        Emit::Object => 1,
+    };
+    out.push_str(&format!("{:7}mov rdi, {}\n", "", status));
+    out.push_str(&format!("{:7}jmp exit\n", ""));
 
     fn save_registers(out: &mut String) {
-        for reg in REGS {
+        for reg in Reg::ALL {
             out.push_str(&format!("{:7}push {}\n", "", reg.to_asm()));
         }
     }
     fn restore_registers(out: &mut String) {
-        for reg in REGS
-        .iter()
-        .rev()
-        {
+        for reg in Reg::ALL.iter().rev() {
             out.push_str(&format!("{:7}pop {}\n", "", reg.to_asm()));
         }
     }
 
+    // Syscalls get their arguments in a, b, c, and d (r10 to r13), see
+    // syscalls.md. Buffers are an offset into the memory and a length.
     out.push_str("syscall_0: ; exit\n");
-    out.push_str(&format!("{:7}mov rax, 60\n", ""));
-    out.push_str(&format!("{:7}mov rdi, 0\n", ""));
-    out.push_str(&format!("{:7}syscall\n", ""));
+    out.push_str(&format!("{:7}mov rdi, r10\n", ""));
+    out.push_str(&format!("{:7}jmp exit\n", ""));
 
     out.push_str("syscall_1: ; print\n");
     save_registers(&mut out);
@@ -165,7 +244,7 @@ pub fn compile(binary: Binary) -> String {
     out.push_str(&format!("{:7}syscall\n", ""));
     restore_registers(&mut out);
     out.push_str(&format!("{:7}ret\n", ""));
-    
+
     out.push_str("syscall_2: ; log\n");
     save_registers(&mut out);
     out.push_str(&format!("{:7}mov rax, 1\n", ""));
@@ -177,27 +256,595 @@ pub fn compile(binary: Binary) -> String {
     restore_registers(&mut out);
     out.push_str(&format!("{:7}ret\n", ""));
 
-    out.push_str("segment readable writable\n");
+    out.push_str("syscall_7: ; write\n");
+    save_registers(&mut out);
+    out.push_str(&format!("{:7}mov rax, 1\n", ""));
+    out.push_str(&format!("{:7}mov rdi, r10\n", ""));
+    out.push_str(&format!("{:7}mov rsi, r11\n", ""));
+    out.push_str(&format!("{:7}add rsi, memory\n", ""));
+    out.push_str(&format!("{:7}mov rdx, r12\n", ""));
+    out.push_str(&format!("{:7}syscall\n", ""));
+    restore_registers(&mut out);
+    out.push_str(&format!("{:7}mov r10, rax\n", ""));
+    out.push_str(&format!("{:7}ret\n", ""));
+
+    // Compiled programs can't receive signals, so none are ever pending.
+    out.push_str("syscall_15: ; poll_signals\n");
+    out.push_str(&format!("{:7}xor r10, r10\n", ""));
+    out.push_str(&format!("{:7}ret\n", ""));
+
+    // Uses clock_gettime with CLOCK_MONOTONIC, which fills a timespec of
+    // seconds and nanoseconds.
+    out.push_str("syscall_16: ; instant\n");
+    save_registers(&mut out);
+    out.push_str(&format!("{:7}sub rsp, 16\n", ""));
+    out.push_str(&format!("{:7}mov rax, 228\n", ""));
+    out.push_str(&format!("{:7}mov rdi, 1\n", ""));
+    out.push_str(&format!("{:7}mov rsi, rsp\n", ""));
+    out.push_str(&format!("{:7}syscall\n", ""));
+    out.push_str(&format!("{:7}mov rax, [rsp]\n", ""));
+    out.push_str(&format!("{:7}imul rax, 1000000000\n", ""));
+    out.push_str(&format!("{:7}add rax, [rsp + 8]\n", ""));
+    out.push_str(&format!("{:7}add rsp, 16\n", ""));
+    restore_registers(&mut out);
+    out.push_str(&format!("{:7}mov r10, rax\n", ""));
+    out.push_str(&format!("{:7}ret\n", ""));
+
+    out.push_str("syscall_9: ; argc\n");
+    out.push_str(&format!("{:7}mov r10, [saved_argc]\n", ""));
+    out.push_str(&format!("{:7}ret\n", ""));
+
+    // Copies the null-terminated argument into the buffer, stopping early if
+    // the buffer is full.
+    out.push_str("syscall_10: ; arg\n");
+    out.push_str(&format!("{:7}cmp r10, [saved_argc]\n", ""));
+    out.push_str(&format!("{:7}jae panic\n", ""));
+    out.push_str(&format!("{:7}mov rax, [saved_argv]\n", ""));
+    out.push_str(&format!("{:7}mov rax, [rax + r10 * 8]\n", ""));
+    out.push_str(&format!("{:7}xor rbx, rbx\n", ""));
+    out.push_str(".copy:\n");
+    out.push_str(&format!("{:7}cmp rbx, r12\n", ""));
+    out.push_str(&format!("{:7}jae .done\n", ""));
+    out.push_str(&format!("{:7}mov dl, [rax + rbx]\n", ""));
+    out.push_str(&format!("{:7}test dl, dl\n", ""));
+    out.push_str(&format!("{:7}jz .done\n", ""));
+    out.push_str(&format!("{:7}mov [r11 + rbx + memory], dl\n", ""));
+    out.push_str(&format!("{:7}inc rbx\n", ""));
+    out.push_str(&format!("{:7}jmp .copy\n", ""));
+    out.push_str(".done:\n");
+    out.push_str(&format!("{:7}mov r10, rbx\n", ""));
+    out.push_str(&format!("{:7}ret\n", ""));
+
+    // Soil memory lives in its own segment. All memory accesses are relative
+    // to the memory label, so the (read-only) code segment is never reachable
+    // from a Load or Store.
+    match options.emit {
+        Emit::Executable => out.push_str("segment readable writable\n"),
+        Emit::Object => {
+            out.push_str("section '.data' writeable align 16\n");
+            out.push_str("saved_rsp: dq 0\n");
+        }
+    }
+    out.push_str("saved_argc: dq 0\n");
+    out.push_str("saved_argv: dq 0\n");
     out.push_str("call_stack:\n");
-    out.push_str("  dq 1024 dup 8\n");
+    out.push_str(&format!(
+        "  dq {} dup 0\n",
+        CallStackLayout::NATIVE.capacity
+    ));
     out.push_str(".len:\n");
     out.push_str("  dq 0\n");
+    bytes(&mut out, "panic_message", PANIC_MESSAGE.as_bytes());
+    // Each entry is a return offset followed by the address and length of
+    // its line.
+    out.push_str("backtrace_table:\n");
+    for (i, offset) in return_offsets.iter().enumerate() {
+        let line = backtrace_line(&binary, *offset);
+        out.push_str(&format!(
+            "  dq {}, backtrace_{}, {}\n",
+            offset,
+            i,
+            line.len()
+        ));
+    }
+    out.push_str(".end:\n");
+    for (i, offset) in return_offsets.iter().enumerate() {
+        let line = backtrace_line(&binary, *offset);
+        bytes(&mut out, &format!("backtrace_{}", i), line.as_bytes());
+    }
     out.push_str("memory:\n");
-    if !binary.memory.is_empty() {
-        out.push_str("  db");
-        let mut is_first = true;
-        for byte in &binary.memory {
-            out.push_str(&format!("{} {}", if is_first { "" } else { "," }, byte));
-            is_first = false;
+    for chunk in memory_chunks(&binary.memory) {
+        match chunk {
+            MemoryChunk::Run { byte, len } => out.push_str(&format!("  db {} dup {}\n", len, byte)),
+            MemoryChunk::Literal(bytes) => {
+                for line in bytes.chunks(32) {
+                    let bytes: Vec<_> = line.iter().map(|byte| byte.to_string()).collect();
+                    out.push_str(&format!("  db {}\n", bytes.join(", ")));
+                }
+            }
+        }
+    }
+    out.push_str(&format!(
+        "  rb {}",
+        options.memory_size - binary.memory.len()
+    ));
+
+    Ok(out)
+}
+
+fn compile_instruction(
+    out: &mut String,
+    cursor: usize,
+    instruction: Instruction,
+    binary: &Binary,
+    options: &CompileOptions,
+) {
+    match instruction {
+        Instruction::Nop => {}
+        Instruction::Panic => out.push_str("call panic\n"),
+        Instruction::Breakpoint => {
+            if options.breakpoints {
+                out.push_str("int3\n")
+            }
+        }
+        Instruction::Move_(a, b) => out.push_str(&format!("mov {}, {}\n", a.to_asm(), b.to_asm())),
+        Instruction::Movei(a, value) => out.push_str(&format!("mov {}, {}\n", a.to_asm(), value)),
+        Instruction::Moveib(a, value) => out.push_str(&format!("mov {}, {}\n", a.to_asm(), value)),
+        Instruction::Moveisb(a, value) => out.push_str(&format!("mov {}, {}\n", a.to_asm(), value)),
+        Instruction::Swap(a, b) => out.push_str(&format!("xchg {}, {}\n", a.to_asm(), b.to_asm())),
+        Instruction::Movz(a, b) => {
+            out.push_str("cmp r9, 0\n");
+            out.push_str(&format!("{:7}cmovz {}, {}\n", "", a.to_asm(), b.to_asm()))
+        }
+        Instruction::Movnz(a, b) => {
+            out.push_str("cmp r9, 0\n");
+            out.push_str(&format!("{:7}cmovnz {}, {}\n", "", a.to_asm(), b.to_asm()))
+        }
+        Instruction::Load(a, b) => {
+            check_bounds(out, b, 8, options);
+            out.push_str(&format!("mov {}, [memory + {}]\n", a.to_asm(), b.to_asm()))
+        }
+        Instruction::Loadb(a, b) => {
+            check_bounds(out, b, 1, options);
+            out.push_str(&format!(
+                "movzx {}, byte [memory + {}]\n",
+                a.to_asm(),
+                b.to_asm()
+            ))
+        }
+        Instruction::Store(a, b) => {
+            check_bounds(out, a, 8, options);
+            out.push_str(&format!("mov [memory + {}], {}\n", a.to_asm(), b.to_asm()))
+        }
+        Instruction::Storeb(a, b) => {
+            check_bounds(out, a, 1, options);
+            out.push_str(&format!(
+                "mov byte [memory + {}], {}b\n",
+                a.to_asm(),
+                b.to_asm()
+            ))
+        }
+        Instruction::Loadh(a, b) => {
+            check_bounds(out, b, 2, options);
+            out.push_str(&format!(
+                "movzx {}, word [memory + {}]\n",
+                a.to_asm(),
+                b.to_asm()
+            ))
+        }
+        // Writing the lower 32 bits of a register zeroes the upper ones.
+        Instruction::Loadw(a, b) => {
+            check_bounds(out, b, 4, options);
+            out.push_str(&format!(
+                "mov {}d, dword [memory + {}]\n",
+                a.to_asm(),
+                b.to_asm()
+            ))
+        }
+        Instruction::Storeh(a, b) => {
+            check_bounds(out, a, 2, options);
+            out.push_str(&format!(
+                "mov word [memory + {}], {}w\n",
+                a.to_asm(),
+                b.to_asm()
+            ))
+        }
+        Instruction::Storew(a, b) => {
+            check_bounds(out, a, 4, options);
+            out.push_str(&format!(
+                "mov dword [memory + {}], {}d\n",
+                a.to_asm(),
+                b.to_asm()
+            ))
+        }
+        // The soil stack lives in the soil memory, not on the native stack.
+        Instruction::Push(a) => {
+            out.push_str("sub r8, 8\n");
+            out.push_str(&format!(
+                "{:7}mov rax, {}\n",
+                "",
+                stack_limit(binary, options)
+            ));
+            out.push_str(&format!("{:7}cmp r8, rax\n", ""));
+            out.push_str(&format!("{:7}jl panic\n", ""));
+            out.push_str(&format!("{:7}mov [memory + r8], {}\n", "", a.to_asm()))
+        }
+        Instruction::Pop(a) => {
+            // Popping from an empty stack reads past the end of the memory.
+            check_bounds(out, Reg::SP, 8, options);
+            out.push_str("mov rax, [memory + r8]\n");
+            out.push_str(&format!("{:7}add r8, 8\n", ""));
+            out.push_str(&format!("{:7}mov {}, rax\n", "", a.to_asm()))
+        }
+        Instruction::Jump(target) | Instruction::Jumpr(target) => {
+            out.push_str(&format!("jmp i{}\n", target))
+        }
+        Instruction::Cjump(target) | Instruction::Cjumpr(target) => {
+            out.push_str("cmp r9, 0\n");
+            out.push_str(&format!("{:7}jnz i{}\n", "", target))
+        }
+        // Besides the native call, calls record their return offset in the
+        // call_stack (see CallStackLayout), so that debuggers can decode it
+        // and deep recursion panics instead of overflowing the native stack.
+        Instruction::Call(target) | Instruction::Callr(target) => {
+            let len = if let Instruction::Call(_) = instruction {
+                9
+            } else {
+                5
+            };
+            out.push_str("mov rax, [call_stack.len]\n");
+            out.push_str(&format!(
+                "{:7}cmp rax, {}\n",
+                "",
+                CallStackLayout::NATIVE.capacity
+            ));
+            out.push_str(&format!("{:7}jae panic\n", ""));
+            out.push_str(&format!(
+                "{:7}mov qword [call_stack + rax * 8], {}\n",
+                "",
+                cursor + len
+            ));
+            out.push_str(&format!("{:7}inc qword [call_stack.len]\n", ""));
+            out.push_str(&format!("{:7}call i{}\n", "", target))
+        }
+        Instruction::Ret => {
+            out.push_str("dec qword [call_stack.len]\n");
+            out.push_str(&format!("{:7}ret\n", ""))
+        }
+        Instruction::Syscall(number) => out.push_str(&format!("call syscall_{}\n", number)),
+        Instruction::Cmp(a, b) => {
+            out.push_str(&format!("mov r9, {}\n", a.to_asm()));
+            out.push_str(&format!("{:7}sub r9, {}\n", "", b.to_asm()))
+        }
+        Instruction::Cmpz(a) => out.push_str(&format!("mov r9, {}\n", a.to_asm())),
+        // The is* instructions compare st against zero themselves instead of
+        // relying on flags, because other instructions may run between the
+        // cmp and them.
+        Instruction::Isequal => {
+            out.push_str("cmp r9, 0\n");
+            out.push_str(&format!("{:7}mov rax, 0\n", ""));
+            out.push_str(&format!("{:7}mov rbx, 1\n", ""));
+            out.push_str(&format!("{:7}cmove r9, rbx\n", ""));
+            out.push_str(&format!("{:7}cmovne r9, rax\n", ""))
+        }
+        Instruction::Isless => {
+            out.push_str("cmp r9, 0\n");
+            out.push_str(&format!("{:7}mov rax, 0\n", ""));
+            out.push_str(&format!("{:7}mov rbx, 1\n", ""));
+            out.push_str(&format!("{:7}cmovl r9, rbx\n", ""));
+            out.push_str(&format!("{:7}cmovnl r9, rax\n", ""))
+        }
+        Instruction::Isgreater => {
+            out.push_str("cmp r9, 0\n");
+            out.push_str(&format!("{:7}mov rax, 0\n", ""));
+            out.push_str(&format!("{:7}mov rbx, 1\n", ""));
+            out.push_str(&format!("{:7}cmovg r9, rbx\n", ""));
+            out.push_str(&format!("{:7}cmovng r9, rax\n", ""))
+        }
+        Instruction::Islessequal => {
+            out.push_str("cmp r9, 0\n");
+            out.push_str(&format!("{:7}mov rax, 0\n", ""));
+            out.push_str(&format!("{:7}mov rbx, 1\n", ""));
+            out.push_str(&format!("{:7}cmovle r9, rbx\n", ""));
+            out.push_str(&format!("{:7}cmovnle r9, rax\n", ""))
         }
-        out.push_str("\n");
+        Instruction::Isgreaterequal => {
+            out.push_str("cmp r9, 0\n");
+            out.push_str(&format!("{:7}mov rax, 0\n", ""));
+            out.push_str(&format!("{:7}mov rbx, 1\n", ""));
+            out.push_str(&format!("{:7}cmovge r9, rbx\n", ""));
+            out.push_str(&format!("{:7}cmovnge r9, rax\n", ""))
+        }
+        Instruction::Inttofloat(a) => {
+            out.push_str(&format!("cvtsi2sd xmm0, {}\n", a.to_asm()));
+            out.push_str(&format!("{:7}movq {}, xmm0\n", "", a.to_asm()))
+        }
+        Instruction::Floattoint(a) => {
+            // cvttsd2si produces 0x8000000000000000 for NaN and for every
+            // value out of range. Fix those up so that the conversion
+            // saturates and NaN becomes 0.
+            out.push_str(&format!("movq xmm0, {}\n", a.to_asm()));
+            out.push_str(&format!("{:7}cvttsd2si {}, xmm0\n", "", a.to_asm()));
+            out.push_str(&format!("{:7}mov rax, 0x8000000000000000\n", ""));
+            out.push_str(&format!("{:7}cmp {}, rax\n", "", a.to_asm()));
+            out.push_str(&format!("{:7}jne i{}_done\n", "", cursor));
+            out.push_str(&format!("{:7}ucomisd xmm0, xmm0\n", ""));
+            out.push_str(&format!("{:7}jp i{}_nan\n", "", cursor));
+            out.push_str(&format!("{:7}xorpd xmm1, xmm1\n", ""));
+            out.push_str(&format!("{:7}ucomisd xmm0, xmm1\n", ""));
+            out.push_str(&format!("{:7}jb i{}_done\n", "", cursor));
+            out.push_str(&format!("{:7}mov {}, 0x7fffffffffffffff\n", "", a.to_asm()));
+            out.push_str(&format!("{:7}jmp i{}_done\n", "", cursor));
+            out.push_str(&format!("i{}_nan:\n", cursor));
+            out.push_str(&format!("{:7}mov {}, 0\n", "", a.to_asm()));
+            out.push_str(&format!("i{}_done:\n", cursor))
+        }
+        Instruction::Add(a, b) => {
+            out.push_str(&format!("add {}, {}\n", a.to_asm(), b.to_asm()));
+            if binary.arithmetic_mode == ArithmeticMode::Trapping {
+                out.push_str(&format!("{:7}jo panic\n", ""))
+            }
+        }
+        Instruction::Sub(a, b) => {
+            out.push_str(&format!("sub {}, {}\n", a.to_asm(), b.to_asm()));
+            if binary.arithmetic_mode == ArithmeticMode::Trapping {
+                out.push_str(&format!("{:7}jo panic\n", ""))
+            }
+        }
+        Instruction::Mul(a, b) => {
+            out.push_str(&format!("imul {}, {}\n", a.to_asm(), b.to_asm()));
+            if binary.arithmetic_mode == ArithmeticMode::Trapping {
+                out.push_str(&format!("{:7}jo panic\n", ""))
+            }
+        }
+        // idiv divides rdx:rax by its operand, leaving the quotient in rax and
+        // the remainder in rdx. Dividing by zero would raise SIGFPE, so that
+        // panics first. So would i64::MIN / -1, so dividing by -1 negates
+        // instead, which wraps the same way.
+        Instruction::Div(a, b) => {
+            out.push_str(&format!("test {}, {}\n", b.to_asm(), b.to_asm()));
+            out.push_str(&format!("{:7}jz panic\n", ""));
+            out.push_str(&format!("{:7}cmp {}, -1\n", "", b.to_asm()));
+            out.push_str(&format!("{:7}jne i{}_div\n", "", cursor));
+            out.push_str(&format!("{:7}neg {}\n", "", a.to_asm()));
+            if binary.arithmetic_mode == ArithmeticMode::Trapping {
+                out.push_str(&format!("{:7}jo panic\n", ""))
+            }
+            out.push_str(&format!("{:7}jmp i{}_done\n", "", cursor));
+            out.push_str(&format!("i{}_div:\n", cursor));
+            out.push_str(&format!("{:7}mov rax, {}\n", "", a.to_asm()));
+            out.push_str(&format!("{:7}cqo\n", ""));
+            out.push_str(&format!("{:7}idiv {}\n", "", b.to_asm()));
+            out.push_str(&format!("{:7}mov {}, rax\n", "", a.to_asm()));
+            out.push_str(&format!("i{}_done:\n", cursor))
+        }
+        // Any number divided by -1 leaves no remainder.
+        Instruction::Rem(a, b) => {
+            out.push_str(&format!("test {}, {}\n", b.to_asm(), b.to_asm()));
+            out.push_str(&format!("{:7}jz panic\n", ""));
+            out.push_str(&format!("{:7}cmp {}, -1\n", "", b.to_asm()));
+            out.push_str(&format!("{:7}jne i{}_div\n", "", cursor));
+            out.push_str(&format!("{:7}xor {}, {}\n", "", a.to_asm(), a.to_asm()));
+            out.push_str(&format!("{:7}jmp i{}_done\n", "", cursor));
+            out.push_str(&format!("i{}_div:\n", cursor));
+            out.push_str(&format!("{:7}mov rax, {}\n", "", a.to_asm()));
+            out.push_str(&format!("{:7}cqo\n", ""));
+            out.push_str(&format!("{:7}idiv {}\n", "", b.to_asm()));
+            out.push_str(&format!("{:7}mov {}, rdx\n", "", a.to_asm()));
+            out.push_str(&format!("i{}_done:\n", cursor))
+        }
+        // div divides rdx:rax as an unsigned number, so rdx is zeroed instead
+        // of sign-extending rax into it.
+        Instruction::Udiv(a, b) => {
+            out.push_str(&format!("test {}, {}\n", b.to_asm(), b.to_asm()));
+            out.push_str(&format!("{:7}jz panic\n", ""));
+            out.push_str(&format!("{:7}mov rax, {}\n", "", a.to_asm()));
+            out.push_str(&format!("{:7}xor edx, edx\n", ""));
+            out.push_str(&format!("{:7}div {}\n", "", b.to_asm()));
+            out.push_str(&format!("{:7}mov {}, rax\n", "", a.to_asm()))
+        }
+        Instruction::Urem(a, b) => {
+            out.push_str(&format!("test {}, {}\n", b.to_asm(), b.to_asm()));
+            out.push_str(&format!("{:7}jz panic\n", ""));
+            out.push_str(&format!("{:7}mov rax, {}\n", "", a.to_asm()));
+            out.push_str(&format!("{:7}xor edx, edx\n", ""));
+            out.push_str(&format!("{:7}div {}\n", "", b.to_asm()));
+            out.push_str(&format!("{:7}mov {}, rdx\n", "", a.to_asm()))
+        }
+        Instruction::Addc(a, b) => {
+            out.push_str(&format!("add {}, {}\n", a.to_asm(), b.to_asm()));
+            overflow_to_st(out)
+        }
+        Instruction::Subc(a, b) => {
+            out.push_str(&format!("sub {}, {}\n", a.to_asm(), b.to_asm()));
+            overflow_to_st(out)
+        }
+        Instruction::Mulc(a, b) => {
+            out.push_str(&format!("imul {}, {}\n", a.to_asm(), b.to_asm()));
+            overflow_to_st(out)
+        }
+        Instruction::And(a, b) => out.push_str(&format!("and {}, {}\n", a.to_asm(), b.to_asm())),
+        Instruction::Or(a, b) => out.push_str(&format!("or {}, {}\n", a.to_asm(), b.to_asm())),
+        Instruction::Xor(a, b) => out.push_str(&format!("xor {}, {}\n", a.to_asm(), b.to_asm())),
+        Instruction::Negate(a) => out.push_str(&format!("not {}\n", a.to_asm())),
+        // Shifts take their amount in cl and mask it to 0 to 63 themselves.
+        Instruction::Shl(a, b) => shift(out, "shl", a, b),
+        Instruction::Shr(a, b) => shift(out, "shr", a, b),
+        Instruction::Sar(a, b) => shift(out, "sar", a, b),
+        Instruction::Rotl(a, b) => shift(out, "rol", a, b),
+        Instruction::Rotr(a, b) => shift(out, "ror", a, b),
+    }
+}
+
+// The value the register has when the program starts. The binary's
+// check_initial_registers makes sure that an initial sp is in the memory.
+pub fn initial_value(binary: &Binary, reg: Reg, memory_size: usize) -> i64 {
+    let initial = binary
+        .initial_registers
+        .iter()
+        .rev()
+        .find(|(it, _)| *it == reg);
+    match (reg, initial) {
+        (_, Some((_, value))) => *value,
+        (Reg::SP, None) => memory_size as i64,
+        (_, None) => 0,
     }
-    out.push_str(&format!("  dq {} dup 0", 1000 - binary.memory.len()));
+}
+
+// Runs of at least this many equal bytes in the initial memory are emitted
+// as a single directive.
+const MIN_RUN_LEN: usize = 16;
+
+pub enum MemoryChunk<'a> {
+    Run { byte: u8, len: usize },
+    Literal(&'a [u8]),
+}
+
+// Splits the initial memory into long runs of equal bytes and the bytes in
+// between, so that big memories with mostly zeros don't turn into huge
+// assembly files.
+pub fn memory_chunks(memory: &[u8]) -> Vec<MemoryChunk<'_>> {
+    let mut chunks = vec![];
+    let mut literal_start = 0;
+    let mut i = 0;
+    while i < memory.len() {
+        let len = memory[i..]
+            .iter()
+            .take_while(|byte| **byte == memory[i])
+            .count();
+        if len >= MIN_RUN_LEN {
+            if literal_start < i {
+                chunks.push(MemoryChunk::Literal(&memory[literal_start..i]));
+            }
+            chunks.push(MemoryChunk::Run {
+                byte: memory[i],
+                len,
+            });
+            literal_start = i + len;
+        }
+        i += len;
+    }
+    if literal_start < memory.len() {
+        chunks.push(MemoryChunk::Literal(&memory[literal_start..]));
+    }
+    chunks
+}
+
+// The lowest address the stack may grow down to.
+pub fn stack_limit(binary: &Binary, options: &CompileOptions) -> usize {
+    match options.stack_size {
+        Some(size) => options.memory_size.saturating_sub(size),
+        None => binary.memory.len(),
+    }
+}
+
+// If enabled, panics unless size bytes starting at the address fit into the
+// memory. The unsigned comparison also catches negative addresses. Leaves the
+// line indented for the access itself.
+fn check_bounds(out: &mut String, address: Reg, size: usize, options: &CompileOptions) {
+    if !options.check_bounds {
+        return;
+    }
+    out.push_str(&format!(
+        "mov rax, {}\n",
+        options.memory_size.saturating_sub(size)
+    ));
+    out.push_str(&format!("{:7}cmp {}, rax\n", "", address.to_asm()));
+    out.push_str(&format!("{:7}ja panic\n", ""));
+    out.push_str(&format!("{:7}", ""));
+}
+
+fn shift(out: &mut String, op: &str, a: Reg, b: Reg) {
+    out.push_str(&format!("mov rcx, {}\n", b.to_asm()));
+    out.push_str(&format!("{:7}{} {}, cl\n", "", op, a.to_asm()));
+}
+
+// Sets st to 1 if the preceding add, sub, or imul overflowed, otherwise to 0.
+fn overflow_to_st(out: &mut String) {
+    out.push_str(&format!("{:7}seto al\n", ""));
+    out.push_str(&format!("{:7}movzx r9, al\n", ""));
+}
+
+const PANIC_MESSAGE: &str = "panicked\nStack:\n";
+
+// Uses the same format as the interpreter's backtrace.
+fn backtrace_line(binary: &Binary, offset: usize) -> String {
+    let label = binary.label_for(offset).unwrap_or("(no label)");
+    format!("{:8x} {}\n", offset, label)
+}
+
+// Defines a label followed by the bytes.
+fn bytes(out: &mut String, label: &str, bytes: &[u8]) {
+    let bytes: Vec<_> = bytes.iter().map(|byte| byte.to_string()).collect();
+    out.push_str(&format!("{}: db {}\n", label, bytes.join(", ")));
+}
+
+// Renders the annotations of the instruction at cursor as comments above it.
+fn annotate(out: &mut String, cursor: usize, binary: &Binary) {
+    for (_, key, value) in binary
+        .annotations
+        .iter()
+        .filter(|(pos, _, _)| *pos == cursor)
+    {
+        out.push_str(&format!("{:7}; {}: {}\n", "", key, value));
+    }
+}
+
+// Lists the label of each instruction in the assembly together with its byte
+// code offset and the label it belongs to, one per line:
+//
+// ```
+// i42 -> 2a -> main
+// ```
+//
+// Both the fasm and the aarch64 backend label instructions like this, so
+// tools can combine the map with the symbols of the assembled program (for
+// example, from fasm -s) to map native addresses back to soil code.
+pub fn instruction_map(binary: &Binary) -> String {
+    let mut out = String::new();
+    for offset in binary.instruction_offsets() {
+        let label = binary.label_for(offset).unwrap_or("(no label)");
+        out.push_str(&format!("i{} -> {:x} -> {}\n", offset, offset, label));
+    }
+    out
+}
+
+// Compiles only the function starting at the given label, up to the next
+// label. This is meant for inspecting the generated code, so the result
+// doesn't contain the prologue, syscalls, or data.
+pub fn compile_function(binary: &Binary, name: &str, options: &CompileOptions) -> Option<String> {
+    let start = binary.labels.iter().find(|(_, label)| label == name)?.0;
+    let end = binary
+        .labels
+        .iter()
+        .map(|(pos, _)| *pos)
+        .filter(|pos| *pos > start)
+        .min()
+        .unwrap_or(binary.byte_code.len());
 
-    return out;
+    let mut out = String::new();
+    out.push_str(&format!("; {}\n", name));
+    let mut byte_code = binary.byte_code.byte_code();
+    loop {
+        let cursor = byte_code.cursor;
+        if cursor >= end {
+            break;
+        }
+        let instruction = match byte_code.next() {
+            Some(instruction) => instruction,
+            None => break,
+        };
+        if cursor >= start {
+            annotate(&mut out, cursor, binary);
+            out.push_str(&format!("{:7}", format!("i{}: ", cursor)));
+            compile_instruction(&mut out, cursor, instruction, binary, options);
+        }
+    }
+    Some(out)
 }
 
-impl Reg {
+#[extension_trait]
+impl FasmReg for Reg {
     fn to_asm(&self) -> &'static str {
         match self {
             Reg::SP => "r8",
@@ -212,211 +859,143 @@ impl Reg {
     }
 }
 
-#[extension_trait]
-impl ByteCode for [u8] {
-    fn byte_code(&self) -> ByteCodeParser {
-        ByteCodeParser {
-            input: self,
-            cursor: 0,
-        }
-    }
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-struct ByteCodeParser<'a> {
-    input: &'a [u8],
-    cursor: usize,
-}
-impl<'a> ByteCodeParser<'a> {
-    fn done(&self) -> bool {
-        self.cursor >= self.input.len()
-    }
-    fn advance_by(&mut self, n: usize) {
-        self.cursor += n;
+    fn compile(byte_code: Vec<u8>, options: &CompileOptions) -> String {
+        let binary = Binary {
+            byte_code,
+            ..Default::default()
+        };
+        compile_with_options(binary, options).unwrap()
     }
-    fn eat_byte(&mut self) -> Option<u8> {
-        if self.done() {
-            return None;
+
+    #[test]
+    fn checked_arithmetic_doesnt_panic_with_checked_bounds() {
+        let options = CompileOptions {
+            check_bounds: true,
+            ..Default::default()
+        };
+        for opcode in [0xab, 0xac, 0xad] {
+            let asm = compile(vec![opcode, 0x32], &options);
+            assert!(asm.contains("seto al"));
+            assert!(!asm.contains("jo panic"));
         }
-        let byte = self.input[self.cursor];
-        self.advance_by(1);
-        Some(byte)
     }
-    fn eat_i64(&mut self) -> Option<i64> {
-        if self.input.len() - self.cursor < 8 {
-            return None;
-        }
-        let word = self.input.word_at(self.cursor);
-        self.advance_by(8);
-        Some(word)
+
+    #[test]
+    fn functions_are_aligned() {
+        // add a b, ret, ret
+        let binary = Binary {
+            byte_code: vec![0xa0, 0x32, 0xf3, 0xf3],
+            labels: vec![(0, "main".to_string()), (2, "helper".to_string())],
+            ..Default::default()
+        };
+        let options = CompileOptions {
+            align_functions: Some(16),
+            ..Default::default()
+        };
+        let asm = compile_with_options(binary, &options).unwrap();
+        assert!(asm.contains("       align 16\ni0:    add"));
+        assert!(asm.contains("       align 16\ni2:    dec"));
+        assert_eq!(asm.matches("align 16").count(), 2);
     }
-    fn eat_usize(&mut self) -> Option<usize> {
-        self.eat_i64().map(|word| word as usize)
+
+    #[test]
+    fn functions_are_compiled_with_the_options() {
+        let binary = Binary {
+            // load a b
+            byte_code: vec![0xd3, 0x32],
+            labels: vec![(0, "main".to_string())],
+            ..Default::default()
+        };
+        let options = CompileOptions {
+            check_bounds: true,
+            ..Default::default()
+        };
+        let function = compile_function(&binary, "main", &options).unwrap();
+        assert!(function.contains("ja panic"));
+        let function = compile_function(&binary, "main", &CompileOptions::default()).unwrap();
+        assert!(!function.contains("ja panic"));
     }
-    fn eat_reg(&mut self) -> Reg {
-        let byte = self.eat_byte().expect("expected register\n");
-        Reg::try_from(byte & 0x0f).unwrap()
+
+    #[test]
+    fn pops_are_bounds_checked() {
+        let options = CompileOptions {
+            check_bounds: true,
+            memory_size: 1024,
+            ..Default::default()
+        };
+        let asm = compile(vec![0xd8, 0x02], &options);
+        assert!(asm.contains("mov rax, 1016\n       cmp r8, rax\n       ja panic\n"));
+        let asm = compile(vec![0xd8, 0x02], &CompileOptions::default());
+        assert!(!asm.contains("ja panic"));
     }
-    fn eat_regs(&mut self) -> (Reg, Reg) {
-        let byte = self.eat_byte().expect("expected registers\n");
-        (
-            Reg::try_from(byte & 0x0f).unwrap(),
-            Reg::try_from(byte >> 4 & 0x0f).unwrap(),
-        )
+
+    #[test]
+    fn initial_memory_has_to_fit_into_the_memory() {
+        let binary = |memory_len| Binary {
+            memory: vec![1; memory_len],
+            ..Default::default()
+        };
+        let options = CompileOptions {
+            memory_size: 1024,
+            ..Default::default()
+        };
+        assert_eq!(
+            compile_with_options(binary(2000), &options),
+            Err(CompileError::InitialMemoryTooLarge {
+                len: 2000,
+                memory_size: 1024
+            })
+        );
+        assert!(compile_with_options(binary(1024), &options)
+            .unwrap()
+            .ends_with("  rb 0"));
+        assert!(compile_with_options(binary(2000), &CompileOptions::default()).is_ok());
     }
-}
 
-enum Instruction {
-    Nop,
-    Panic,
-    Move_(Reg, Reg),
-    Movei(Reg, i64),
-    Moveib(Reg, u8),
-    Load(Reg, Reg),
-    Loadb(Reg, Reg),
-    Store(Reg, Reg),
-    Storeb(Reg, Reg),
-    Push(Reg),
-    Pop(Reg),
-    Jump(usize),
-    Cjump(usize),
-    Call(usize),
-    Ret,
-    Syscall(u8),
-    Cmp(Reg, Reg),
-    Isequal,
-    Isless,
-    Isgreater,
-    Islessequal,
-    Isgreaterequal,
-    Add(Reg, Reg),
-    Sub(Reg, Reg),
-    Mul(Reg, Reg),
-    Div(Reg, Reg),
-    Rem(Reg, Reg),
-    And(Reg, Reg),
-    Or(Reg, Reg),
-    Xor(Reg, Reg),
-    Negate(Reg),
-}
+    #[test]
+    fn is_instructions_compare_st_themselves() {
+        // cmp a b, add c c, isless
+        let asm = compile(
+            vec![0xc0, 0x32, 0xa0, 0x44, 0xc2],
+            &CompileOptions::default(),
+        );
+        assert!(asm.contains("sub r9, r11\n"));
+        assert!(
+            asm.contains("cmp r9, 0\n       mov rax, 0\n       mov rbx, 1\n       cmovl r9, rbx\n")
+        );
+    }
 
-#[derive(Clone, Copy, PartialEq, Eq)]
-enum Reg {
-    SP,
-    ST,
-    A,
-    B,
-    C,
-    D,
-    E,
-    F,
-}
-const REGS: [Reg; 8] = [Reg::SP, Reg::ST, Reg::A, Reg::B, Reg::C, Reg::D, Reg::E, Reg::F];
-
-impl TryFrom<u8> for Reg {
-    type Error = ();
-
-    fn try_from(value: u8) -> Result<Self, ()> {
-        Ok(match value {
-            0 => Reg::SP,
-            1 => Reg::ST,
-            2 => Reg::A,
-            3 => Reg::B,
-            4 => Reg::C,
-            5 => Reg::D,
-            6 => Reg::E,
-            7 => Reg::F,
-            _ => return Err(()),
-        })
+    #[test]
+    fn loadb_zero_extends() {
+        let asm = compile(vec![0xd4, 0x32], &CompileOptions::default());
+        assert!(asm.contains("movzx r10, byte [memory + r11]\n"));
     }
-}
 
-impl<'a> Iterator for ByteCodeParser<'a> {
-    type Item = Instruction;
+    #[test]
+    fn conditional_moves_test_st() {
+        let asm = compile(vec![0xdb, 0x32, 0xdc, 0x32], &CompileOptions::default());
+        assert!(asm.contains("cmp r9, 0\n       cmovz r10, r11\n"));
+        assert!(asm.contains("cmp r9, 0\n       cmovnz r10, r11\n"));
+    }
 
-    fn next(&mut self) -> Option<Self::Item> {
-        Some(match self.eat_byte()? {
-            0x00 => Instruction::Nop,
-            0xe0 => Instruction::Panic,
-            0xd0 => {
-                let (a, b) = self.eat_regs();
-                Instruction::Move_(a, b)
-            }
-            0xd1 => {
-                let reg = self.eat_reg();
-                let value = self.eat_i64().unwrap();
-                Instruction::Movei(reg, value)
-            }
-            0xd2 => {
-                let reg = self.eat_reg();
-                let value = self.eat_byte().unwrap();
-                Instruction::Moveib(reg, value)
-            }
-            0xd3 => {
-                let (a, b) = self.eat_regs();
-                Instruction::Load(a, b)
-            }
-            0xd4 => {
-                let (a, b) = self.eat_regs();
-                Instruction::Loadb(a, b)
-            }
-            0xd5 => {
-                let (a, b) = self.eat_regs();
-                Instruction::Store(a, b)
-            }
-            0xd6 => {
-                let (a, b) = self.eat_regs();
-                Instruction::Storeb(a, b)
-            }
-            0xd7 => Instruction::Push(self.eat_reg()),
-            0xd8 => Instruction::Pop(self.eat_reg()),
-            0xf0 => Instruction::Jump(self.eat_usize().unwrap()),
-            0xf1 => Instruction::Cjump(self.eat_usize().unwrap()),
-            0xf2 => Instruction::Call(self.eat_usize().unwrap()),
-            0xf3 => Instruction::Ret,
-            0xf4 => Instruction::Syscall(self.eat_byte().unwrap()),
-            0xc0 => {
-                let (a, b) = self.eat_regs();
-                Instruction::Cmp(a, b)
-            }
-            0xc1 => Instruction::Isequal,
-            0xc2 => Instruction::Isless,
-            0xc3 => Instruction::Isgreater,
-            0xc4 => Instruction::Islessequal,
-            0xc5 => Instruction::Isgreaterequal,
-            0xa0 => {
-                let (a, b) = self.eat_regs();
-                Instruction::Add(a, b)
-            }
-            0xa1 => {
-                let (a, b) = self.eat_regs();
-                Instruction::Sub(a, b)
-            }
-            0xa2 => {
-                let (a, b) = self.eat_regs();
-                Instruction::Mul(a, b)
-            }
-            0xa3 => {
-                let (a, b) = self.eat_regs();
-                Instruction::Div(a, b)
-            }
-            0xa4 => {
-                let (a, b) = self.eat_regs();
-                Instruction::Rem(a, b)
-            }
-            0xb0 => {
-                let (a, b) = self.eat_regs();
-                Instruction::And(a, b)
-            }
-            0xb1 => {
-                let (a, b) = self.eat_regs();
-                Instruction::Or(a, b)
-            }
-            0xb2 => {
-                let (a, b) = self.eat_regs();
-                Instruction::Xor(a, b)
-            }
-            0xb3 => Instruction::Negate(self.eat_reg()),
-            opcode => panic!("unknown opcode {}\n", opcode),
-        })
+    #[test]
+    fn breakpoints_only_trap_if_enabled() {
+        assert!(!compile(vec![0xe1], &CompileOptions::default()).contains("int3"));
+        let options = CompileOptions {
+            breakpoints: true,
+            ..Default::default()
+        };
+        assert!(compile(vec![0xe1], &options).contains("i0:    int3\n"));
+    }
+
+    #[test]
+    fn polling_signals_finds_none() {
+        let asm = compile(vec![0xf4, 15], &CompileOptions::default());
+        assert!(asm.contains("call syscall_15\n"));
+        assert!(asm.contains("syscall_15: ; poll_signals\n       xor r10, r10\n       ret\n"));
     }
 }
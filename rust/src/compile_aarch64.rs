@@ -0,0 +1,500 @@
+// Compiles soil byte code to AArch64 assembly for the GNU assembler, producing
+// a static Linux executable:
+//
+// ```
+// soil --target aarch64 < program.soil > program.s
+// as program.s -o program.o && ld program.o -o program
+// ```
+//
+// Soil registers live in callee-saved registers, so they survive the Linux
+// syscalls:
+//
+// | soil | AArch64 |
+// | ---- | ------- |
+// | sp   | x19     |
+// | st   | x20     |
+// | a    | x21     |
+// | b    | x22     |
+// | c    | x23     |
+// | d    | x24     |
+// | e    | x25     |
+// | f    | x26     |
+//
+// x27 holds the address of the soil memory and x9 to x12 are scratch
+// registers. Unlike the fasm backend, push and pop operate on the soil memory
+// at sp. Calls push their return address on the native stack, so they don't
+// clobber the link register of an outer call.
+
+use extension_trait::extension_trait;
+
+use crate::{
+    binary::{ArithmeticMode, Binary},
+    bytecode::{ByteCode, Instruction, Reg},
+    compile::{
+        check_memory_size, initial_value, memory_chunks, stack_limit, CompileError, CompileOptions,
+        MemoryChunk,
+    },
+    exit_status::PANIC_EXIT_STATUS,
+};
+
+pub fn compile_aarch64(binary: Binary, options: &CompileOptions) -> Result<String, CompileError> {
+    check_memory_size(&binary, options)?;
+    let mut out = String::new();
+
+    out.push_str(".text\n");
+    out.push_str(".global _start\n");
+    out.push_str("_start:\n");
+    // Linux puts argc at the top of the stack, followed by the argv pointers.
+    out.push_str("    ldr x9, [sp]\n");
+    out.push_str("    adrp x10, saved_argc\n");
+    out.push_str("    str x9, [x10, :lo12:saved_argc]\n");
+    out.push_str("    add x9, sp, #8\n");
+    out.push_str("    adrp x10, saved_argv\n");
+    out.push_str("    str x9, [x10, :lo12:saved_argv]\n");
+    for reg in Reg::ALL {
+        mov_immediate(
+            &mut out,
+            reg.x(),
+            initial_value(&binary, reg, options.memory_size),
+        );
+    }
+    out.push_str("    adrp x27, memory\n");
+    out.push_str("    add x27, x27, :lo12:memory\n");
+    if binary.entry_point() != 0 {
+        out.push_str(&format!("    b i{}\n", binary.entry_point()));
+    }
+
+    let mut byte_code = binary.byte_code.byte_code();
+    loop {
+        let cursor = byte_code.cursor;
+        let instruction = match byte_code.next() {
+            Some(instruction) => instruction,
+            None => break,
+        };
+        out.push_str(&format!("i{}:\n", cursor));
+        compile_instruction(&mut out, instruction, &binary, options);
+    }
+
+    out.push_str("panic:\n");
+    out.push_str(&format!("    mov x0, #{}\n", PANIC_EXIT_STATUS));
+    out.push_str("    mov x8, #93\n"); // exit
+    out.push_str("    svc #0\n");
+
+    out.push_str("syscall_0: // exit\n");
+    out.push_str("    mov x0, x21\n");
+    out.push_str("    mov x8, #93\n");
+    out.push_str("    svc #0\n");
+
+    for (number, name, fd) in [(1, "print", 1), (2, "log", 2)] {
+        out.push_str(&format!("syscall_{}: // {}\n", number, name));
+        out.push_str(&format!("    mov x0, #{}\n", fd));
+        out.push_str("    add x1, x27, x21\n");
+        out.push_str("    mov x2, x22\n");
+        out.push_str("    mov x8, #64\n"); // write
+        out.push_str("    svc #0\n");
+        out.push_str("    ret\n");
+    }
+
+    out.push_str("syscall_7: // write\n");
+    out.push_str("    mov x0, x21\n");
+    out.push_str("    add x1, x27, x22\n");
+    out.push_str("    mov x2, x23\n");
+    out.push_str("    mov x8, #64\n");
+    out.push_str("    svc #0\n");
+    out.push_str("    mov x21, x0\n");
+    out.push_str("    ret\n");
+
+    // Like in the fasm backend, no signals are ever pending.
+    out.push_str("syscall_15: // poll_signals\n");
+    out.push_str("    mov x21, #0\n");
+    out.push_str("    ret\n");
+
+    // Like in the fasm backend, uses clock_gettime with CLOCK_MONOTONIC.
+    out.push_str("syscall_16: // instant\n");
+    out.push_str("    sub sp, sp, #16\n");
+    out.push_str("    mov x0, #1\n");
+    out.push_str("    mov x1, sp\n");
+    out.push_str("    mov x8, #113\n"); // clock_gettime
+    out.push_str("    svc #0\n");
+    out.push_str("    ldp x9, x10, [sp]\n");
+    out.push_str("    add sp, sp, #16\n");
+    mov_immediate(&mut out, "x11", 1_000_000_000);
+    out.push_str("    madd x21, x9, x11, x10\n");
+    out.push_str("    ret\n");
+
+    out.push_str("syscall_9: // argc\n");
+    out.push_str("    adrp x9, saved_argc\n");
+    out.push_str("    ldr x21, [x9, :lo12:saved_argc]\n");
+    out.push_str("    ret\n");
+
+    // Copies the null-terminated argument into the buffer, stopping early if
+    // the buffer is full.
+    out.push_str("syscall_10: // arg\n");
+    out.push_str("    adrp x9, saved_argc\n");
+    out.push_str("    ldr x9, [x9, :lo12:saved_argc]\n");
+    out.push_str("    cmp x21, x9\n");
+    out.push_str("    b.hs panic\n");
+    out.push_str("    adrp x9, saved_argv\n");
+    out.push_str("    ldr x9, [x9, :lo12:saved_argv]\n");
+    out.push_str("    ldr x9, [x9, x21, lsl #3]\n");
+    out.push_str("    add x11, x27, x22\n");
+    out.push_str("    mov x10, #0\n");
+    out.push_str("1:\n");
+    out.push_str("    cmp x10, x23\n");
+    out.push_str("    b.hs 2f\n");
+    out.push_str("    ldrb w12, [x9, x10]\n");
+    out.push_str("    cbz w12, 2f\n");
+    out.push_str("    strb w12, [x11, x10]\n");
+    out.push_str("    add x10, x10, #1\n");
+    out.push_str("    b 1b\n");
+    out.push_str("2:\n");
+    out.push_str("    mov x21, x10\n");
+    out.push_str("    ret\n");
+
+    out.push_str(".data\n");
+    out.push_str(".balign 8\n");
+    out.push_str("saved_argc: .quad 0\n");
+    out.push_str("saved_argv: .quad 0\n");
+    out.push_str("memory:\n");
+    for chunk in memory_chunks(&binary.memory) {
+        match chunk {
+            MemoryChunk::Run { byte, len } => {
+                out.push_str(&format!("    .fill {}, 1, {}\n", len, byte))
+            }
+            MemoryChunk::Literal(bytes) => {
+                for line in bytes.chunks(16) {
+                    let bytes: Vec<_> = line.iter().map(|byte| byte.to_string()).collect();
+                    out.push_str(&format!("    .byte {}\n", bytes.join(", ")));
+                }
+            }
+        }
+    }
+    out.push_str(&format!(
+        "    .space {}\n",
+        options.memory_size - binary.memory.len()
+    ));
+
+    Ok(out)
+}
+
+fn compile_instruction(
+    out: &mut String,
+    instruction: Instruction,
+    binary: &Binary,
+    options: &CompileOptions,
+) {
+    let trapping = binary.arithmetic_mode == ArithmeticMode::Trapping;
+    match instruction {
+        Instruction::Nop => out.push_str("    nop\n"),
+        Instruction::Panic => out.push_str("    b panic\n"),
+        Instruction::Breakpoint => {
+            if options.breakpoints {
+                out.push_str("    brk #0\n")
+            } else {
+                out.push_str("    nop\n")
+            }
+        }
+        Instruction::Move_(a, b) => out.push_str(&format!("    mov {}, {}\n", a.x(), b.x())),
+        Instruction::Movei(a, value) => mov_immediate(out, a.x(), value),
+        Instruction::Moveib(a, value) => mov_immediate(out, a.x(), value as i64),
+        Instruction::Moveisb(a, value) => mov_immediate(out, a.x(), value as i64),
+        Instruction::Swap(a, b) => {
+            out.push_str(&format!("    mov x9, {}\n", a.x()));
+            out.push_str(&format!("    mov {}, {}\n", a.x(), b.x()));
+            out.push_str(&format!("    mov {}, x9\n", b.x()));
+        }
+        Instruction::Movz(a, b) => {
+            out.push_str("    cmp x20, #0\n");
+            out.push_str(&format!("    csel {}, {}, {}, eq\n", a.x(), b.x(), a.x()));
+        }
+        Instruction::Movnz(a, b) => {
+            out.push_str("    cmp x20, #0\n");
+            out.push_str(&format!("    csel {}, {}, {}, ne\n", a.x(), b.x(), a.x()));
+        }
+        Instruction::Load(a, b) => {
+            check_bounds(out, b, 8, options);
+            out.push_str(&format!("    ldr {}, [x27, {}]\n", a.x(), b.x()));
+        }
+        Instruction::Loadb(a, b) => {
+            check_bounds(out, b, 1, options);
+            out.push_str(&format!("    ldrb {}, [x27, {}]\n", a.w(), b.x()));
+        }
+        Instruction::Store(a, b) => {
+            check_bounds(out, a, 8, options);
+            out.push_str(&format!("    str {}, [x27, {}]\n", b.x(), a.x()));
+        }
+        Instruction::Storeb(a, b) => {
+            check_bounds(out, a, 1, options);
+            out.push_str(&format!("    strb {}, [x27, {}]\n", b.w(), a.x()));
+        }
+        // Loads into w registers zero the upper 32 bits.
+        Instruction::Loadh(a, b) => {
+            check_bounds(out, b, 2, options);
+            out.push_str(&format!("    ldrh {}, [x27, {}]\n", a.w(), b.x()));
+        }
+        Instruction::Loadw(a, b) => {
+            check_bounds(out, b, 4, options);
+            out.push_str(&format!("    ldr {}, [x27, {}]\n", a.w(), b.x()));
+        }
+        Instruction::Storeh(a, b) => {
+            check_bounds(out, a, 2, options);
+            out.push_str(&format!("    strh {}, [x27, {}]\n", b.w(), a.x()));
+        }
+        Instruction::Storew(a, b) => {
+            check_bounds(out, a, 4, options);
+            out.push_str(&format!("    str {}, [x27, {}]\n", b.w(), a.x()));
+        }
+        Instruction::Push(a) => {
+            out.push_str("    sub x19, x19, #8\n");
+            mov_immediate(out, "x9", stack_limit(binary, options) as i64);
+            out.push_str("    cmp x19, x9\n");
+            out.push_str("    b.lt panic\n");
+            out.push_str(&format!("    str {}, [x27, x19]\n", a.x()));
+        }
+        Instruction::Pop(a) => {
+            check_bounds(out, Reg::SP, 8, options);
+            out.push_str("    ldr x9, [x27, x19]\n");
+            out.push_str("    add x19, x19, #8\n");
+            out.push_str(&format!("    mov {}, x9\n", a.x()));
+        }
+        Instruction::Jump(target) | Instruction::Jumpr(target) => {
+            out.push_str(&format!("    b i{}\n", target))
+        }
+        Instruction::Cjump(target) | Instruction::Cjumpr(target) => {
+            out.push_str(&format!("    cbnz x20, i{}\n", target))
+        }
+        Instruction::Call(target) | Instruction::Callr(target) => {
+            out.push_str("    adr x9, 1f\n");
+            out.push_str("    str x9, [sp, #-16]!\n");
+            out.push_str(&format!("    b i{}\n", target));
+            out.push_str("1:\n");
+        }
+        Instruction::Ret => {
+            out.push_str("    ldr x9, [sp], #16\n");
+            out.push_str("    br x9\n");
+        }
+        Instruction::Syscall(number) => out.push_str(&format!("    bl syscall_{}\n", number)),
+        Instruction::Cmp(a, b) => out.push_str(&format!("    sub x20, {}, {}\n", a.x(), b.x())),
+        Instruction::Cmpz(a) => out.push_str(&format!("    mov x20, {}\n", a.x())),
+        Instruction::Isequal => compare_st(out, "eq"),
+        Instruction::Isless => compare_st(out, "lt"),
+        Instruction::Isgreater => compare_st(out, "gt"),
+        Instruction::Islessequal => compare_st(out, "le"),
+        Instruction::Isgreaterequal => compare_st(out, "ge"),
+        Instruction::Inttofloat(a) => {
+            out.push_str(&format!("    scvtf d0, {}\n", a.x()));
+            out.push_str(&format!("    fmov {}, d0\n", a.x()));
+        }
+        // fcvtzs already rounds toward zero, saturates, and turns NaN into 0.
+        Instruction::Floattoint(a) => {
+            out.push_str(&format!("    fmov d0, {}\n", a.x()));
+            out.push_str(&format!("    fcvtzs {}, d0\n", a.x()));
+        }
+        Instruction::Add(a, b) if trapping => {
+            out.push_str(&format!("    adds {}, {}, {}\n", a.x(), a.x(), b.x()));
+            out.push_str("    b.vs panic\n");
+        }
+        Instruction::Add(a, b) => {
+            out.push_str(&format!("    add {}, {}, {}\n", a.x(), a.x(), b.x()))
+        }
+        Instruction::Sub(a, b) if trapping => {
+            out.push_str(&format!("    subs {}, {}, {}\n", a.x(), a.x(), b.x()));
+            out.push_str("    b.vs panic\n");
+        }
+        Instruction::Sub(a, b) => {
+            out.push_str(&format!("    sub {}, {}, {}\n", a.x(), a.x(), b.x()))
+        }
+        // The product overflowed if its high half isn't just the sign
+        // extension of its low half.
+        Instruction::Mul(a, b) if trapping => {
+            out.push_str(&format!("    mul x9, {}, {}\n", a.x(), b.x()));
+            out.push_str(&format!("    smulh x10, {}, {}\n", a.x(), b.x()));
+            out.push_str("    cmp x10, x9, asr #63\n");
+            out.push_str("    b.ne panic\n");
+            out.push_str(&format!("    mov {}, x9\n", a.x()));
+        }
+        Instruction::Mul(a, b) => {
+            out.push_str(&format!("    mul {}, {}, {}\n", a.x(), a.x(), b.x()))
+        }
+        // sdiv returns 0 when dividing by zero, but soil panics. It wraps
+        // i64::MIN / -1, which only panics in trapping mode.
+        Instruction::Div(a, b) => {
+            out.push_str(&format!("    cbz {}, panic\n", b.x()));
+            if trapping {
+                out.push_str(&format!("    cmn {}, #1\n", b.x()));
+                mov_immediate(out, "x9", i64::MIN);
+                out.push_str(&format!("    ccmp {}, x9, #0, eq\n", a.x()));
+                out.push_str("    b.eq panic\n");
+            }
+            out.push_str(&format!("    sdiv {}, {}, {}\n", a.x(), a.x(), b.x()));
+        }
+        Instruction::Rem(a, b) => {
+            out.push_str(&format!("    cbz {}, panic\n", b.x()));
+            out.push_str(&format!("    sdiv x9, {}, {}\n", a.x(), b.x()));
+            out.push_str(&format!("    msub {}, x9, {}, {}\n", a.x(), b.x(), a.x()));
+        }
+        Instruction::Udiv(a, b) => {
+            out.push_str(&format!("    cbz {}, panic\n", b.x()));
+            out.push_str(&format!("    udiv {}, {}, {}\n", a.x(), a.x(), b.x()));
+        }
+        Instruction::Urem(a, b) => {
+            out.push_str(&format!("    cbz {}, panic\n", b.x()));
+            out.push_str(&format!("    udiv x9, {}, {}\n", a.x(), b.x()));
+            out.push_str(&format!("    msub {}, x9, {}, {}\n", a.x(), b.x(), a.x()));
+        }
+        Instruction::Addc(a, b) => {
+            out.push_str(&format!("    adds {}, {}, {}\n", a.x(), a.x(), b.x()));
+            overflow_to_st(out, "vs");
+        }
+        Instruction::Subc(a, b) => {
+            out.push_str(&format!("    subs {}, {}, {}\n", a.x(), a.x(), b.x()));
+            overflow_to_st(out, "vs");
+        }
+        Instruction::Mulc(a, b) => {
+            out.push_str(&format!("    mul x9, {}, {}\n", a.x(), b.x()));
+            out.push_str(&format!("    smulh x10, {}, {}\n", a.x(), b.x()));
+            out.push_str(&format!("    mov {}, x9\n", a.x()));
+            out.push_str("    cmp x10, x9, asr #63\n");
+            overflow_to_st(out, "ne");
+        }
+        Instruction::And(a, b) => {
+            out.push_str(&format!("    and {}, {}, {}\n", a.x(), a.x(), b.x()))
+        }
+        Instruction::Or(a, b) => {
+            out.push_str(&format!("    orr {}, {}, {}\n", a.x(), a.x(), b.x()))
+        }
+        Instruction::Xor(a, b) => {
+            out.push_str(&format!("    eor {}, {}, {}\n", a.x(), a.x(), b.x()))
+        }
+        Instruction::Negate(a) => out.push_str(&format!("    mvn {}, {}\n", a.x(), a.x())),
+        // The register forms of the shifts use the amount modulo 64.
+        Instruction::Shl(a, b) => {
+            out.push_str(&format!("    lsl {}, {}, {}\n", a.x(), a.x(), b.x()))
+        }
+        Instruction::Shr(a, b) => {
+            out.push_str(&format!("    lsr {}, {}, {}\n", a.x(), a.x(), b.x()))
+        }
+        Instruction::Sar(a, b) => {
+            out.push_str(&format!("    asr {}, {}, {}\n", a.x(), a.x(), b.x()))
+        }
+        // There's no rotate left, but rotating right by the negated amount
+        // is the same modulo 64.
+        Instruction::Rotl(a, b) => {
+            out.push_str(&format!("    neg x9, {}\n", b.x()));
+            out.push_str(&format!("    ror {}, {}, x9\n", a.x(), a.x()));
+        }
+        Instruction::Rotr(a, b) => {
+            out.push_str(&format!("    ror {}, {}, {}\n", a.x(), a.x(), b.x()))
+        }
+    }
+}
+
+// Like in the fasm backend, panics unless size bytes starting at the address
+// fit into the memory.
+fn check_bounds(out: &mut String, address: Reg, size: usize, options: &CompileOptions) {
+    if !options.check_bounds {
+        return;
+    }
+    mov_immediate(out, "x9", options.memory_size.saturating_sub(size) as i64);
+    out.push_str(&format!("    cmp {}, x9\n", address.x()));
+    out.push_str("    b.hi panic\n");
+}
+
+// Sets st to 1 if the flags fulfill the condition, which signals an
+// overflow.
+fn overflow_to_st(out: &mut String, condition: &str) {
+    out.push_str(&format!("    cset x20, {}\n", condition));
+}
+
+// Sets st to 1 if st fulfills the condition when compared against zero,
+// otherwise to 0.
+fn compare_st(out: &mut String, condition: &str) {
+    out.push_str("    cmp x20, #0\n");
+    out.push_str(&format!("    cset x20, {}\n", condition));
+}
+
+// Moves an arbitrary 64-bit value into the register, 16 bits at a time.
+fn mov_immediate(out: &mut String, reg: &str, value: i64) {
+    let value = value as u64;
+    out.push_str(&format!("    movz {}, #{}\n", reg, value & 0xffff));
+    for shift in [16, 32, 48] {
+        let part = (value >> shift) & 0xffff;
+        if part != 0 {
+            out.push_str(&format!("    movk {}, #{}, lsl #{}\n", reg, part, shift));
+        }
+    }
+}
+
+#[extension_trait]
+impl Aarch64Reg for Reg {
+    fn x(self) -> &'static str {
+        ["x19", "x20", "x21", "x22", "x23", "x24", "x25", "x26"][self.index()]
+    }
+    fn w(self) -> &'static str {
+        ["w19", "w20", "w21", "w22", "w23", "w24", "w25", "w26"][self.index()]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checked_arithmetic_doesnt_panic_with_checked_bounds() {
+        let options = CompileOptions {
+            check_bounds: true,
+            ..Default::default()
+        };
+        for opcode in [0xab, 0xac, 0xad] {
+            let binary = Binary {
+                byte_code: vec![opcode, 0x32],
+                ..Default::default()
+            };
+            let asm = compile_aarch64(binary, &options).unwrap();
+            assert!(asm.contains("cset x20"));
+            assert!(!asm.contains("b.vs panic") && !asm.contains("b.ne panic"));
+        }
+    }
+
+    #[test]
+    fn pops_are_bounds_checked() {
+        let options = CompileOptions {
+            check_bounds: true,
+            ..Default::default()
+        };
+        let binary = Binary {
+            byte_code: vec![0xd8, 0x02],
+            ..Default::default()
+        };
+        let asm = compile_aarch64(binary, &options).unwrap();
+        assert!(asm.contains("    cmp x19, x9\n    b.hi panic\n    ldr x9, [x27, x19]\n"));
+    }
+
+    #[test]
+    fn breakpoints_only_trap_if_enabled() {
+        let binary = || Binary {
+            byte_code: vec![0xe1],
+            ..Default::default()
+        };
+        let asm = compile_aarch64(binary(), &CompileOptions::default()).unwrap();
+        assert!(!asm.contains("brk"));
+        let options = CompileOptions {
+            breakpoints: true,
+            ..Default::default()
+        };
+        assert!(compile_aarch64(binary(), &options)
+            .unwrap()
+            .contains("    brk #0\n"));
+    }
+
+    #[test]
+    fn polling_signals_finds_none() {
+        let binary = Binary {
+            byte_code: vec![0xf4, 15],
+            ..Default::default()
+        };
+        let asm = compile_aarch64(binary, &CompileOptions::default()).unwrap();
+        assert!(asm.contains("    bl syscall_15\n"));
+        assert!(asm.contains("syscall_15: // poll_signals\n    mov x21, #0\n    ret\n"));
+    }
+}
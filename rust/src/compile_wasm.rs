@@ -0,0 +1,744 @@
+// Compiles soil byte code to a WebAssembly module:
+//
+// ```
+// soil --target wasm -o program.wasm < program.soil
+// ```
+//
+// The module exports a function `run: () -> i64` that runs the program and
+// returns its exit status, and its `memory`. Panics trap.
+//
+// The first CALL_STACK_SIZE bytes of the memory hold the call stack, the soil
+// memory comes after them. So the soil address x is at the byte
+// x + CALL_STACK_SIZE of the exported memory.
+//
+// Syscalls other than exit are imported from the `soil` namespace as
+// `syscall_<number>`, but only the ones the program uses. They all have the
+// same signature:
+//
+// ```
+// (func (param $a i64) (param $b i64) (param $c i64) (param $d i64)
+//       (param $e i64) (param $f i64) (result i64))
+// ```
+//
+// They get the registers a to f, and the result is the new value of a.
+// Syscalls that don't produce a value return a unchanged.
+//
+// Wasm only has structured control flow, so the code is split into blocks
+// that start at jump targets and return offsets. A loop dispatches on the
+// index of the next block in the pc local. Each block falls through into the
+// next one and jumps by setting pc and branching back to the loop.
+
+use std::collections::BTreeMap;
+
+use crate::{
+    binary::{ArithmeticMode, Binary},
+    bytecode::{ByteCode, Instruction, Reg},
+    compile::{check_memory_size, initial_value, stack_limit, CompileError, CompileOptions},
+};
+
+pub const CALL_STACK_SIZE: usize = 8 * 1024;
+
+const PAGE_SIZE: usize = 64 * 1024;
+
+// Locals of the run function. The registers come first, using their numbers
+// as indices.
+const TMP: u32 = 8;
+const PC: u32 = 9;
+const CSP: u32 = 10;
+
+pub fn compile_wasm(binary: Binary, options: &CompileOptions) -> Result<Vec<u8>, CompileError> {
+    check_memory_size(&binary, options)?;
+    let mut syscalls = vec![];
+    let mut leaders = vec![binary.entry_point()];
+    let mut byte_code = binary.byte_code.byte_code();
+    while let Some(instruction) = byte_code.next() {
+        match instruction {
+            Instruction::Jump(target)
+            | Instruction::Cjump(target)
+            | Instruction::Jumpr(target)
+            | Instruction::Cjumpr(target) => leaders.push(target),
+            Instruction::Call(target) | Instruction::Callr(target) => {
+                leaders.push(target);
+                leaders.push(byte_code.cursor);
+            }
+            Instruction::Syscall(number) if number != 0 && !syscalls.contains(&number) => {
+                syscalls.push(number)
+            }
+            _ => {}
+        }
+    }
+    let instruction_offsets = binary.instruction_offsets();
+    leaders.retain(|offset| instruction_offsets.contains(offset));
+    leaders.sort();
+    leaders.dedup();
+    // Blocks that don't start at an instruction get the index leaders.len(),
+    // which the dispatch turns into a panic.
+    let blocks: BTreeMap<usize, u32> = leaders
+        .iter()
+        .enumerate()
+        .map(|(i, offset)| (*offset, i as u32))
+        .collect();
+
+    let mut function = Function {
+        code: vec![],
+        blocks,
+        syscalls: syscalls.clone(),
+        stack_limit: stack_limit(&binary, options),
+        trapping: binary.arithmetic_mode == ArithmeticMode::Trapping,
+    };
+    function.compile(&binary, options);
+
+    let mut module = vec![];
+    module.extend(b"\0asm");
+    module.extend([1, 0, 0, 0]);
+
+    // Type 0 is run, type 1 the syscalls.
+    let mut types = vec![];
+    uleb(&mut types, 2);
+    types.extend([0x60, 0, 1, 0x7e]);
+    types.extend([0x60, 6, 0x7e, 0x7e, 0x7e, 0x7e, 0x7e, 0x7e, 1, 0x7e]);
+    section(&mut module, 1, types);
+
+    let mut imports = vec![];
+    uleb(&mut imports, syscalls.len() as u64);
+    for number in &syscalls {
+        name(&mut imports, "soil");
+        name(&mut imports, &format!("syscall_{}", number));
+        imports.extend([0x00, 1]);
+    }
+    section(&mut module, 2, imports);
+
+    section(&mut module, 3, vec![1, 0]);
+
+    let pages = (CALL_STACK_SIZE + options.memory_size).div_ceil(PAGE_SIZE);
+    let mut memories = vec![1, 0x00];
+    uleb(&mut memories, pages as u64);
+    section(&mut module, 5, memories);
+
+    let mut exports = vec![];
+    uleb(&mut exports, 2);
+    name(&mut exports, "run");
+    exports.push(0x00);
+    uleb(&mut exports, syscalls.len() as u64);
+    name(&mut exports, "memory");
+    exports.extend([0x02, 0]);
+    section(&mut module, 7, exports);
+
+    let mut body = vec![];
+    // Eight registers and TMP are i64, PC and CSP are i32.
+    body.extend([2, 9, 0x7e, 2, 0x7f]);
+    body.extend(function.code);
+    body.push(0x0b);
+    let mut code = vec![1];
+    uleb(&mut code, body.len() as u64);
+    code.extend(body);
+    section(&mut module, 10, code);
+
+    if !binary.memory.is_empty() {
+        let mut data = vec![1, 0x00, 0x41];
+        sleb(&mut data, CALL_STACK_SIZE as i64);
+        data.push(0x0b);
+        uleb(&mut data, binary.memory.len() as u64);
+        data.extend(&binary.memory);
+        section(&mut module, 11, data);
+    }
+
+    Ok(module)
+}
+
+struct Function {
+    code: Vec<u8>,
+    // Maps the offsets where blocks start to their index.
+    blocks: BTreeMap<usize, u32>,
+    syscalls: Vec<u8>,
+    stack_limit: usize,
+    trapping: bool,
+}
+
+impl Function {
+    fn compile(&mut self, binary: &Binary, options: &CompileOptions) {
+        for reg in Reg::ALL {
+            self.i64_const(initial_value(binary, reg, options.memory_size));
+            self.local_set(reg_index(reg));
+        }
+        let entry = self.block_index(binary.entry_point());
+        self.i32_const(entry as i32);
+        self.local_set(PC);
+
+        let num_blocks = self.blocks.len() as u32;
+        self.code.push(0x03); // loop
+        self.code.push(0x40);
+        // One block for each soil block plus one for panics.
+        for _ in 0..=num_blocks {
+            self.code.push(0x02); // block
+            self.code.push(0x40);
+        }
+        self.local_get(PC);
+        self.code.push(0x0e); // br_table
+        uleb(&mut self.code, num_blocks as u64);
+        for i in 0..num_blocks {
+            uleb(&mut self.code, i as u64);
+        }
+        uleb(&mut self.code, num_blocks as u64);
+
+        let mut byte_code = binary.byte_code.byte_code();
+        let mut block = 0;
+        loop {
+            let cursor = byte_code.cursor;
+            let instruction = match byte_code.next() {
+                Some(instruction) => instruction,
+                None => break,
+            };
+            if let Some(index) = self.blocks.get(&cursor) {
+                block = *index;
+                self.code.push(0x0b); // end of the block this one starts at
+            }
+            // Inside block i, the loop is num_blocks - i labels out.
+            self.instruction(instruction, byte_code.cursor, num_blocks - block);
+        }
+        // Running past the end of the byte code panics.
+        self.code.push(0x0b);
+        self.code.push(0x00); // unreachable
+        self.code.push(0x0b); // end of the loop
+        self.code.push(0x00);
+    }
+
+    fn instruction(&mut self, instruction: Instruction, next: usize, depth: u32) {
+        match instruction {
+            Instruction::Nop | Instruction::Breakpoint => {}
+            Instruction::Panic => self.code.push(0x00),
+            Instruction::Move_(a, b) => {
+                self.get(b);
+                self.set(a);
+            }
+            Instruction::Movei(a, value) => {
+                self.i64_const(value);
+                self.set(a);
+            }
+            Instruction::Moveib(a, value) => {
+                self.i64_const(value as i64);
+                self.set(a);
+            }
+            Instruction::Moveisb(a, value) => {
+                self.i64_const(value as i64);
+                self.set(a);
+            }
+            Instruction::Swap(a, b) => {
+                self.get(a);
+                self.get(b);
+                self.set(a);
+                self.set(b);
+            }
+            // select takes the first value if the condition is not zero.
+            Instruction::Movz(a, b) => {
+                self.get(b);
+                self.get(a);
+                self.get(Reg::ST);
+                self.code.push(0x50); // i64.eqz
+                self.code.push(0x1b); // select
+                self.set(a);
+            }
+            Instruction::Movnz(a, b) => {
+                self.get(a);
+                self.get(b);
+                self.get(Reg::ST);
+                self.code.push(0x50); // i64.eqz
+                self.code.push(0x1b); // select
+                self.set(a);
+            }
+            Instruction::Load(a, b) => {
+                self.address(b);
+                self.memory_op(0x29, 3); // i64.load
+                self.set(a);
+            }
+            Instruction::Loadb(a, b) => {
+                self.address(b);
+                self.memory_op(0x31, 0); // i64.load8_u
+                self.set(a);
+            }
+            Instruction::Store(a, b) => {
+                self.address(a);
+                self.get(b);
+                self.memory_op(0x37, 3); // i64.store
+            }
+            Instruction::Storeb(a, b) => {
+                self.address(a);
+                self.get(b);
+                self.memory_op(0x3c, 0); // i64.store8
+            }
+            Instruction::Loadh(a, b) => {
+                self.address(b);
+                self.memory_op(0x33, 1); // i64.load16_u
+                self.set(a);
+            }
+            Instruction::Loadw(a, b) => {
+                self.address(b);
+                self.memory_op(0x35, 2); // i64.load32_u
+                self.set(a);
+            }
+            Instruction::Storeh(a, b) => {
+                self.address(a);
+                self.get(b);
+                self.memory_op(0x3d, 1); // i64.store16
+            }
+            Instruction::Storew(a, b) => {
+                self.address(a);
+                self.get(b);
+                self.memory_op(0x3e, 2); // i64.store32
+            }
+            Instruction::Push(a) => {
+                self.get(Reg::SP);
+                self.i64_const(8);
+                self.code.push(0x7d); // i64.sub
+                self.set(Reg::SP);
+                self.get(Reg::SP);
+                self.i64_const(self.stack_limit as i64);
+                self.code.push(0x53); // i64.lt_s
+                self.trap_if();
+                self.address(Reg::SP);
+                self.get(a);
+                self.memory_op(0x37, 3);
+            }
+            Instruction::Pop(a) => {
+                self.address(Reg::SP);
+                self.memory_op(0x29, 3);
+                self.get(Reg::SP);
+                self.i64_const(8);
+                self.code.push(0x7c); // i64.add
+                self.set(Reg::SP);
+                self.set(a);
+            }
+            Instruction::Jump(target) | Instruction::Jumpr(target) => self.jump(target, depth),
+            Instruction::Cjump(target) | Instruction::Cjumpr(target) => {
+                self.get(Reg::ST);
+                self.code.push(0x50); // i64.eqz
+                self.code.push(0x45); // i32.eqz
+                self.code.push(0x04); // if
+                self.code.push(0x40);
+                self.jump(target, depth + 1);
+                self.code.push(0x0b);
+            }
+            Instruction::Call(target) | Instruction::Callr(target) => {
+                self.local_get(CSP);
+                self.i32_const(CALL_STACK_SIZE as i32);
+                self.code.push(0x4f); // i32.ge_u
+                self.trap_if();
+                self.local_get(CSP);
+                self.i64_const(self.blocks[&next] as i64);
+                self.call_stack_op(0x37);
+                self.local_get(CSP);
+                self.i32_const(8);
+                self.code.push(0x6a); // i32.add
+                self.local_set(CSP);
+                self.jump(target, depth);
+            }
+            Instruction::Ret => {
+                self.local_get(CSP);
+                self.i32_const(8);
+                self.code.push(0x6b); // i32.sub
+                self.local_set(CSP);
+                self.local_get(CSP);
+                self.call_stack_op(0x29);
+                self.code.push(0xa7); // i32.wrap_i64
+                self.local_set(PC);
+                self.br(depth);
+            }
+            Instruction::Syscall(0) => {
+                self.get(Reg::A);
+                self.code.push(0x0f); // return
+            }
+            Instruction::Syscall(number) => {
+                for reg in [Reg::A, Reg::B, Reg::C, Reg::D, Reg::E, Reg::F] {
+                    self.get(reg);
+                }
+                let index = self.syscalls.iter().position(|it| *it == number).unwrap();
+                self.code.push(0x10); // call
+                uleb(&mut self.code, index as u64);
+                self.set(Reg::A);
+            }
+            Instruction::Cmp(a, b) => {
+                self.get(a);
+                self.get(b);
+                self.code.push(0x7d);
+                self.set(Reg::ST);
+            }
+            Instruction::Cmpz(a) => {
+                self.get(a);
+                self.set(Reg::ST);
+            }
+            Instruction::Isequal => self.compare_st(0x51),
+            Instruction::Isless => self.compare_st(0x53),
+            Instruction::Isgreater => self.compare_st(0x55),
+            Instruction::Islessequal => self.compare_st(0x57),
+            Instruction::Isgreaterequal => self.compare_st(0x59),
+            Instruction::Inttofloat(a) => {
+                self.get(a);
+                self.code.push(0xb9); // f64.convert_i64_s
+                self.code.push(0xbd); // i64.reinterpret_f64
+                self.set(a);
+            }
+            // Saturates and turns NaN into 0.
+            Instruction::Floattoint(a) => {
+                self.get(a);
+                self.code.push(0xbf); // f64.reinterpret_i64
+                self.code.extend([0xfc, 0x06]); // i64.trunc_sat_f64_s
+                self.set(a);
+            }
+            Instruction::Add(a, b) => self.arithmetic(0x7c, a, b),
+            Instruction::Sub(a, b) => self.arithmetic(0x7d, a, b),
+            Instruction::Mul(a, b) => self.arithmetic(0x7e, a, b),
+            // Dividing by zero traps, which is a panic.
+            Instruction::Div(a, b) => self.divide(a, b),
+            Instruction::Rem(a, b) => self.binary_op(0x81, a, b),
+            Instruction::Udiv(a, b) => self.binary_op(0x80, a, b),
+            Instruction::Urem(a, b) => self.binary_op(0x82, a, b),
+            Instruction::Addc(a, b) => self.checked(0x7c, a, b),
+            Instruction::Subc(a, b) => self.checked(0x7d, a, b),
+            Instruction::Mulc(a, b) => self.checked(0x7e, a, b),
+            Instruction::And(a, b) => self.binary_op(0x83, a, b),
+            Instruction::Or(a, b) => self.binary_op(0x84, a, b),
+            Instruction::Xor(a, b) => self.binary_op(0x85, a, b),
+            Instruction::Negate(a) => {
+                self.get(a);
+                self.i64_const(-1);
+                self.code.push(0x85);
+                self.set(a);
+            }
+            // The wasm shifts already use the amount modulo 64.
+            Instruction::Shl(a, b) => self.binary_op(0x86, a, b),
+            Instruction::Shr(a, b) => self.binary_op(0x88, a, b),
+            Instruction::Sar(a, b) => self.binary_op(0x87, a, b),
+            Instruction::Rotl(a, b) => self.binary_op(0x89, a, b),
+            Instruction::Rotr(a, b) => self.binary_op(0x8a, a, b),
+        }
+    }
+
+    fn block_index(&self, offset: usize) -> u32 {
+        self.blocks
+            .get(&offset)
+            .copied()
+            .unwrap_or(self.blocks.len() as u32)
+    }
+
+    // Continues at the block starting at target. depth is the number of
+    // labels between the current code and the dispatch loop.
+    fn jump(&mut self, target: usize, depth: u32) {
+        let index = self.block_index(target);
+        self.i32_const(index as i32);
+        self.local_set(PC);
+        self.br(depth);
+    }
+
+    fn binary_op(&mut self, op: u8, a: Reg, b: Reg) {
+        self.get(a);
+        self.get(b);
+        self.code.push(op);
+        self.set(a);
+    }
+
+    // i64.div_s also traps for i64::MIN / -1, which soil wraps, so dividing
+    // by -1 negates instead.
+    fn divide(&mut self, a: Reg, b: Reg) {
+        self.get(b);
+        self.i64_const(-1);
+        self.code.push(0x51); // i64.eq
+        self.code.extend([0x04, 0x40]); // if
+        if self.trapping {
+            self.get(a);
+            self.i64_const(i64::MIN);
+            self.code.push(0x51);
+            self.trap_if();
+        }
+        self.i64_const(0);
+        self.get(a);
+        self.code.push(0x7d); // i64.sub
+        self.set(a);
+        self.code.push(0x05); // else
+        self.binary_op(0x7f, a, b);
+        self.code.push(0x0b); // end
+    }
+
+    fn arithmetic(&mut self, op: u8, a: Reg, b: Reg) {
+        if !self.trapping {
+            self.binary_op(op, a, b);
+            return;
+        }
+        self.overflows(op, a, b);
+        self.trap_if();
+        self.local_get(TMP);
+        self.set(a);
+    }
+
+    // Like the arithmetic op, but sets st to whether it overflowed.
+    fn checked(&mut self, op: u8, a: Reg, b: Reg) {
+        self.overflows(op, a, b);
+        self.code.push(0xad); // i64.extend_i32_u
+        self.local_get(TMP);
+        self.set(a);
+        self.set(Reg::ST);
+    }
+
+    // Saves the result of the op in TMP and leaves whether it overflowed on
+    // the stack as an i32.
+    fn overflows(&mut self, op: u8, a: Reg, b: Reg) {
+        self.get(a);
+        self.get(b);
+        self.code.push(op);
+        self.local_set(TMP);
+        match op {
+            // The sum overflowed if its sign differs from both operands.
+            0x7c => {
+                self.get(a);
+                self.local_get(TMP);
+                self.code.push(0x85);
+                self.get(b);
+                self.local_get(TMP);
+                self.code.push(0x85);
+                self.code.push(0x83); // i64.and
+                self.i64_const(0);
+                self.code.push(0x53);
+            }
+            // The difference overflowed if the operands have different signs
+            // and its sign differs from the first one.
+            0x7d => {
+                self.get(a);
+                self.get(b);
+                self.code.push(0x85);
+                self.get(a);
+                self.local_get(TMP);
+                self.code.push(0x85);
+                self.code.push(0x83);
+                self.i64_const(0);
+                self.code.push(0x53);
+            }
+            // The product overflowed if dividing it by a doesn't give b. That
+            // division would itself overflow for a = -1, so that case is
+            // handled separately.
+            _ => {
+                self.get(a);
+                self.code.push(0x50); // i64.eqz
+                self.code.extend([0x04, 0x7f]); // if (result i32)
+                self.i32_const(0);
+                self.code.push(0x05); // else
+                self.get(a);
+                self.i64_const(-1);
+                self.code.push(0x51); // i64.eq
+                self.code.extend([0x04, 0x7f]);
+                self.get(b);
+                self.i64_const(i64::MIN);
+                self.code.push(0x51);
+                self.code.push(0x05);
+                self.local_get(TMP);
+                self.get(a);
+                self.code.push(0x7f); // i64.div_s
+                self.get(b);
+                self.code.push(0x52); // i64.ne
+                self.code.push(0x0b);
+                self.code.push(0x0b);
+            }
+        }
+    }
+
+    // Sets st to 1 if comparing st against zero with the op holds.
+    fn compare_st(&mut self, op: u8) {
+        self.get(Reg::ST);
+        self.i64_const(0);
+        self.code.push(op);
+        self.code.push(0xad);
+        self.set(Reg::ST);
+    }
+
+    // Traps if the i32 on the stack isn't 0.
+    fn trap_if(&mut self) {
+        self.code.extend([0x04, 0x40, 0x00, 0x0b]);
+    }
+
+    // Pushes the register as an address into the soil memory.
+    fn address(&mut self, reg: Reg) {
+        self.get(reg);
+        self.code.push(0xa7); // i32.wrap_i64
+    }
+
+    // Accesses the soil memory, which comes after the call stack.
+    fn memory_op(&mut self, op: u8, align: u32) {
+        self.code.push(op);
+        uleb(&mut self.code, align as u64);
+        uleb(&mut self.code, CALL_STACK_SIZE as u64);
+    }
+    fn call_stack_op(&mut self, op: u8) {
+        self.code.push(op);
+        uleb(&mut self.code, 3);
+        uleb(&mut self.code, 0);
+    }
+
+    fn get(&mut self, reg: Reg) {
+        self.local_get(reg_index(reg));
+    }
+    fn set(&mut self, reg: Reg) {
+        self.local_set(reg_index(reg));
+    }
+    fn local_get(&mut self, index: u32) {
+        self.code.push(0x20);
+        uleb(&mut self.code, index as u64);
+    }
+    fn local_set(&mut self, index: u32) {
+        self.code.push(0x21);
+        uleb(&mut self.code, index as u64);
+    }
+    fn i32_const(&mut self, value: i32) {
+        self.code.push(0x41);
+        sleb(&mut self.code, value as i64);
+    }
+    fn i64_const(&mut self, value: i64) {
+        self.code.push(0x42);
+        sleb(&mut self.code, value);
+    }
+    fn br(&mut self, depth: u32) {
+        self.code.push(0x0c);
+        uleb(&mut self.code, depth as u64);
+    }
+}
+
+fn reg_index(reg: Reg) -> u32 {
+    reg.index() as u32
+}
+
+fn section(module: &mut Vec<u8>, id: u8, content: Vec<u8>) {
+    module.push(id);
+    uleb(module, content.len() as u64);
+    module.extend(content);
+}
+
+fn name(out: &mut Vec<u8>, name: &str) {
+    uleb(out, name.len() as u64);
+    out.extend(name.as_bytes());
+}
+
+fn uleb(out: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn sleb(out: &mut Vec<u8>, mut value: i64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        let done = (value == 0 && byte & 0x40 == 0) || (value == -1 && byte & 0x40 != 0);
+        if done {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn compile(byte_code: Vec<u8>, memory: Vec<u8>) -> Vec<u8> {
+        let binary = Binary {
+            byte_code,
+            memory,
+            ..Default::default()
+        };
+        compile_wasm(binary, &CompileOptions::default()).unwrap()
+    }
+
+    fn read_uleb(bytes: &mut &[u8]) -> u64 {
+        let mut value = 0;
+        for shift in (0..).step_by(7) {
+            let byte = bytes[0];
+            *bytes = &bytes[1..];
+            value |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                break;
+            }
+        }
+        value
+    }
+
+    fn read_name(bytes: &mut &[u8]) -> String {
+        let len = read_uleb(bytes) as usize;
+        let name = String::from_utf8(bytes[..len].to_vec()).unwrap();
+        *bytes = &bytes[len..];
+        name
+    }
+
+    // The ids and contents of the module's sections.
+    fn sections(module: &[u8]) -> Vec<(u8, &[u8])> {
+        assert_eq!(&module[..8], b"\0asm\x01\0\0\0");
+        let mut rest = &module[8..];
+        let mut sections = vec![];
+        while !rest.is_empty() {
+            let id = rest[0];
+            rest = &rest[1..];
+            let len = read_uleb(&mut rest) as usize;
+            sections.push((id, &rest[..len]));
+            rest = &rest[len..];
+        }
+        sections
+    }
+
+    fn section(module: &[u8], id: u8) -> &[u8] {
+        let sections = sections(module);
+        let (_, content) = sections.into_iter().find(|(i, _)| *i == id).unwrap();
+        content
+    }
+
+    #[test]
+    fn sections_are_in_order() {
+        let ids = |module: &[u8]| {
+            sections(module)
+                .iter()
+                .map(|(id, _)| *id)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(ids(&compile(vec![0xf3], vec![])), [1, 2, 3, 5, 7, 10]);
+        assert_eq!(ids(&compile(vec![0xf3], vec![1])), [1, 2, 3, 5, 7, 10, 11]);
+    }
+
+    #[test]
+    fn only_used_syscalls_are_imported() {
+        // print, instant, print, exit
+        let module = compile(vec![0xf4, 1, 0xf4, 16, 0xf4, 1, 0xf4, 0], vec![]);
+
+        let mut imports = section(&module, 2);
+        assert_eq!(read_uleb(&mut imports), 2);
+        for expected in ["syscall_1", "syscall_16"] {
+            assert_eq!(read_name(&mut imports), "soil");
+            assert_eq!(read_name(&mut imports), expected);
+            assert_eq!(&imports[..2], [0x00, 1]);
+            imports = &imports[2..];
+        }
+        assert!(imports.is_empty());
+
+        // The imported functions come first, so run's index is after them.
+        let mut exports = section(&module, 7);
+        assert_eq!(read_uleb(&mut exports), 2);
+        assert_eq!(read_name(&mut exports), "run");
+        assert_eq!(exports[0], 0x00);
+        exports = &exports[1..];
+        assert_eq!(read_uleb(&mut exports), 2);
+    }
+
+    #[test]
+    fn the_memory_comes_after_the_call_stack() {
+        let module = compile(vec![0xf3], vec![1, 2, 3]);
+        let mut data = section(&module, 11);
+        assert_eq!(&data[..3], [1, 0x00, 0x41]);
+        data = &data[3..];
+        let mut offset = vec![];
+        sleb(&mut offset, CALL_STACK_SIZE as i64);
+        assert_eq!(&data[..offset.len()], offset);
+        data = &data[offset.len()..];
+        assert_eq!(data, [0x0b, 3, 1, 2, 3]);
+    }
+}
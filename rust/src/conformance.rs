@@ -0,0 +1,450 @@
+use crate::{
+    binary::{ArithmeticMode, Binary},
+    compile::DEFAULT_MEMORY_SIZE,
+    interpreter::{Termination, Vm},
+};
+#[cfg(feature = "run-fasm")]
+use crate::{
+    compile::{compile_with_options, CompileOptions},
+    exit_status::PANIC_EXIT_STATUS,
+    run::{run, RunError},
+};
+
+// Runs a small program for each instruction with the interpreter and with the
+// fasm backend, and reports the ones where a backend doesn't compute the
+// expected value. Returns whether all backends got all cases right.
+//
+// Each case leaves its result in a. The program then exits with status 1 if
+// a is the expected value, and with status 0 otherwise, so comparing the exit
+// status is enough even though native exit statuses only have 8 bits.
+#[cfg(feature = "run-fasm")]
+pub fn check_backends() -> bool {
+    let mut all_correct = true;
+    for case in cases() {
+        let interpreted = interpret(&case);
+        let asm = compile_with_options(program(&case), &CompileOptions::default())
+            .expect("the cases fit into the default memory");
+        let compiled = match run(&asm) {
+            Ok(output) => match output.exit_code {
+                Some(1) => "ok".to_string(),
+                Some(0) => "wrong result".to_string(),
+                Some(PANIC_EXIT_STATUS) => "panicked".to_string(),
+                Some(status) => format!("exited with {}", status),
+                None => "killed by a signal".to_string(),
+            },
+            Err(RunError::FasmNotFound) => {
+                eprintln!("{}", RunError::FasmNotFound);
+                return false;
+            }
+            Err(error) => format!("{}", error),
+        };
+        if interpreted != "ok" || compiled != "ok" {
+            all_correct = false;
+        }
+        println!(
+            "{:16} interpreter: {}, fasm: {}",
+            case.name, interpreted, compiled
+        );
+    }
+    all_correct
+}
+
+fn interpret(case: &Case) -> String {
+    match Vm::init(program(case), &[], DEFAULT_MEMORY_SIZE)
+        .expect("the cases fit into the default memory")
+        .execute()
+    {
+        Termination::Exited(1) => "ok".to_string(),
+        Termination::Exited(_) => "wrong result".to_string(),
+        Termination::Panicked { reason, .. } => format!("panicked: {}", reason),
+    }
+}
+
+struct Case {
+    name: &'static str,
+    code: Vec<u8>,
+    expected: i64,
+}
+
+// Appends the check of a against the expected value and the exit.
+fn program(case: &Case) -> Binary {
+    let mut byte_code = case.code.clone();
+    byte_code.extend(movei(B, case.expected));
+    byte_code.extend(regs(0xb2, A, B)); // xor a b
+    byte_code.extend([0x90, A]); // cmpz a
+    byte_code.push(0xc1); // isequal
+    byte_code.extend(regs(0xd0, A, ST)); // move a st
+    byte_code.extend([0xf4, 0]); // syscall exit
+    Binary {
+        name: Some(case.name.to_string()),
+        memory: vec![],
+        byte_code,
+        labels: vec![],
+        source_map: vec![],
+        annotations: vec![],
+        arithmetic_mode: ArithmeticMode::Wrapping,
+        initial_registers: vec![],
+    }
+}
+
+const ST: u8 = 1;
+const A: u8 = 2;
+const B: u8 = 3;
+const C: u8 = 4;
+
+fn movei(reg: u8, value: i64) -> Vec<u8> {
+    let mut bytes = vec![0xd1, reg];
+    bytes.extend(value.to_le_bytes());
+    bytes
+}
+
+fn regs(opcode: u8, a: u8, b: u8) -> Vec<u8> {
+    vec![opcode, a | b << 4]
+}
+
+// A relative jump or call by the given amount of bytes.
+fn relative(opcode: u8, offset: i32) -> Vec<u8> {
+    let mut bytes = vec![opcode];
+    bytes.extend(offset.to_le_bytes());
+    bytes
+}
+
+fn word(opcode: u8, value: usize) -> Vec<u8> {
+    let mut bytes = vec![opcode];
+    bytes.extend((value as u64).to_le_bytes());
+    bytes
+}
+
+// Sets a to x and b to y, then runs the instruction on a and b.
+fn on(opcode: u8, x: i64, y: i64) -> Vec<u8> {
+    [movei(A, x), movei(B, y), regs(opcode, A, B)].concat()
+}
+
+// Compares x and y and saves the result of the is* instruction in a.
+fn compare(is: u8, x: i64, y: i64) -> Vec<u8> {
+    [on(0xc0, x, y), vec![is], regs(0xd0, A, ST)].concat()
+}
+
+fn case(name: &'static str, code: Vec<u8>, expected: i64) -> Case {
+    Case {
+        name,
+        code,
+        expected,
+    }
+}
+
+fn cases() -> Vec<Case> {
+    let min = i64::MIN;
+    let max = i64::MAX;
+    vec![
+        case("move", [movei(B, 7), regs(0xd0, A, B)].concat(), 7),
+        case("movei", movei(A, min), min),
+        case("moveib", vec![0xd2, A, 0xff], 255),
+        case("moveisb", vec![0xd9, A, 0xff], -1),
+        case("moveisb min", vec![0xd9, A, 0x80], -128),
+        case("moveisb max", vec![0xd9, A, 0x7f], 127),
+        case("swap", on(0xda, 1, 2), 2),
+        case(
+            "movz taken",
+            [vec![0xd2, ST, 0], on(0xdb, 1, 2)].concat(),
+            2,
+        ),
+        case(
+            "movz not taken",
+            [vec![0xd2, ST, 1], on(0xdb, 1, 2)].concat(),
+            1,
+        ),
+        case(
+            "movnz taken",
+            [vec![0xd2, ST, 1], on(0xdc, 1, 2)].concat(),
+            2,
+        ),
+        case(
+            "movnz not taken",
+            [vec![0xd2, ST, 0], on(0xdc, 1, 2)].concat(),
+            1,
+        ),
+        case(
+            "store load",
+            [
+                movei(B, 16),
+                movei(A, 0x1234),
+                regs(0xd5, B, A),
+                movei(A, 0),
+                regs(0xd3, A, B),
+            ]
+            .concat(),
+            0x1234,
+        ),
+        case(
+            "storeb loadb",
+            [
+                movei(B, 16),
+                movei(A, -1),
+                regs(0xd6, B, A),
+                regs(0xd4, A, B),
+            ]
+            .concat(),
+            255,
+        ),
+        case(
+            "storeh loadh",
+            [
+                movei(B, 16),
+                movei(A, -1),
+                regs(0xdf, B, A),
+                regs(0xdd, A, B),
+            ]
+            .concat(),
+            0xffff,
+        ),
+        case(
+            "storew loadw",
+            [
+                movei(B, 16),
+                movei(A, -1),
+                regs(0xe2, B, A),
+                regs(0xde, A, B),
+            ]
+            .concat(),
+            0xffff_ffff,
+        ),
+        // Stores a word and loads its lowest half word, so the bytes have to be
+        // in little endian order.
+        case(
+            "loadh endianness",
+            [
+                movei(B, 16),
+                movei(A, 0x1234_5678),
+                regs(0xd5, B, A),
+                regs(0xdd, A, B),
+            ]
+            .concat(),
+            0x5678,
+        ),
+        case(
+            "push pop",
+            [movei(A, 42), vec![0xd7, A], movei(A, 0), vec![0xd8, A]].concat(),
+            42,
+        ),
+        // movei a 1, jump to the end, movei a 2
+        case(
+            "jump",
+            [movei(A, 1), word(0xf0, 29), movei(A, 2)].concat(),
+            1,
+        ),
+        // movei a 1, moveib st 1, cjump to the end, movei a 2
+        case(
+            "cjump",
+            [movei(A, 1), vec![0xd2, ST, 1], word(0xf1, 32), movei(A, 2)].concat(),
+            1,
+        ),
+        // movei a 1, call the function, jump to the end, and the function:
+        // movei a 5, ret
+        case(
+            "call ret",
+            [
+                movei(A, 1),
+                word(0xf2, 28),
+                word(0xf0, 39),
+                movei(A, 5),
+                vec![0xf3],
+            ]
+            .concat(),
+            5,
+        ),
+        // movei a 1, jumpr over the next instruction, movei a 2
+        case(
+            "jumpr",
+            [movei(A, 1), relative(0xf5, 10), movei(A, 2)].concat(),
+            1,
+        ),
+        // jumpr to the last instruction, which jumps back to movei a 7, after
+        // which a jumpr skips to the end
+        case(
+            "jumpr backwards",
+            [
+                relative(0xf5, 15),
+                movei(A, 7),
+                relative(0xf5, 5),
+                relative(0xf5, -20),
+            ]
+            .concat(),
+            7,
+        ),
+        case(
+            "cjumpr taken",
+            [
+                movei(A, 1),
+                vec![0xd2, ST, 1],
+                relative(0xf6, 10),
+                movei(A, 2),
+            ]
+            .concat(),
+            1,
+        ),
+        case(
+            "cjumpr not taken",
+            [
+                movei(A, 1),
+                vec![0xd2, ST, 0],
+                relative(0xf6, 10),
+                movei(A, 2),
+            ]
+            .concat(),
+            2,
+        ),
+        // movei a 1, callr the function, jumpr to the end, and the function:
+        // movei a 5, ret
+        case(
+            "callr ret",
+            [
+                movei(A, 1),
+                relative(0xf7, 5),
+                relative(0xf5, 11),
+                movei(A, 5),
+                vec![0xf3],
+            ]
+            .concat(),
+            5,
+        ),
+        case("breakpoint", [movei(A, 3), vec![0xe1]].concat(), 3),
+        case("cmp", [on(0xc0, 5, 3), regs(0xd0, A, ST)].concat(), 2),
+        case(
+            "cmp wraps",
+            [on(0xc0, min, 1), regs(0xd0, A, ST)].concat(),
+            max,
+        ),
+        case(
+            "cmpz",
+            [movei(A, -3), vec![0x90, A], regs(0xd0, A, ST)].concat(),
+            -3,
+        ),
+        case("isequal", compare(0xc1, 3, 3), 1),
+        case("isless", compare(0xc2, -1, 1), 1),
+        case("isgreater", compare(0xc3, -1, 1), 0),
+        case("islessequal", compare(0xc4, 1, 1), 1),
+        case("isgreaterequal", compare(0xc5, -1, 1), 0),
+        case(
+            "inttofloat",
+            [movei(A, -3), vec![0xce, A]].concat(),
+            (-3.0f64).to_bits() as i64,
+        ),
+        case(
+            "floattoint",
+            [movei(A, (-2.5f64).to_bits() as i64), vec![0xcf, A]].concat(),
+            -2,
+        ),
+        case(
+            "floattoint nan",
+            [movei(A, f64::NAN.to_bits() as i64), vec![0xcf, A]].concat(),
+            0,
+        ),
+        case(
+            "floattoint inf",
+            [movei(A, f64::INFINITY.to_bits() as i64), vec![0xcf, A]].concat(),
+            max,
+        ),
+        case(
+            "floattoint -inf",
+            [movei(A, f64::NEG_INFINITY.to_bits() as i64), vec![0xcf, A]].concat(),
+            min,
+        ),
+        case(
+            "floattoint 1e19",
+            [movei(A, 1e19f64.to_bits() as i64), vec![0xcf, A]].concat(),
+            max,
+        ),
+        case("add", on(0xa0, max, 1), min),
+        case("sub", on(0xa1, min, 1), max),
+        case("mul", on(0xa2, -3, 5), -15),
+        case("div", on(0xa3, -7, 2), -3),
+        case("rem", on(0xa4, -7, 2), -1),
+        case("div overflow", on(0xa3, min, -1), min),
+        case("rem overflow", on(0xa4, min, -1), 0),
+        case("udiv", on(0xa9, -1, 2), max),
+        case("urem", on(0xaa, -1, 16), 15),
+        case("addc", on(0xab, max, 1), min),
+        case("addc st", [on(0xab, max, 1), regs(0xd0, A, ST)].concat(), 1),
+        case("subc st", [on(0xac, 5, 3), regs(0xd0, A, ST)].concat(), 0),
+        case(
+            "subc min st",
+            [on(0xac, min, 1), regs(0xd0, A, ST)].concat(),
+            1,
+        ),
+        case("mulc st", [on(0xad, max, 2), regs(0xd0, A, ST)].concat(), 1),
+        case("and", on(0xb0, 0b1100, 0b1010), 0b1000),
+        case("or", on(0xb1, 0b1100, 0b1010), 0b1110),
+        case("xor", on(0xb2, 0b1100, 0b1010), 0b0110),
+        case("not", [movei(A, 5), vec![0xb3, A]].concat(), !5),
+        case("shl", on(0xb4, 1, 65), 2),
+        case("shr", on(0xb5, -16, 60), 15),
+        case("sar", on(0xb6, -16, 2), -4),
+        case("rotl", on(0xb7, 0x0123456789abcdef, 4), 0x123456789abcdef0),
+        case(
+            "rotl 68",
+            on(0xb7, 0x0123456789abcdef, 68),
+            0x123456789abcdef0,
+        ),
+        case(
+            "rotr",
+            on(0xb8, 0x0123456789abcdef, 4),
+            0xf0123456789abcde_u64 as i64,
+        ),
+        case(
+            "rotr 68",
+            on(0xb8, 0x0123456789abcdef, 68),
+            0xf0123456789abcde_u64 as i64,
+        ),
+        // print and log return nothing, so they leave a as it is
+        case(
+            "print",
+            [movei(A, 16), movei(B, 0), vec![0xf4, 1]].concat(),
+            16,
+        ),
+        case(
+            "log",
+            [movei(A, 16), movei(B, 0), vec![0xf4, 2]].concat(),
+            16,
+        ),
+        // writes a newline to stdout
+        case(
+            "write",
+            [
+                movei(B, 16),
+                movei(A, 10),
+                regs(0xd6, B, A),
+                movei(A, 1),
+                movei(C, 1),
+                vec![0xf4, 7],
+            ]
+            .concat(),
+            1,
+        ),
+        case("argc", vec![0xf4, 9], 1),
+        // copies none of the program's name
+        case(
+            "arg",
+            [movei(A, 0), movei(B, 16), movei(C, 0), vec![0xf4, 10]].concat(),
+            0,
+        ),
+        case("poll_signals", [movei(A, 5), vec![0xf4, 15]].concat(), 0),
+        case(
+            "instant",
+            [vec![0xf4, 16], vec![0x90, A], vec![0xc5], regs(0xd0, A, ST)].concat(),
+            1,
+        ),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interpreter_passes_all_cases() {
+        for case in cases() {
+            assert_eq!(interpret(&case), "ok", "{}", case.name);
+        }
+    }
+}
@@ -0,0 +1,185 @@
+use crate::{binary::Binary, disassemble::disassemble};
+
+// A line of the disassembly. Instructions have an offset, labels and
+// annotations don't.
+#[derive(Clone, Copy)]
+struct Line<'a> {
+    offset: Option<&'a str>,
+    text: &'a str,
+}
+
+fn lines(disassembly: &str) -> Vec<Line<'_>> {
+    disassembly
+        .lines()
+        .map(|line| {
+            // Instructions are the offset, the entry point marker, and the
+            // instruction. The marker is left out so that it doesn't show up
+            // as a difference.
+            let instruction = line.split_once(' ').and_then(|(offset, rest)| {
+                let is_offset = offset.len() >= 4 && offset.chars().all(|c| c.is_ascii_hexdigit());
+                let text = rest
+                    .strip_prefix("> ")
+                    .or_else(|| rest.strip_prefix("| "))?;
+                is_offset.then_some(Line {
+                    offset: Some(offset),
+                    text,
+                })
+            });
+            instruction.unwrap_or(Line {
+                offset: None,
+                text: line.trim_start(),
+            })
+        })
+        .collect()
+}
+
+enum Change {
+    Same,
+    Removed,
+    Added,
+}
+
+// Compares the disassemblies of both binaries and renders the lines that were
+// removed from the old one (-) or added in the new one (+), with their offsets
+// in both. Instructions are compared without their offsets, so code that only
+// moved doesn't show up. Differences in the memory are summarized at the end.
+// Returns an empty string if the binaries are the same.
+pub fn diff(old: &Binary, new: &Binary) -> String {
+    let (old_disassembly, new_disassembly) = (disassemble(old), disassemble(new));
+    let (old_lines, new_lines) = (lines(&old_disassembly), lines(&new_disassembly));
+
+    let mut out = String::new();
+    for (change, line) in changes(&old_lines, &new_lines) {
+        let (sign, old_offset, new_offset) = match change {
+            Change::Same => continue,
+            Change::Removed => ("-", line.offset, None),
+            Change::Added => ("+", None, line.offset),
+        };
+        out.push_str(&format!(
+            "{} {:4} {:4} {}\n",
+            sign,
+            old_offset.unwrap_or(""),
+            new_offset.unwrap_or(""),
+            line.text
+        ));
+    }
+
+    if old.memory != new.memory {
+        let first_difference = old
+            .memory
+            .iter()
+            .zip(&new.memory)
+            .position(|(old, new)| old != new)
+            .unwrap_or(old.memory.len().min(new.memory.len()));
+        out.push_str(&format!(
+            "memory differs from byte {:x} on (it was {} bytes and is {} bytes)\n",
+            first_difference,
+            old.memory.len(),
+            new.memory.len()
+        ));
+    }
+    out
+}
+
+// A longest common subsequence of the lines, with the rest marked as removed
+// or added. The common start and end are skipped first, so that small changes
+// to large programs stay cheap.
+fn changes<'a>(old: &[Line<'a>], new: &[Line<'a>]) -> Vec<(Change, Line<'a>)> {
+    let prefix = old
+        .iter()
+        .zip(new)
+        .take_while(|(old, new)| old.text == new.text)
+        .count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(old, new)| old.text == new.text)
+        .count();
+    let (old_middle, new_middle) = (
+        &old[prefix..old.len() - suffix],
+        &new[prefix..new.len() - suffix],
+    );
+
+    // common[i][j] is the length of the longest common subsequence of
+    // old_middle[i..] and new_middle[j..].
+    let mut common = vec![vec![0; new_middle.len() + 1]; old_middle.len() + 1];
+    for i in (0..old_middle.len()).rev() {
+        for j in (0..new_middle.len()).rev() {
+            common[i][j] = if old_middle[i].text == new_middle[j].text {
+                common[i + 1][j + 1] + 1
+            } else {
+                common[i + 1][j].max(common[i][j + 1])
+            };
+        }
+    }
+
+    let mut changes: Vec<_> = old[..prefix]
+        .iter()
+        .map(|line| (Change::Same, *line))
+        .collect();
+    let (mut i, mut j) = (0, 0);
+    while i < old_middle.len() || j < new_middle.len() {
+        if i < old_middle.len() && j < new_middle.len() && old_middle[i].text == new_middle[j].text
+        {
+            changes.push((Change::Same, old_middle[i]));
+            i += 1;
+            j += 1;
+        } else if j == new_middle.len()
+            || (i < old_middle.len() && common[i + 1][j] >= common[i][j + 1])
+        {
+            changes.push((Change::Removed, old_middle[i]));
+            i += 1;
+        } else {
+            changes.push((Change::Added, new_middle[j]));
+            j += 1;
+        }
+    }
+    changes.extend(
+        old[old.len() - suffix..]
+            .iter()
+            .map(|line| (Change::Same, *line)),
+    );
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn binary(byte_code: Vec<u8>) -> Binary {
+        Binary {
+            byte_code,
+            labels: vec![(0, "main".to_string())],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn pinpoints_a_changed_instruction() {
+        // add a b, sub a b, ret
+        let old = binary(vec![0xa0, 0x32, 0xa1, 0x32, 0xf3]);
+        // add a b, mul a b, ret
+        let new = binary(vec![0xa0, 0x32, 0xa2, 0x32, 0xf3]);
+        assert_eq!(
+            diff(&old, &new),
+            "- 0002      sub a b\n\
+             +      0002 mul a b\n"
+        );
+        assert_eq!(diff(&old, &old), "");
+    }
+
+    #[test]
+    fn moved_code_is_not_a_difference() {
+        let old = binary(vec![0xa0, 0x32, 0xf3]);
+        let mut new = binary(vec![0x00, 0xa0, 0x32, 0xf3]);
+        new.labels.push((3, "end".to_string()));
+        new.memory = vec![1, 2];
+        assert_eq!(
+            diff(&old, &new),
+            "+      0000 nop\n\
+             +           end:\n\
+             memory differs from byte 0 on (it was 0 bytes and is 2 bytes)\n"
+        );
+    }
+}
@@ -0,0 +1,157 @@
+use crate::{
+    binary::Binary,
+    bytecode::{ByteCode, DecodeError},
+};
+
+// Renders the byte code as one instruction per line, prefixed with its offset.
+// Labels become headers and annotations become comments above the
+// instructions they belong to. Invalid instructions are shown instead of aborting,
+// so that partially corrupt binaries can still be inspected. The entry point
+// is marked with an arrow.
+pub fn disassemble(binary: &Binary) -> String {
+    render(binary, false)
+}
+
+// Like disassemble, but also shows the bytes of each instruction between the
+// offset and the instruction.
+pub fn disassemble_with_bytes(binary: &Binary) -> String {
+    render(binary, true)
+}
+
+// The longest instruction is movei with its opcode, register, and word.
+const MAX_INSTRUCTION_LEN: usize = 10;
+
+fn render(binary: &Binary, show_bytes: bool) -> String {
+    let mut out = String::new();
+    let mut parser = binary.byte_code.byte_code();
+    loop {
+        let offset = parser.cursor;
+        let Some(instruction) = parser.next_instruction() else {
+            break;
+        };
+        for (_, label) in binary.labels.iter().filter(|(pos, _)| *pos == offset) {
+            out.push_str(&format!("{}:\n", label));
+        }
+        for (_, key, value) in binary
+            .annotations
+            .iter()
+            .filter(|(pos, _, _)| *pos == offset)
+        {
+            out.push_str(&format!("       ; {}: {}\n", key, value));
+        }
+        let marker = if offset == binary.entry_point() {
+            ">"
+        } else {
+            "|"
+        };
+        out.push_str(&format!("{:04x} {} ", offset, marker));
+        // A truncated instruction reaches until the end of the byte code.
+        let truncated = matches!(instruction, Err(DecodeError::Truncated { .. }));
+        let end = if truncated {
+            binary.byte_code.len()
+        } else {
+            parser.cursor
+        };
+        if show_bytes {
+            let bytes: Vec<_> = binary.byte_code[offset..end]
+                .iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect();
+            out.push_str(&format!(
+                "{:width$}  ",
+                bytes.join(" "),
+                width = 3 * MAX_INSTRUCTION_LEN - 1
+            ));
+        }
+        match instruction {
+            Ok(instruction) => out.push_str(&format!("{}\n", instruction)),
+            Err(DecodeError::UnknownOpcode { opcode, .. }) if show_bytes => {
+                out.push_str(&format!(".byte 0x{:02x}\n", opcode))
+            }
+            Err(DecodeError::UnknownOpcode { opcode, .. }) => {
+                out.push_str(&format!("; <unknown 0x{:02x}>\n", opcode))
+            }
+            Err(error) => out.push_str(&format!("; <{}>\n", error)),
+        }
+        if truncated {
+            break;
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bytes_match_the_instruction_lengths() {
+        // movei a 42, add a b, an unknown opcode, ret
+        let byte_code = [
+            &[0xd1, 0x02][..],
+            &42u64.to_le_bytes(),
+            &[0xa0, 0x32, 0xff, 0xf3],
+        ]
+        .concat();
+        let binary = Binary {
+            byte_code,
+            ..Default::default()
+        };
+        let disassembly = disassemble_with_bytes(&binary);
+        assert_eq!(
+            disassembly,
+            "0000 > d1 02 2a 00 00 00 00 00 00 00  movei a 42\n\
+             000a | a0 32                          add a b\n\
+             000c | ff                             .byte 0xff\n\
+             000d | f3                             ret\n"
+        );
+        let mut parser = binary.byte_code.byte_code();
+        for line in disassembly.lines() {
+            let offset = parser.cursor;
+            parser.next_instruction();
+            let bytes = line[7..7 + 3 * MAX_INSTRUCTION_LEN]
+                .split_whitespace()
+                .count();
+            assert_eq!(bytes, parser.cursor - offset, "{}", line);
+        }
+    }
+
+    #[test]
+    fn annotations_are_above_their_instruction() {
+        // nop, ret
+        let binary = Binary {
+            byte_code: vec![0x00, 0xf3],
+            annotations: vec![(1, "inlined".to_string(), "helper".to_string())],
+            ..Default::default()
+        };
+        assert_eq!(
+            disassemble(&binary),
+            ["0000 > nop", "       ; inlined: helper", "0001 | ret", ""].join("\n")
+        );
+    }
+
+    #[test]
+    fn corrupt_bytes_dont_stop_the_disassembly() {
+        // nop, a corrupt byte, add a b, ret, and a movei that's cut off
+        let binary = Binary {
+            byte_code: vec![0x00, 0xff, 0xa0, 0x32, 0xf3, 0xd1, 0x02, 0x2a],
+            labels: vec![(0, "main".to_string()), (4, "helper".to_string())],
+            ..Default::default()
+        };
+        assert_eq!(
+            disassemble(&binary),
+            [
+                "main:",
+                "0000 > nop",
+                "0001 | ; <unknown 0xff>",
+                "0002 | add a b",
+                "helper:",
+                "0004 | ret",
+                "0005 | ; <the byte code ends in the instruction 0xd1 at byte 0x5>",
+                "",
+            ]
+            .join("\n")
+        );
+        assert!(disassemble_with_bytes(&binary).ends_with("0005 | d1 02 2a                       ; <the byte code ends in the instruction 0xd1 at byte 0x5>\n"));
+    }
+}
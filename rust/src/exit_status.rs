@@ -0,0 +1,6 @@
+// Exit statuses that the interpreter and the compiled executables agree on.
+
+// The process exits with this status if the program panics, like Rust
+// programs do. That way, scripts can tell panics apart from programs that exit
+// with 1.
+pub const PANIC_EXIT_STATUS: i32 = 101;
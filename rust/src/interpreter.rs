@@ -1,320 +1,1000 @@
-use std::{cmp::min, fs, process::exit};
+use std::{
+    cell::Cell,
+    cmp::min,
+    collections::HashMap,
+    fmt, fs,
+    io::{self, BufRead, BufReader, Read, Write},
+    ops::Range,
+    path::PathBuf,
+    process::exit,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::Sender,
+        Arc,
+    },
+    time::Instant,
+};
 
-use crate::{binary::Binary, utils::WordFromByteSlice};
+use crate::{
+    binary::{ArithmeticMode, Binary, SourceLocation},
+    bytecode::{ByteCode, Instruction, Reg},
+    exit_status::PANIC_EXIT_STATUS,
+    utils::WordFromByteSlice,
+};
 
-const MEMORY_SIZE: usize = 500000;
-const TRACE_CALLS: bool = true;
+const TRACE_CALLS: bool = false;
 
 #[derive(Debug, Default)]
 pub struct Vm {
-    // Registers
-    regs: [i64; 8], // sp, st, a, b, c, d, e, f
+    regs: Registers,
 
     // Memory
     memory: Vec<u8>,
 
-    // Byte code
+    arithmetic_mode: ArithmeticMode,
+
+    // Byte code. This is a separate address space from memory: Load and
+    // Store can only reach memory, so programs can't modify their own code.
     byte_code: Vec<u8>,
+    // The instruction at each offset of the byte code, decoded the first time
+    // it runs.
+    decoded: Vec<Decoded>,
     ip: usize,
     call_stack: Vec<usize>,
 
+    // The arguments of the argc and arg syscalls. Like in the other VMs, the
+    // first one is the path of the VM itself.
+    args: Vec<String>,
+
     // Debug stuff
     labels: Vec<(usize, String)>,
+    source_map: Vec<(usize, SourceLocation)>,
+    // While tracing, each instruction is printed to stderr together with the
+    // registers it changed. Tracing starts once ip reaches trace_from.
+    tracing: bool,
+    trace_from: Option<usize>,
+    // Set by the breakpoint instruction. While set, the debugger prompt is
+    // shown before each instruction.
+    stepping: bool,
+
+    // If set, syscall effects are sent here instead of going to the
+    // process's stdout/stderr, and exits and panics stop the VM instead of
+    // the process.
+    events: Option<Sender<VmEvent>>,
+    // If set, exits and panics stop the VM instead of the process, even
+    // without events. Either way, how the program ended is saved.
+    return_on_halt: bool,
+    halted: bool,
+    termination: Option<Termination>,
+    // If set, the memory is written to this file when the program exits or
+    // panics.
+    memory_dump_path: Option<PathBuf>,
+    // If set, the number of instructions that may still run before run
+    // returns.
+    fuel: Option<u64>,
+    // If set, how often the instruction at each offset ran. It's printed to
+    // stderr when the program exits or panics.
+    profile: Option<HashMap<usize, u64>>,
+
+    // Pushing below this address panics instead of overwriting the data
+    // before it.
+    stack_limit: usize,
+
+    // The lowest the stack pointer got, only tracked if track_memory is set.
+    track_memory: bool,
+    lowest_sp: i64,
+
+    // Signals raised by the host. If a handler is set, it's called on the
+    // next backwards jump while signals are pending.
+    signals: Signals,
+    signal_handler: Option<usize>,
+    signal_handler_depth: Option<usize>,
+    // What the instant syscall measures from. Set by its first call.
+    clock_epoch: Option<Instant>,
+    // The files the program opened, by file descriptor.
+    files: HashMap<i64, fs::File>,
+
+    panic_formatter: Option<PanicFormatter>,
+    pre_step_hook: Option<PreStepHook>,
+    // Host-defined syscalls. They take precedence over the built-in ones.
+    custom_syscalls: HashMap<u8, SyscallHandler>,
+
+    // Only used if check_uninitialized_reads is set: For each call frame, a
+    // bit mask of the registers written since the function was entered.
+    check_uninitialized_reads: bool,
+    initialized_registers: Vec<u8>,
+    uninitialized_read: Cell<Option<Reg>>,
+}
+
+// A handle for raising signals in a running VM from another thread. Signals
+// are numbered 0 to 63 and the program reads and clears the pending ones
+// using the poll_signals syscall.
+#[derive(Clone, Debug, Default)]
+pub struct Signals(Arc<AtomicU64>);
+
+impl Signals {
+    // Programs can only receive signals 0 to 63, so others are ignored.
+    pub fn raise(&self, signal: u8) {
+        if signal < 64 {
+            self.0.fetch_or(1 << signal, Ordering::SeqCst);
+        }
+    }
+    fn are_pending(&self) -> bool {
+        self.0.load(Ordering::Relaxed) != 0
+    }
+    fn take(&self) -> u64 {
+        self.0.swap(0, Ordering::SeqCst)
+    }
+}
+
+// The values of the eight registers.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Registers {
+    pub sp: i64,
+    pub st: i64,
+    pub a: i64,
+    pub b: i64,
+    pub c: i64,
+    pub d: i64,
+    pub e: i64,
+    pub f: i64,
+}
+
+impl Registers {
+    pub fn get(&self, reg: Reg) -> i64 {
+        match reg {
+            Reg::SP => self.sp,
+            Reg::ST => self.st,
+            Reg::A => self.a,
+            Reg::B => self.b,
+            Reg::C => self.c,
+            Reg::D => self.d,
+            Reg::E => self.e,
+            Reg::F => self.f,
+        }
+    }
+    pub fn set(&mut self, reg: Reg, value: i64) {
+        let slot = match reg {
+            Reg::SP => &mut self.sp,
+            Reg::ST => &mut self.st,
+            Reg::A => &mut self.a,
+            Reg::B => &mut self.b,
+            Reg::C => &mut self.c,
+            Reg::D => &mut self.d,
+            Reg::E => &mut self.e,
+            Reg::F => &mut self.f,
+        };
+        *slot = value;
+    }
+}
+
+// Everything known about a panic, used for rendering it.
+pub struct TrapInfo<'a> {
+    pub reason: &'a str,
+    pub ip: usize,
+    pub registers: Registers,
+    pub backtrace: Vec<Frame<'a>>,
+}
+pub struct Frame<'a> {
+    pub offset: usize,
+    pub label: Option<&'a str>,
+    pub location: Option<&'a SourceLocation>,
+}
+
+pub struct PanicFormatter(Box<dyn Fn(&TrapInfo) -> String>);
+
+impl fmt::Debug for PanicFormatter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "PanicFormatter")
+    }
+}
+
+// Called before each instruction with its offset. The result decides whether
+// the instruction runs.
+pub struct PreStepHook(Box<PreStepHookFn>);
+type PreStepHookFn = dyn FnMut(usize, &Instruction, &Vm) -> HookAction;
+
+impl fmt::Debug for PreStepHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "PreStepHook")
+    }
+}
+
+// A host-defined syscall. Gets the memory and the registers.
+pub struct SyscallHandler(Box<SyscallHandlerFn>);
+type SyscallHandlerFn = dyn FnMut(&mut [u8], &mut Registers) -> SyscallOutcome;
+
+impl fmt::Debug for SyscallHandler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SyscallHandler")
+    }
+}
+
+pub enum SyscallOutcome {
+    Continue,
+    Exit(i32),
+    Panic,
+}
+
+pub enum HookAction {
+    Continue,
+    // Continues after the instruction without running it.
+    Skip,
+    // Panics with the given reason instead of running the instruction.
+    Trap(String),
+}
+
+fn default_panic_format(info: &TrapInfo) -> String {
+    let mut out = String::new();
+    out.push_str(&format!("{}\n", info.reason));
+    out.push_str("Stack:\n");
+    for frame in &info.backtrace {
+        let label = frame.label.unwrap_or("(no label)");
+        match frame.location {
+            Some(location) => {
+                out.push_str(&format!("{:8x} {} in {}\n", frame.offset, location, label))
+            }
+            None => out.push_str(&format!("{:8x} {}\n", frame.offset, label)),
+        }
+    }
+    out.push_str("\nRegisters:\n");
+    for (reg, name) in Reg::ALL
+        .iter()
+        .zip(["sp", "st", "a ", "b ", "c ", "d ", "e ", "f "])
+    {
+        let value = info.registers.get(*reg);
+        out.push_str(&format!("{} = {:8} {:8x}\n", name, value, value));
+    }
+    out.push('\n');
+    out
+}
+
+// How a program ended.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Termination {
+    Exited(i64),
+    // The backtrace contains the return offsets of the calls on the call
+    // stack, followed by the offset of the panicking instruction. Binary's
+    // label_for turns them into function names.
+    Panicked {
+        reason: String,
+        backtrace: Vec<usize>,
+    },
+}
+
+// Why the VM couldn't be set up for a binary.
+#[derive(Debug, PartialEq, Eq)]
+pub enum InitError {
+    // The binary's initial sp doesn't point into the memory.
+    InitialSpOutsideOfMemory { sp: i64, memory_size: usize },
+    // The arguments of the main function need len bytes of stack, but there
+    // are only sp bytes below the initial sp.
+    ArgumentsDontFit { len: usize, sp: i64 },
+}
+
+impl fmt::Display for InitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InitError::InitialSpOutsideOfMemory { sp, memory_size } => write!(
+                f,
+                "the initial sp {} is outside of the memory, which is {} bytes large",
+                sp, memory_size
+            ),
+            InitError::ArgumentsDontFit { len, sp } => write!(
+                f,
+                "the arguments need {} bytes of stack, but the initial sp is {}",
+                len, sp
+            ),
+        }
+    }
+}
+
+// Why run_with_fuel returned.
+#[derive(Debug, PartialEq, Eq)]
+pub enum RunOutcome {
+    // The program exited or panicked. Without run_streaming or execute, that
+    // ends the process instead.
+    Halted,
+    // The instructions ran out. Calling run_with_fuel again resumes the
+    // program.
+    OutOfFuel,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum VmEvent {
+    Print(Vec<u8>),
+    Log(Vec<u8>),
+    Exit(i64),
+    Panic(String),
+}
+
+// An instruction decoded ahead of time, together with the function that
+// runs it. Running an instruction is then a single indirect call instead of
+// decoding its bytes and matching on its opcode, like a computed goto.
+#[derive(Clone, Copy, Debug)]
+struct Decoded {
+    handler: Handler,
+    a: Reg,
+    b: Reg,
+    // The immediate value, jump target, or syscall number.
+    value: i64,
+    size: usize,
+}
+type Handler = fn(&mut Vm, &Decoded, usize);
+
+impl Decoded {
+    const UNDECODED: Decoded = Decoded::with(Vm::decode, Reg::SP, Reg::SP, 0, 0);
+
+    const fn with(handler: Handler, a: Reg, b: Reg, value: i64, size: usize) -> Self {
+        Decoded {
+            handler,
+            a,
+            b,
+            value,
+            size,
+        }
+    }
+
+    fn new(instruction: Instruction, size: usize) -> Self {
+        use handlers::*;
+        let none = |handler: Handler| Decoded::with(handler, Reg::SP, Reg::SP, 0, size);
+        let reg = |handler: Handler, a| Decoded::with(handler, a, Reg::SP, 0, size);
+        let regs = |handler: Handler, a, b| Decoded::with(handler, a, b, 0, size);
+        let value = |handler: Handler, a, value| Decoded::with(handler, a, Reg::SP, value, size);
+        match instruction {
+            Instruction::Nop => none(nop),
+            Instruction::Panic => none(panic),
+            Instruction::Breakpoint => none(breakpoint),
+            Instruction::Move_(a, b) => regs(move_, a, b),
+            Instruction::Movei(a, v) => value(movei, a, v),
+            Instruction::Moveib(a, v) => value(movei, a, v as i64),
+            Instruction::Moveisb(a, v) => value(movei, a, v as i64),
+            Instruction::Swap(a, b) => regs(swap, a, b),
+            Instruction::Movz(a, b) => regs(movz, a, b),
+            Instruction::Movnz(a, b) => regs(movnz, a, b),
+            Instruction::Load(a, b) => regs(load, a, b),
+            Instruction::Loadb(a, b) => regs(loadb, a, b),
+            Instruction::Store(a, b) => regs(store, a, b),
+            Instruction::Storeb(a, b) => regs(storeb, a, b),
+            Instruction::Loadh(a, b) => regs(loadh, a, b),
+            Instruction::Loadw(a, b) => regs(loadw, a, b),
+            Instruction::Storeh(a, b) => regs(storeh, a, b),
+            Instruction::Storew(a, b) => regs(storew, a, b),
+            Instruction::Push(a) => reg(push, a),
+            Instruction::Pop(a) => reg(pop, a),
+            Instruction::Jump(target) | Instruction::Jumpr(target) => {
+                value(jump, Reg::SP, target as i64)
+            }
+            Instruction::Cjump(target) | Instruction::Cjumpr(target) => {
+                value(cjump, Reg::SP, target as i64)
+            }
+            Instruction::Call(target) | Instruction::Callr(target) => {
+                value(call, Reg::SP, target as i64)
+            }
+            Instruction::Ret => none(ret),
+            Instruction::Syscall(number) => value(syscall, Reg::SP, number as i64),
+            Instruction::Cmp(a, b) => regs(cmp, a, b),
+            Instruction::Cmpz(a) => reg(cmpz, a),
+            Instruction::Isequal => none(isequal),
+            Instruction::Isless => none(isless),
+            Instruction::Isgreater => none(isgreater),
+            Instruction::Islessequal => none(islessequal),
+            Instruction::Isgreaterequal => none(isgreaterequal),
+            Instruction::Inttofloat(a) => reg(inttofloat, a),
+            Instruction::Floattoint(a) => reg(floattoint, a),
+            Instruction::Add(a, b) => regs(add, a, b),
+            Instruction::Sub(a, b) => regs(sub, a, b),
+            Instruction::Mul(a, b) => regs(mul, a, b),
+            Instruction::Div(a, b) => regs(div, a, b),
+            Instruction::Rem(a, b) => regs(rem, a, b),
+            Instruction::Udiv(a, b) => regs(udiv, a, b),
+            Instruction::Urem(a, b) => regs(urem, a, b),
+            Instruction::Addc(a, b) => regs(addc, a, b),
+            Instruction::Subc(a, b) => regs(subc, a, b),
+            Instruction::Mulc(a, b) => regs(mulc, a, b),
+            Instruction::And(a, b) => regs(and, a, b),
+            Instruction::Or(a, b) => regs(or, a, b),
+            Instruction::Xor(a, b) => regs(xor, a, b),
+            Instruction::Negate(a) => reg(not, a),
+            Instruction::Shl(a, b) => regs(shl, a, b),
+            Instruction::Shr(a, b) => regs(shr, a, b),
+            Instruction::Sar(a, b) => regs(sar, a, b),
+            Instruction::Rotl(a, b) => regs(rotl, a, b),
+            Instruction::Rotr(a, b) => regs(rotr, a, b),
+        }
+    }
 }
-const SP: usize = 0;
-const ST: usize = 1;
-const REGA: usize = 2;
-const REGB: usize = 3;
-const REGC: usize = 4;
-const REGD: usize = 5;
-const REGE: usize = 6;
-const REGF: usize = 7;
 
 impl Vm {
-    pub fn init(binary: Binary, args: &[String]) -> Self {
-        let mut vm = Vm { regs: [0; 8], memory: binary.memory, byte_code: binary.byte_code, ip: 0, call_stack: vec![], labels: binary.labels };
+    // The memory is at least memory_size bytes large, or larger if the
+    // binary's initial memory doesn't fit.
+    pub fn init(binary: Binary, args: &[String], memory_size: usize) -> Result<Self, InitError> {
+        let mut vm = Vm {
+            ip: binary.entry_point(),
+            stack_limit: binary.memory.len(),
+            memory: binary.memory,
+            decoded: vec![Decoded::UNDECODED; binary.byte_code.len()],
+            byte_code: binary.byte_code,
+            labels: binary.labels,
+            source_map: binary.source_map,
+            arithmetic_mode: binary.arithmetic_mode,
+            args: std::env::args()
+                .take(1)
+                .chain(args.iter().cloned())
+                .collect(),
+            // Outside of functions, all registers have well-defined values.
+            initialized_registers: vec![0xff],
+            ..Default::default()
+        };
 
-        while vm.memory.len() < MEMORY_SIZE {
-            vm.memory.push(0);
+        // A zeroed vec is allocated in one go, while resizing writes the zeros
+        // one by one.
+        if vm.memory.len() < memory_size {
+            let mut memory = vec![0; memory_size];
+            memory[..vm.memory.len()].copy_from_slice(&vm.memory);
+            vm.memory = memory;
         }
 
-        vm.regs[SP] = vm.memory.len() as i64;
+        vm.set(Reg::SP, vm.memory.len() as i64);
+        for (reg, value) in binary.initial_registers {
+            if reg == Reg::SP && (value < 0 || value as usize > vm.memory.len()) {
+                return Err(InitError::InitialSpOutsideOfMemory {
+                    sp: value,
+                    memory_size: vm.memory.len(),
+                });
+            }
+            vm.set(reg, value);
+        }
 
         // Push main function arguments to the stack.
-        vm.regs[SP] -= (16 * args.len()) as i64;
-        let slice = vm.regs[SP];
+        let len = 16 * args.len() + args.iter().map(|arg| arg.len()).sum::<usize>();
+        let fits = (vm.get(Reg::SP) as usize)
+            .checked_sub(len)
+            .is_some_and(|sp| sp / 8 * 8 >= 16);
+        if !fits {
+            return Err(InitError::ArgumentsDontFit {
+                len,
+                sp: vm.get(Reg::SP),
+            });
+        }
+        vm.set(Reg::SP, vm.get(Reg::SP) - (16 * args.len()) as i64);
+        let slice = vm.get(Reg::SP);
         for (i, arg) in args.iter().enumerate() {
-            vm.regs[SP] -= arg.len() as i64;
+            vm.set(Reg::SP, vm.get(Reg::SP) - arg.len() as i64);
+            let sp = vm.get(Reg::SP) as usize;
             for (j, c) in arg.bytes().enumerate() {
-                vm.memory[SP + j] = c;
+                vm.memory[sp + j] = c;
             }
-            *vm.memory.word_at_mut(slice as usize + 16 * i) = vm.regs[SP];
-            *vm.memory.word_at_mut(slice as usize + 16 * i + 8) = arg.len() as i64;
+            vm.memory.set_word_at(slice as usize + 16 * i, sp as i64);
+            vm.memory
+                .set_word_at(slice as usize + 16 * i + 8, arg.len() as i64);
         }
-        vm.regs[SP] = vm.regs[SP] / 8 * 8;
-        vm.regs[SP] -= 16;
-        *vm.memory.word_at_mut(SP) = slice;
-        *vm.memory.word_at_mut(SP + 8) = args.len() as i64;
+        vm.set(Reg::SP, vm.get(Reg::SP) / 8 * 8);
+        vm.set(Reg::SP, vm.get(Reg::SP) - 16);
+        let sp = vm.get(Reg::SP) as usize;
+        vm.memory.set_word_at(sp, slice);
+        vm.memory.set_word_at(sp + 8, args.len() as i64);
+        vm.lowest_sp = vm.get(Reg::SP);
 
-        vm
+        Ok(vm)
     }
 }
 
 impl Vm {
-    fn find_label(&self, pos: usize) -> Option<(usize, &str)> {
-        for (label_pos, label) in self.labels.iter().rev() {
-            if *label_pos <= pos {
-                return Some((pos, label));
+    // Limits the stack to the given number of bytes at the end of the memory.
+    // By default, it may grow down to the end of the initial memory.
+    pub fn set_stack_size(&mut self, size: usize) {
+        self.stack_limit = self.memory.len().saturating_sub(size);
+    }
+
+    // A snapshot of the registers, for example to inspect them after the
+    // program ran or in a pre-step hook.
+    pub fn registers(&self) -> Registers {
+        self.regs
+    }
+
+    // Like Binary::label_for.
+    fn find_label(&self, pos: usize) -> Option<&str> {
+        self.labels
+            .iter()
+            .filter(|(label_pos, _)| *label_pos <= pos)
+            .max_by_key(|(label_pos, _)| *label_pos)
+            .map(|(_, label)| label.as_str())
+    }
+
+    // Parses the whole byte code instead of the slice starting at the
+    // offset, so that relative targets resolve to the right offsets.
+    fn instruction_at(&self, offset: usize) -> Option<Instruction> {
+        let mut parser = self.byte_code.byte_code();
+        parser.cursor = offset;
+        parser.next_instruction()?.ok()
+    }
+
+    fn find_source_location(&self, pos: usize) -> Option<&SourceLocation> {
+        for (location_pos, location) in self.source_map.iter().rev() {
+            if *location_pos <= pos {
+                return Some(location);
             }
         }
         None
     }
 
-    fn print_stack_entry(&self, pos: usize) {
-        println!(
-            "{:8x} {}",
-            pos,
-            self.find_label(pos).map_or("(no label)", |it| it.1)
-        );
+    fn trap_info<'a>(&'a self, reason: &'a str) -> TrapInfo<'a> {
+        let frame = |offset: usize| Frame {
+            offset,
+            label: self.find_label(offset),
+            location: self.find_source_location(offset),
+        };
+        let mut backtrace: Vec<Frame> = self.call_stack.iter().map(|entry| frame(*entry)).collect();
+        backtrace.push(frame(self.ip));
+        TrapInfo {
+            reason,
+            ip: self.ip,
+            registers: self.regs,
+            backtrace,
+        }
+    }
+
+    fn format_panic(&self, reason: &str) -> String {
+        let info = self.trap_info(reason);
+        match &self.panic_formatter {
+            Some(formatter) => format!("{}\n", (formatter.0)(&info)),
+            None => default_panic_format(&info),
+        }
     }
 
-    fn dump_and_panic(&self, msg: &str) {
-        println!("{msg}");
-        println!("Stack:");
-        for entry in &self.call_stack {
-            self.print_stack_entry(*entry);
-        }
-        self.print_stack_entry(self.ip);
-        println!("");
-        println!("Registers:");
-        println!("ip = {:8} {:8x}", self.regs[SP], self.regs[SP]);
-        println!("st = {:8} {:8x}", self.regs[ST], self.regs[ST]);
-        println!("a  = {:8} {:8x}", self.regs[REGA], self.regs[REGA]);
-        println!("b  = {:8} {:8x}", self.regs[REGB], self.regs[REGB]);
-        println!("c  = {:8} {:8x}", self.regs[REGC], self.regs[REGC]);
-        println!("d  = {:8} {:8x}", self.regs[REGD], self.regs[REGD]);
-        println!("e  = {:8} {:8x}", self.regs[REGE], self.regs[REGE]);
-        println!("f  = {:8} {:8x}", self.regs[REGF], self.regs[REGF]);
-        println!("");
+    // Uses the given function instead of the default format when printing
+    // panics.
+    pub fn set_panic_formatter(&mut self, formatter: Box<dyn Fn(&TrapInfo) -> String>) {
+        self.panic_formatter = Some(PanicFormatter(formatter));
+    }
+
+    // Calls the hook before each instruction. Useful for building
+    // instrumentation such as coverage or custom debuggers.
+    pub fn set_pre_step_hook(&mut self, hook: Box<PreStepHookFn>) {
+        self.pre_step_hook = Some(PreStepHook(hook));
+    }
+
+    // Writes the memory to the file given to dump_memory_on_exit, if any.
+    pub fn dump_memory_on_exit(&mut self, path: impl Into<PathBuf>) {
+        self.memory_dump_path = Some(path.into());
+    }
+    fn dump_memory(&self) {
+        if let Some(path) = &self.memory_dump_path {
+            if let Err(error) = fs::write(path, &self.memory) {
+                eprintln!("couldn't dump the memory to {}: {}", path.display(), error);
+            }
+        }
+    }
+
+    fn dump_and_panic(&mut self, msg: &str) {
+        self.dump_memory();
+        self.report_profile();
+        self.report_memory();
+        if self.events.is_some() || self.return_on_halt {
+            if let Some(events) = &self.events {
+                let _ = events.send(VmEvent::Panic(msg.to_string()));
+            }
+            let backtrace = self.call_stack.iter().copied().chain([self.ip]).collect();
+            self.termination = Some(Termination::Panicked {
+                reason: msg.to_string(),
+                backtrace,
+            });
+            self.halted = true;
+            return;
+        }
+        print!("{}", self.format_panic(msg));
         fs::write("crash", &self.memory).unwrap();
         println!("Memory dumped to crash.");
-        exit(1);
+        exit(PANIC_EXIT_STATUS);
     }
 
+    // Reads the registers directly, so that dumping them doesn't count as a
+    // read of uninitialized registers.
     fn dump_reg(&self) {
-        println!(
+        eprintln!(
             "sp = {:x}, st = {:x}, a = {:x}, b = {:x}, c = {:x}, d = {:x}, e = {:x}, f = {:x}",
-            self.regs[0],
-            self.regs[1],
-            self.regs[2],
-            self.regs[3],
-            self.regs[4],
-            self.regs[5],
-            self.regs[6],
-            self.regs[7]
+            self.regs.get(Reg::SP),
+            self.regs.get(Reg::ST),
+            self.regs.get(Reg::A),
+            self.regs.get(Reg::B),
+            self.regs.get(Reg::C),
+            self.regs.get(Reg::D),
+            self.regs.get(Reg::E),
+            self.regs.get(Reg::F)
         );
     }
 
-    fn eat_byte(&mut self) -> u8 {
-        let byte = self.byte_code[self.ip];
-        self.ip += 1;
-        byte
+    pub fn get(&self, reg: Reg) -> i64 {
+        if self.check_uninitialized_reads {
+            let initialized = self.initialized_registers.last().copied().unwrap_or(0xff);
+            if initialized & (1 << reg.index()) == 0 && self.uninitialized_read.get().is_none() {
+                self.uninitialized_read.set(Some(reg));
+            }
+        }
+        self.regs.get(reg)
     }
-    fn eat_word(&mut self) -> i64 {
-        let word = self.byte_code.word_at(self.ip);
-        self.ip += 8;
-        word
+    pub fn set(&mut self, reg: Reg, value: i64) {
+        if self.check_uninitialized_reads {
+            if let Some(initialized) = self.initialized_registers.last_mut() {
+                *initialized |= 1 << reg.index();
+            }
+        }
+        self.regs.set(reg, value);
     }
-    fn eat_reg(&mut self) -> usize {
-        let byte = self.eat_byte();
-        (byte & 0x0f) as usize
+
+    fn run_single(&mut self) {
+        let offset = self.ip;
+        let decoded = self.decoded[offset];
+        self.ip = offset + decoded.size;
+        (decoded.handler)(self, &decoded, offset);
     }
-    fn eat_regs(&mut self) -> (usize, usize) {
-        let byte = self.eat_byte();
-        ((byte & 0x0f) as usize, (byte >> 4 & 0x0f) as usize)
+
+    // The handler of instructions that didn't run yet. It decodes the
+    // instruction, remembers it for the next time, and runs it.
+    fn decode(&mut self, _: &Decoded, offset: usize) {
+        // Parses the whole byte code so that relative targets are resolved to
+        // offsets in it.
+        let mut parser = self.byte_code.byte_code();
+        parser.cursor = offset;
+        let decoded = match parser.next_instruction() {
+            Some(Ok(instruction)) => Decoded::new(instruction, parser.cursor - offset),
+            Some(Err(error)) => {
+                self.dump_and_panic(&error.to_string());
+                return;
+            }
+            None => unreachable!("run checks that ip is in the byte code"),
+        };
+        self.decoded[offset] = decoded;
+        self.ip = offset + decoded.size;
+        (decoded.handler)(self, &decoded, offset);
     }
 
-    fn run_single(&mut self) {
-        let opcode: u8 = self.eat_byte();
-        // println!("ip {:x} has opcode {:x}\n", self.ip, opcode);
-        match opcode {
-            0x00 => {}                               // nop
-            0xe0 => self.dump_and_panic("panicked"), // panic
-            0xd0 => {
-                // move
-                let (a, b) = self.eat_regs();
-                self.regs[a] = self.regs[b];
-            }
-            0xd1 => {
-                // movei
-                let reg = self.eat_reg();
-                let value = self.eat_word();
-                self.regs[reg] = value;
-            }
-            0xd2 => {
-                // moveib
-                let reg = self.eat_reg();
-                let value = self.eat_byte();
-                self.regs[reg] = value as i64;
-            }
-            0xd3 => {
-                // load
-                let (a, b) = self.eat_regs();
-                if b > MEMORY_SIZE - 8 {
-                    self.dump_and_panic("segmentation fault");
-                }
-                self.regs[a] = self.memory.word_at(self.regs[b] as usize);
+    // Panics unless size bytes starting at the address fit into the memory.
+    fn check_access(&mut self, offset: usize, address: i64, size: usize) -> bool {
+        let in_memory = address >= 0
+            && (address as usize)
+                .checked_add(size)
+                .is_some_and(|end| end <= self.memory.len());
+        if !in_memory {
+            self.dump_and_panic(&format!(
+                "segmentation fault: the instruction at {:x} accesses address {:x}, which is outside of the memory",
+                offset, address
+            ));
+            return false;
+        }
+        true
+    }
+
+    fn set_arithmetic_result(&mut self, reg: Reg, result: i64, overflowed: bool) {
+        if overflowed && self.arithmetic_mode == ArithmeticMode::Trapping {
+            self.dump_and_panic("arithmetic overflow");
+            return;
+        }
+        self.set(reg, result);
+    }
+
+    fn jump(&mut self, target: usize) {
+        let is_back_edge = target < self.ip;
+        self.ip = target;
+        if is_back_edge {
+            self.check_signals();
+        }
+    }
+
+    fn trace_call(&self, target: usize) {
+        if !TRACE_CALLS {
+            return;
+        }
+        for _ in 0..self.call_stack.len() {
+            print!(" ");
+        }
+        let label = self.find_label(target).unwrap_or("(no label)");
+        print!("{}", label);
+        for _ in (self.call_stack.len() + label.len())..50 {
+            print!(" ");
+        }
+        for i in
+            (self.get(Reg::SP) as usize)..min(self.memory.len(), self.get(Reg::SP) as usize + 40)
+        {
+            if i % 8 == 0 {
+                print!(" |");
             }
-            0xd4 => {
-                // loadb
-                let (a, b) = self.eat_regs();
-                if b > MEMORY_SIZE - 1 {
-                    self.dump_and_panic("segmentation fault");
-                }
-                self.regs[a] = self.memory[self.regs[b] as usize] as i64;
+            print!(" {:02x}", self.memory[i]);
+        }
+        println!();
+    }
+
+    fn call(&mut self, target: usize) {
+        self.call_stack.push(self.ip);
+        self.initialized_registers.push(1 << Reg::SP.index());
+        self.ip = target;
+    }
+
+    fn check_signals(&mut self) {
+        let Some(handler) = self.signal_handler else {
+            return;
+        };
+        if self.signal_handler_depth.is_none() && self.signals.are_pending() {
+            self.signal_handler_depth = Some(self.call_stack.len());
+            self.call(handler);
+        }
+    }
+
+    // Counts how often each instruction runs and prints the counts when the
+    // program ends.
+    pub fn profile(&mut self) {
+        self.profile = Some(HashMap::new());
+    }
+    fn report_profile(&self) {
+        let profile = match &self.profile {
+            Some(profile) => profile,
+            None => return,
+        };
+        let mut counts: Vec<_> = profile
+            .iter()
+            .map(|(offset, count)| (*offset, *count))
+            .collect();
+        counts.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        eprintln!(
+            "{:>12} {:>8}  {:24} label",
+            "count", "offset", "instruction"
+        );
+        for (offset, count) in &counts {
+            let instruction = match self.instruction_at(*offset) {
+                Some(instruction) => instruction.to_string(),
+                None => "?".to_string(),
+            };
+            let label = self.find_label(*offset).unwrap_or("(no label)");
+            eprintln!("{:>12} {:>8x}  {:24} {}", count, offset, instruction, label);
+        }
+        let total: u64 = counts.iter().map(|(_, count)| count).sum();
+        eprintln!("{:>12} in total", total);
+    }
+
+    // Tracks the lowest the stack pointer gets and prints how much of the
+    // stack the program used when it ends.
+    pub fn track_memory(&mut self) {
+        self.track_memory = true;
+    }
+    fn report_memory(&self) {
+        if self.track_memory {
+            eprintln!("peak stack: {} bytes", self.peak_stack());
+        }
+    }
+    fn peak_stack(&self) -> i64 {
+        self.memory.len() as i64 - self.lowest_sp
+    }
+
+    // Panics when a function reads a register before writing it. When the
+    // program starts, all registers count as written. When a function is
+    // entered, only sp does.
+    pub fn check_uninitialized_reads(&mut self) {
+        self.check_uninitialized_reads = true;
+    }
+
+    // Prints each executed instruction to stderr, starting at the given offset
+    // or right away.
+    pub fn trace(&mut self, from: Option<usize>) {
+        match from {
+            Some(offset) => self.trace_from = Some(offset),
+            None => self.tracing = true,
+        }
+    }
+
+    fn trace_step(&self, ip: usize, before: Registers) {
+        let mut line = match self.instruction_at(ip) {
+            Some(instruction) => format!("{:8x} | {}", ip, instruction),
+            None => format!("{:8x} | ?", ip),
+        };
+        for reg in Reg::ALL {
+            if self.regs.get(reg) != before.get(reg) {
+                line.push_str(&format!(", {} = {}", reg, self.regs.get(reg)));
             }
-            0xd5 => {
-                // store
-                let (a, b) = self.eat_regs();
-                if a > MEMORY_SIZE - 8 {
-                    self.dump_and_panic("segmentation fault");
-                }
-                *self.memory.word_at_mut(self.regs[a] as usize) = self.regs[b];
+        }
+        eprintln!("{}", line);
+    }
+
+    // A minimal debugger, shown after a breakpoint instruction. It reads
+    // commands from the terminal because stdin usually contains the binary:
+    // - s: run the next instruction and come back here
+    // - c: continue until the next breakpoint
+    // - x addr len: print len bytes of memory starting at addr (both hex)
+    fn debug_prompt(&mut self) {
+        let mut input: Box<dyn BufRead> = match fs::File::open("/dev/tty") {
+            Ok(tty) => Box::new(BufReader::new(tty)),
+            Err(_) => Box::new(BufReader::new(io::stdin())),
+        };
+        self.debug_prompt_with(&mut input);
+    }
+
+    fn debug_prompt_with(&mut self, input: &mut dyn BufRead) {
+        // A breakpoint can be the last instruction, so ip can be at the end.
+        match self.instruction_at(self.ip) {
+            Some(instruction) => eprintln!("{:x} | {}", self.ip, instruction),
+            None => eprintln!("{:x} | ?", self.ip),
+        }
+        self.dump_reg();
+        loop {
+            eprint!("(debug) ");
+            let mut line = String::new();
+            if input.read_line(&mut line).unwrap_or(0) == 0 {
+                // No more input, so just run the program.
+                self.stepping = false;
+                return;
             }
-            0xd6 => {
-                // storeb
-                let (a, b) = self.eat_regs();
-                if a > MEMORY_SIZE - 1 {
-                    self.dump_and_panic("segmentation fault");
+            let words: Vec<_> = line.split_whitespace().collect();
+            match words.as_slice() {
+                ["s"] => return,
+                ["c"] => {
+                    self.stepping = false;
+                    return;
                 }
-                self.memory[self.regs[a] as usize] = self.regs[b] as u8;
-            }
-            0xd7 => {
-                // push
-                let reg = self.eat_reg();
-                self.regs[SP] -= 8;
-                *self.memory.word_at_mut(self.regs[SP] as usize) = self.regs[reg];
-            }
-            0xd8 => {
-                // pop
-                let reg = self.eat_reg();
-                self.regs[reg] = self.memory.word_at(self.regs[SP] as usize);
-                self.regs[SP] += 8;
-            }
-            0xf0 => self.ip = self.eat_word() as usize, // jump
-            0xf1 => {
-                // cjump
-                let target = self.eat_word() as usize;
-                if self.regs[ST] != 0 {
-                    // println!("jumping because it's {}", self.regs[ST]);
-                    self.ip = target;
-                }
-            }
-            0xf2 => {
-                // call
-                let target = self.eat_word() as usize;
-                if TRACE_CALLS {
-                    for _ in 0..self.call_stack.len() {
-                        print!(" ");
-                    }
-                    let label = self.find_label(target).map_or("(no label)", |it| it.1);
-                    print!("{}", label);
-                    for _ in (self.call_stack.len() + label.len())..50 {
-                        print!(" ");
+                ["x", addr, len] => {
+                    let (Ok(addr), Ok(len)) = (
+                        usize::from_str_radix(addr, 16),
+                        usize::from_str_radix(len, 16),
+                    ) else {
+                        eprintln!("usage: x <addr> <len>, both in hex");
+                        continue;
+                    };
+                    let end = addr.saturating_add(len).min(self.memory.len());
+                    for (i, chunk) in self.memory[addr.min(end)..end].chunks(16).enumerate() {
+                        let bytes: Vec<_> =
+                            chunk.iter().map(|byte| format!("{:02x}", byte)).collect();
+                        eprintln!("{:8x} | {}", addr + 16 * i, bytes.join(" "));
                     }
-                    for i in (self.regs[SP] as usize)..min(MEMORY_SIZE, self.regs[SP] as usize + 40)
-                    {
-                        if i % 8 == 0 {
-                            print!(" |");
-                        }
-                        print!(" {:02x}", self.memory[i]);
-                    }
-                    println!();
                 }
-                self.call_stack.push(self.ip);
-                self.ip = target;
-            }
-            0xf3 => {
-                // ret
-                let target = self.call_stack.pop().unwrap();
-                self.ip = target;
-            }
-            0xf4 => {
-                // syscall
-                let number = self.eat_byte();
-                self.syscall(number);
-            }
-            0xc0 => {
-                // cmp
-                let (a, b) = self.eat_regs();
-                self.regs[ST] = self.regs[a] - self.regs[b];
-            }
-            0xc1 => self.regs[ST] = i64::from(self.regs[ST] == 0), // isequal
-            0xc2 => self.regs[ST] = i64::from(self.regs[ST] < 0),  // isless
-            0xc3 => self.regs[ST] = i64::from(self.regs[ST] > 0),  // isgreater
-            0xc4 => self.regs[ST] = i64::from(self.regs[ST] <= 0), // islessequal
-            0xc5 => self.regs[ST] = i64::from(self.regs[ST] >= 0), // isgreaterequal
-            0xa0 => {
-                // add
-                let (a, b) = self.eat_regs();
-                self.regs[a] += self.regs[b];
-            }
-            0xa1 => {
-                // sub
-                let (a, b) = self.eat_regs();
-                self.regs[a] -= self.regs[b];
-            }
-            0xa2 => {
-                // mul
-                let (a, b) = self.eat_regs();
-                self.regs[a] *= self.regs[b];
-            }
-            0xa3 => {
-                // div
-                let (a, b) = self.eat_regs();
-                self.regs[a] /= self.regs[b];
-            }
-            0xa4 => {
-                // rem
-                let (a, b) = self.eat_regs();
-                self.regs[a] %= self.regs[b];
-            }
-            0xb0 => {
-                // and
-                let (a, b) = self.eat_regs();
-                self.regs[a] &= self.regs[b];
-            }
-            0xb1 => {
-                // or
-                let (a, b) = self.eat_regs();
-                self.regs[a] |= self.regs[b];
-            }
-            0xb2 => {
-                // xor
-                let (a, b) = self.eat_regs();
-                self.regs[a] ^= self.regs[b];
-            }
-            0xb3 => {
-                // xor
-                let reg = self.eat_reg();
-                self.regs[reg] = !self.regs[reg];
-            }
-            _ => self.dump_and_panic("invalid instruction"),
+                _ => eprintln!("commands: s (step), c (continue), x <addr> <len> (dump memory)"),
+            }
         }
     }
 
+    // Returns a handle that can be used to raise signals while the VM runs.
+    pub fn signals(&self) -> Signals {
+        self.signals.clone()
+    }
+
+    // Calls the function at the given offset on the next backwards jump while
+    // signals are pending. The handler should use the poll_signals syscall to
+    // read and clear them. It's not called again until it returns.
+    pub fn set_signal_handler(&mut self, offset: usize) {
+        self.signal_handler = Some(offset);
+    }
+
     pub fn run(&mut self) {
-        loop {
+        while !self.halted {
+            if let Some(fuel) = &mut self.fuel {
+                if *fuel == 0 {
+                    return;
+                }
+                *fuel -= 1;
+            }
             // self.dump_reg();
             // printf("Memory:");
             // for (int i = 0x18650; i < MEMORY_SIZE; i++)
             //   printf("%c%02x", i == SP ? '|' : ' ', mem[i]);
             // printf("\n");
+            let ip = self.ip;
+            if self.stepping {
+                self.debug_prompt();
+            }
+            if ip >= self.byte_code.len() {
+                self.dump_and_panic(&format!(
+                    "ip {:x} is outside of the byte code, which ends at {:x}",
+                    ip,
+                    self.byte_code.len()
+                ));
+                continue;
+            }
+            if let Some(mut hook) = self.pre_step_hook.take() {
+                let mut parser = self.byte_code.byte_code();
+                parser.cursor = ip;
+                let action = match parser.next_instruction() {
+                    Some(Ok(instruction)) => (hook.0)(ip, &instruction, self),
+                    Some(Err(error)) => HookAction::Trap(error.to_string()),
+                    None => unreachable!(),
+                };
+                self.pre_step_hook = Some(hook);
+                match action {
+                    HookAction::Continue => {}
+                    HookAction::Skip => {
+                        self.ip = parser.cursor;
+                        continue;
+                    }
+                    HookAction::Trap(reason) => {
+                        self.dump_and_panic(&reason);
+                        continue;
+                    }
+                }
+            }
+            if let Some(start) = self.trace_from {
+                if ip == start {
+                    self.tracing = true;
+                    self.trace_from = None;
+                }
+            }
+            if let Some(profile) = &mut self.profile {
+                *profile.entry(ip).or_insert(0) += 1;
+            }
+            let registers_before = if self.tracing { Some(self.regs) } else { None };
             self.run_single();
+            if let Some(before) = registers_before {
+                self.trace_step(ip, before);
+            }
+            if self.check_uninitialized_reads {
+                if let Some(reg) = self.uninitialized_read.take() {
+                    self.dump_and_panic(&format!(
+                        "read of uninitialized register {} at {:x}",
+                        reg, ip
+                    ));
+                }
+            }
+            if self.track_memory {
+                self.lowest_sp = min(self.lowest_sp, self.get(Reg::SP));
+            }
+        }
+    }
+
+    // Runs the program to its end and returns how it ended instead of exiting
+    // the process. Syscalls still print to stdout and stderr.
+    pub fn execute(&mut self) -> Termination {
+        self.return_on_halt = true;
+        self.run();
+        self.termination
+            .take()
+            .expect("the VM halted without terminating")
+    }
+
+    // Like run, but runs at most max_instructions instructions. This bounds
+    // runaway loops of untrusted programs.
+    pub fn run_with_fuel(&mut self, max_instructions: u64) -> RunOutcome {
+        self.fuel = Some(max_instructions);
+        self.run();
+        let out_of_fuel = self.fuel == Some(0) && !self.halted;
+        self.fuel = None;
+        if out_of_fuel {
+            // The program didn't exit, so nothing reported the profile yet.
+            self.report_profile();
+            self.report_memory();
+            RunOutcome::OutOfFuel
+        } else {
+            RunOutcome::Halted
         }
     }
 
+    // Makes the syscall with the given number call the handler. For example,
+    // this adds a syscall that reads a line from stdin into the buffer
+    // described by a and b, and sets a to the number of bytes read:
+    //
+    // ```
+    // vm.register_syscall(16, Box::new(|memory, registers| {
+    //     let mut line = String::new();
+    //     std::io::stdin().read_line(&mut line).unwrap();
+    //     let start = registers.a as usize;
+    //     let len = line.len().min(registers.b as usize);
+    //     memory[start..start + len].copy_from_slice(&line.as_bytes()[..len]);
+    //     registers.a = len as i64;
+    //     SyscallOutcome::Continue
+    // }));
+    // ```
+    pub fn register_syscall(&mut self, number: u8, handler: Box<SyscallHandlerFn>) {
+        self.custom_syscalls.insert(number, SyscallHandler(handler));
+    }
+
     fn syscall(&mut self, number: u8) {
+        if let Some(mut handler) = self.custom_syscalls.remove(&number) {
+            let outcome = (handler.0)(&mut self.memory, &mut self.regs);
+            self.custom_syscalls.insert(number, handler);
+            match outcome {
+                SyscallOutcome::Continue => {}
+                SyscallOutcome::Exit(status) => self.exit_with(status as i64),
+                SyscallOutcome::Panic => {
+                    self.dump_and_panic(&format!("syscall {} panicked", number))
+                }
+            }
+            return;
+        }
         match number {
             0 => self.syscall_exit(),
             1 => self.syscall_print(),
@@ -325,64 +1005,1289 @@ impl Vm {
             6 => self.syscall_read(),
             7 => self.syscall_write(),
             8 => self.syscall_close(),
+            9 => self.syscall_argc(),
+            10 => self.syscall_arg(),
+            15 => self.syscall_poll_signals(),
+            16 => self.syscall_instant(),
             _ => self.dump_and_panic("invalid syscall number"),
         }
     }
 
-    fn syscall_exit(&self) {
-        println!("exiting with status {}", self.regs[REGA]);
-        exit(self.regs[REGA] as i32);
+    // Runs the VM, streaming the effects of syscalls to the given sender as
+    // they happen. Returns once the program exits or panics.
+    pub fn run_streaming(&mut self, events: Sender<VmEvent>) {
+        self.events = Some(events);
+        self.run();
     }
 
-    fn syscall_print(&self) {
-        for i in 0..self.regs[REGB] {
-            print!("{}", self.memory[(self.regs[REGA] + i) as usize] as char);
+    fn syscall_exit(&mut self) {
+        self.exit_with(self.get(Reg::A));
+    }
+
+    fn exit_with(&mut self, status: i64) {
+        self.dump_memory();
+        self.report_profile();
+        self.report_memory();
+        if self.events.is_some() || self.return_on_halt {
+            if let Some(events) = &self.events {
+                let _ = events.send(VmEvent::Exit(status));
+            }
+            self.termination = Some(Termination::Exited(status));
+            self.halted = true;
+            return;
         }
+        println!("exiting with status {}", status);
+        exit(status as i32);
     }
 
-    fn syscall_log(&self) {
-        for i in 0..self.regs[REGB] {
-            eprint!("{}", self.memory[(self.regs[REGA] + i) as usize] as char);
+    fn syscall_print(&mut self) {
+        let Some(range) = self.message() else {
+            return;
+        };
+        if let Some(events) = &self.events {
+            let _ = events.send(VmEvent::Print(self.memory[range].to_vec()));
+            return;
+        }
+        for byte in &self.memory[range] {
+            print!("{}", *byte as char);
+        }
+    }
+
+    fn syscall_log(&mut self) {
+        let Some(range) = self.message() else {
+            return;
+        };
+        if let Some(events) = &self.events {
+            let _ = events.send(VmEvent::Log(self.memory[range].to_vec()));
+            return;
+        }
+        for byte in &self.memory[range] {
+            eprint!("{}", *byte as char);
         }
     }
 
-    fn syscall_create(&self) {
-        todo!("create file")
-        // let filename = str::from_raw_parts(self.memory[self.regs[2] as usize], self.regs[3], self.regs[3]);
-        // let file = File::create(filename);
+    // The memory range of the message in a and b. Panics if it's not inside
+    // the memory.
+    fn message(&mut self) -> Option<Range<usize>> {
+        let (start, len) = (self.get(Reg::A), self.get(Reg::B));
+        if start < 0 || len < 0 || start.saturating_add(len) as usize > self.memory.len() {
+            self.dump_and_panic(&format!(
+                "segmentation fault: the message at {:x} with length {} is outside of the memory",
+                start, len
+            ));
+            return None;
+        }
+        Some(start as usize..(start + len) as usize)
+    }
+
+    // Like the C VM, creating a file and opening it for writing both open it
+    // for reading and writing, creating or truncating it.
+    fn syscall_create(&mut self) {
+        self.open(
+            fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(true),
+        );
+    }
+
+    fn syscall_open_reading(&mut self) {
+        self.open(fs::OpenOptions::new().read(true));
+    }
+
+    fn syscall_open_writing(&mut self) {
+        self.syscall_create();
+    }
+
+    // Opens the file named by the string in a and b and sets a to its file
+    // descriptor, or to zero if it didn't work.
+    fn open(&mut self, options: &fs::OpenOptions) {
+        let Some(range) = self.message() else {
+            return;
+        };
+        let file = std::str::from_utf8(&self.memory[range])
+            .ok()
+            .and_then(|name| options.open(name).ok());
+        let fd = match file {
+            Some(file) => {
+                // 0 to 2 are the standard streams.
+                let fd = (3..).find(|fd| !self.files.contains_key(fd)).unwrap();
+                self.files.insert(fd, file);
+                fd
+            }
+            None => 0,
+        };
+        self.set(Reg::A, fd);
+    }
+
+    fn syscall_read(&mut self) {
+        let (fd, start, len) = (self.get(Reg::A), self.get(Reg::B), self.get(Reg::C));
+        let Some(buffer) = (start as usize)
+            .checked_add(len as usize)
+            .and_then(|end| self.memory.get_mut(start as usize..end))
+        else {
+            self.dump_and_panic("segmentation fault");
+            return;
+        };
+        let read = match self.files.get_mut(&fd) {
+            Some(file) => file.read(buffer).unwrap_or(0),
+            None => 0,
+        };
+        self.set(Reg::A, read as i64);
+    }
+
+    // Writes raw bytes, so programs can also use this to dump parts of their
+    // memory to stdout or stderr. Writing to a file descriptor that isn't open
+    // writes nothing.
+    fn syscall_write(&mut self) {
+        let (fd, start, len) = (self.get(Reg::A), self.get(Reg::B), self.get(Reg::C));
+        let Some(bytes) = (start as usize)
+            .checked_add(len as usize)
+            .and_then(|end| self.memory.get(start as usize..end))
+        else {
+            self.dump_and_panic("segmentation fault");
+            return;
+        };
+        let written = match (&self.events, fd) {
+            (Some(events), 1) => {
+                let _ = events.send(VmEvent::Print(bytes.to_vec()));
+                bytes.len()
+            }
+            (Some(events), 2) => {
+                let _ = events.send(VmEvent::Log(bytes.to_vec()));
+                bytes.len()
+            }
+            (None, 1) => io::stdout().write(bytes).unwrap_or(0),
+            (None, 2) => io::stderr().write(bytes).unwrap_or(0),
+            _ => match self.files.get_mut(&fd) {
+                Some(file) => file.write(bytes).unwrap_or(0),
+                None => 0,
+            },
+        };
+        self.set(Reg::A, written as i64);
+    }
+
+    fn syscall_close(&mut self) {
+        let closed = self.files.remove(&self.get(Reg::A)).is_some();
+        self.set(Reg::A, closed as i64);
+    }
+
+    fn syscall_argc(&mut self) {
+        self.set(Reg::A, self.args.len() as i64);
+    }
+
+    fn syscall_arg(&mut self) {
+        let index = self.get(Reg::A);
+        if index < 0 || index as usize >= self.args.len() {
+            self.dump_and_panic("arg index out of bounds");
+            return;
+        }
+        let arg = self.args[index as usize].as_bytes();
+        let written = arg.len().min(self.get(Reg::C).max(0) as usize);
+        let start = self.get(Reg::B) as usize;
+        match self.memory.get_mut(start..start.saturating_add(written)) {
+            Some(buffer) => buffer.copy_from_slice(&arg[..written]),
+            None => {
+                self.dump_and_panic("segmentation fault");
+                return;
+            }
+        }
+        self.set(Reg::A, written as i64);
+    }
+
+    fn syscall_poll_signals(&mut self) {
+        self.set(Reg::A, self.signals.take() as i64);
+    }
+
+    fn syscall_instant(&mut self) {
+        let epoch = *self.clock_epoch.get_or_insert_with(Instant::now);
+        self.set(Reg::A, epoch.elapsed().as_nanos() as i64);
+    }
+}
+
+// The handlers of the decoded instructions. They run with ip already pointing
+// to the next instruction and get the offset of their own instruction.
+mod handlers {
+    use super::*;
+
+    pub fn nop(_: &mut Vm, _: &Decoded, _: usize) {}
+    pub fn panic(vm: &mut Vm, _: &Decoded, _: usize) {
+        vm.dump_and_panic("panicked");
+    }
+    // Ignored when the VM is embedded, because there's no terminal to debug
+    // in.
+    pub fn breakpoint(vm: &mut Vm, _: &Decoded, _: usize) {
+        vm.stepping = vm.events.is_none() && !vm.return_on_halt;
+    }
+    pub fn move_(vm: &mut Vm, op: &Decoded, _: usize) {
+        vm.set(op.a, vm.get(op.b));
+    }
+    // Also used for moveib and moveisb, which are extended when decoding.
+    pub fn movei(vm: &mut Vm, op: &Decoded, _: usize) {
+        vm.set(op.a, op.value);
+    }
+    pub fn swap(vm: &mut Vm, op: &Decoded, _: usize) {
+        let (value_a, value_b) = (vm.get(op.a), vm.get(op.b));
+        vm.set(op.a, value_b);
+        vm.set(op.b, value_a);
+    }
+    pub fn movz(vm: &mut Vm, op: &Decoded, _: usize) {
+        let choices = [vm.get(op.a), vm.get(op.b)];
+        vm.set(op.a, choices[(vm.get(Reg::ST) == 0) as usize]);
+    }
+    pub fn movnz(vm: &mut Vm, op: &Decoded, _: usize) {
+        let choices = [vm.get(op.a), vm.get(op.b)];
+        vm.set(op.a, choices[(vm.get(Reg::ST) != 0) as usize]);
+    }
+    pub fn load(vm: &mut Vm, op: &Decoded, offset: usize) {
+        if !vm.check_access(offset, vm.get(op.b), 8) {
+            return;
+        }
+        vm.set(op.a, vm.memory.word_at(vm.get(op.b) as usize));
+    }
+    pub fn loadb(vm: &mut Vm, op: &Decoded, offset: usize) {
+        if !vm.check_access(offset, vm.get(op.b), 1) {
+            return;
+        }
+        vm.set(op.a, vm.memory[vm.get(op.b) as usize] as i64);
+    }
+    pub fn loadh(vm: &mut Vm, op: &Decoded, offset: usize) {
+        if !vm.check_access(offset, vm.get(op.b), 2) {
+            return;
+        }
+        let address = vm.get(op.b) as usize;
+        let bytes = vm.memory[address..address + 2].try_into().unwrap();
+        vm.set(op.a, u16::from_le_bytes(bytes) as i64);
+    }
+    pub fn loadw(vm: &mut Vm, op: &Decoded, offset: usize) {
+        if !vm.check_access(offset, vm.get(op.b), 4) {
+            return;
+        }
+        let address = vm.get(op.b) as usize;
+        let bytes = vm.memory[address..address + 4].try_into().unwrap();
+        vm.set(op.a, u32::from_le_bytes(bytes) as i64);
+    }
+    pub fn store(vm: &mut Vm, op: &Decoded, offset: usize) {
+        if !vm.check_access(offset, vm.get(op.a), 8) {
+            return;
+        }
+        let (address, value) = (vm.get(op.a) as usize, vm.get(op.b));
+        vm.memory.set_word_at(address, value);
+    }
+    pub fn storeb(vm: &mut Vm, op: &Decoded, offset: usize) {
+        if !vm.check_access(offset, vm.get(op.a), 1) {
+            return;
+        }
+        let (address, value) = (vm.get(op.a) as usize, vm.get(op.b));
+        vm.memory[address] = value as u8;
+    }
+    pub fn storeh(vm: &mut Vm, op: &Decoded, offset: usize) {
+        if !vm.check_access(offset, vm.get(op.a), 2) {
+            return;
+        }
+        let (address, value) = (vm.get(op.a) as usize, vm.get(op.b));
+        vm.memory[address..address + 2].copy_from_slice(&(value as u16).to_le_bytes());
+    }
+    pub fn storew(vm: &mut Vm, op: &Decoded, offset: usize) {
+        if !vm.check_access(offset, vm.get(op.a), 4) {
+            return;
+        }
+        let (address, value) = (vm.get(op.a) as usize, vm.get(op.b));
+        vm.memory[address..address + 4].copy_from_slice(&(value as u32).to_le_bytes());
+    }
+    pub fn push(vm: &mut Vm, op: &Decoded, offset: usize) {
+        if vm.get(Reg::SP) < vm.stack_limit as i64 + 8 {
+            vm.dump_and_panic("stack overflow");
+            return;
+        }
+        if !vm.check_access(offset, vm.get(Reg::SP) - 8, 8) {
+            return;
+        }
+        vm.set(Reg::SP, vm.get(Reg::SP) - 8);
+        let (address, value) = (vm.get(Reg::SP) as usize, vm.get(op.a));
+        vm.memory.set_word_at(address, value);
+    }
+    pub fn pop(vm: &mut Vm, op: &Decoded, offset: usize) {
+        let sp = vm.get(Reg::SP);
+        if sp > vm.memory.len() as i64 - 8 {
+            vm.dump_and_panic(&format!(
+                "stack underflow at offset {:x}: popping at sp {:x}, but the memory ends at {:x}",
+                offset,
+                sp,
+                vm.memory.len()
+            ));
+            return;
+        }
+        if !vm.check_access(offset, sp, 8) {
+            return;
+        }
+        let value = vm.memory.word_at(vm.get(Reg::SP) as usize);
+        vm.set(Reg::SP, vm.get(Reg::SP) + 8);
+        vm.set(op.a, value);
+    }
+    // Also used for jumpr, cjumpr, and callr, whose targets are resolved
+    // when decoding.
+    pub fn jump(vm: &mut Vm, op: &Decoded, _: usize) {
+        vm.jump(op.value as usize);
+    }
+    pub fn cjump(vm: &mut Vm, op: &Decoded, _: usize) {
+        if vm.get(Reg::ST) != 0 {
+            vm.jump(op.value as usize);
+        }
+    }
+    pub fn call(vm: &mut Vm, op: &Decoded, _: usize) {
+        vm.trace_call(op.value as usize);
+        vm.call(op.value as usize);
+    }
+    pub fn ret(vm: &mut Vm, _: &Decoded, _: usize) {
+        let Some(target) = vm.call_stack.pop() else {
+            vm.dump_and_panic("return with empty call stack");
+            return;
+        };
+        vm.ip = target;
+        vm.initialized_registers.pop();
+        if vm.signal_handler_depth == Some(vm.call_stack.len()) {
+            vm.signal_handler_depth = None;
+        }
+    }
+    pub fn syscall(vm: &mut Vm, op: &Decoded, _: usize) {
+        vm.syscall(op.value as u8);
+    }
+    pub fn cmp(vm: &mut Vm, op: &Decoded, _: usize) {
+        vm.set(Reg::ST, vm.get(op.a).wrapping_sub(vm.get(op.b)));
+    }
+    pub fn cmpz(vm: &mut Vm, op: &Decoded, _: usize) {
+        vm.set(Reg::ST, vm.get(op.a));
+    }
+    pub fn isequal(vm: &mut Vm, _: &Decoded, _: usize) {
+        vm.set(Reg::ST, i64::from(vm.get(Reg::ST) == 0));
+    }
+    pub fn isless(vm: &mut Vm, _: &Decoded, _: usize) {
+        vm.set(Reg::ST, i64::from(vm.get(Reg::ST) < 0));
+    }
+    pub fn isgreater(vm: &mut Vm, _: &Decoded, _: usize) {
+        vm.set(Reg::ST, i64::from(vm.get(Reg::ST) > 0));
+    }
+    pub fn islessequal(vm: &mut Vm, _: &Decoded, _: usize) {
+        vm.set(Reg::ST, i64::from(vm.get(Reg::ST) <= 0));
+    }
+    pub fn isgreaterequal(vm: &mut Vm, _: &Decoded, _: usize) {
+        vm.set(Reg::ST, i64::from(vm.get(Reg::ST) >= 0));
+    }
+    pub fn inttofloat(vm: &mut Vm, op: &Decoded, _: usize) {
+        vm.set(op.a, (vm.get(op.a) as f64).to_bits() as i64);
+    }
+    // Saturates, and NaN becomes 0.
+    pub fn floattoint(vm: &mut Vm, op: &Decoded, _: usize) {
+        vm.set(op.a, f64::from_bits(vm.get(op.a) as u64) as i64);
+    }
+    pub fn add(vm: &mut Vm, op: &Decoded, _: usize) {
+        let (result, overflowed) = vm.get(op.a).overflowing_add(vm.get(op.b));
+        vm.set_arithmetic_result(op.a, result, overflowed);
+    }
+    pub fn sub(vm: &mut Vm, op: &Decoded, _: usize) {
+        let (result, overflowed) = vm.get(op.a).overflowing_sub(vm.get(op.b));
+        vm.set_arithmetic_result(op.a, result, overflowed);
+    }
+    pub fn mul(vm: &mut Vm, op: &Decoded, _: usize) {
+        let (result, overflowed) = vm.get(op.a).overflowing_mul(vm.get(op.b));
+        vm.set_arithmetic_result(op.a, result, overflowed);
+    }
+    // i64::MIN / -1 doesn't fit, so it wraps like the other arithmetic
+    // instructions.
+    pub fn div(vm: &mut Vm, op: &Decoded, _: usize) {
+        if vm.get(op.b) == 0 {
+            vm.dump_and_panic("division by zero");
+        } else {
+            let (result, overflowed) = vm.get(op.a).overflowing_div(vm.get(op.b));
+            vm.set_arithmetic_result(op.a, result, overflowed);
+        }
+    }
+    pub fn rem(vm: &mut Vm, op: &Decoded, _: usize) {
+        if vm.get(op.b) == 0 {
+            vm.dump_and_panic("division by zero");
+        } else {
+            vm.set(op.a, vm.get(op.a).wrapping_rem(vm.get(op.b)));
+        }
+    }
+    pub fn udiv(vm: &mut Vm, op: &Decoded, _: usize) {
+        if vm.get(op.b) == 0 {
+            vm.dump_and_panic("division by zero");
+        } else {
+            vm.set(op.a, (vm.get(op.a) as u64 / vm.get(op.b) as u64) as i64);
+        }
+    }
+    pub fn urem(vm: &mut Vm, op: &Decoded, _: usize) {
+        if vm.get(op.b) == 0 {
+            vm.dump_and_panic("division by zero");
+        } else {
+            vm.set(op.a, (vm.get(op.a) as u64 % vm.get(op.b) as u64) as i64);
+        }
+    }
+    pub fn addc(vm: &mut Vm, op: &Decoded, _: usize) {
+        let (result, overflowed) = vm.get(op.a).overflowing_add(vm.get(op.b));
+        vm.set(op.a, result);
+        vm.set(Reg::ST, i64::from(overflowed));
+    }
+    pub fn subc(vm: &mut Vm, op: &Decoded, _: usize) {
+        let (result, overflowed) = vm.get(op.a).overflowing_sub(vm.get(op.b));
+        vm.set(op.a, result);
+        vm.set(Reg::ST, i64::from(overflowed));
+    }
+    pub fn mulc(vm: &mut Vm, op: &Decoded, _: usize) {
+        let (result, overflowed) = vm.get(op.a).overflowing_mul(vm.get(op.b));
+        vm.set(op.a, result);
+        vm.set(Reg::ST, i64::from(overflowed));
+    }
+    pub fn and(vm: &mut Vm, op: &Decoded, _: usize) {
+        vm.set(op.a, vm.get(op.a) & vm.get(op.b));
+    }
+    pub fn or(vm: &mut Vm, op: &Decoded, _: usize) {
+        vm.set(op.a, vm.get(op.a) | vm.get(op.b));
+    }
+    pub fn xor(vm: &mut Vm, op: &Decoded, _: usize) {
+        vm.set(op.a, vm.get(op.a) ^ vm.get(op.b));
+    }
+    pub fn not(vm: &mut Vm, op: &Decoded, _: usize) {
+        vm.set(op.a, !vm.get(op.a));
+    }
+    pub fn shl(vm: &mut Vm, op: &Decoded, _: usize) {
+        vm.set(op.a, vm.get(op.a) << (vm.get(op.b) & 63));
+    }
+    pub fn shr(vm: &mut Vm, op: &Decoded, _: usize) {
+        vm.set(op.a, (vm.get(op.a) as u64 >> (vm.get(op.b) & 63)) as i64);
+    }
+    pub fn sar(vm: &mut Vm, op: &Decoded, _: usize) {
+        vm.set(op.a, vm.get(op.a) >> (vm.get(op.b) & 63));
+    }
+    pub fn rotl(vm: &mut Vm, op: &Decoded, _: usize) {
+        vm.set(
+            op.a,
+            (vm.get(op.a) as u64).rotate_left((vm.get(op.b) & 63) as u32) as i64,
+        );
+    }
+    pub fn rotr(vm: &mut Vm, op: &Decoded, _: usize) {
+        vm.set(
+            op.a,
+            (vm.get(op.a) as u64).rotate_right((vm.get(op.b) & 63) as u32) as i64,
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Runs the byte code, followed by an exit with a as the status.
+    fn execute(byte_code: &[u8], arithmetic_mode: ArithmeticMode) -> Termination {
+        let binary = Binary {
+            byte_code: [byte_code, &[0xf4, 0]].concat(),
+            arithmetic_mode,
+            ..Default::default()
+        };
+        Vm::init(binary, &[], 1024).unwrap().execute()
+    }
+
+    // Runs the byte code like execute, but collects the events.
+    fn stream(memory: &[u8], byte_code: &[u8]) -> Vec<VmEvent> {
+        let binary = Binary {
+            memory: memory.to_vec(),
+            byte_code: [byte_code, &[0xf4, 0]].concat(),
+            ..Default::default()
+        };
+        let (sender, receiver) = std::sync::mpsc::channel();
+        Vm::init(binary, &[], 1024).unwrap().run_streaming(sender);
+        receiver.into_iter().collect()
+    }
+
+    fn movei(reg: Reg, value: i64) -> Vec<u8> {
+        [&[0xd1, reg as u8][..], &value.to_le_bytes()].concat()
+    }
+
+    fn regs(opcode: u8, a: Reg, b: Reg) -> Vec<u8> {
+        vec![opcode, a as u8 | (b as u8) << 4]
+    }
+
+    fn on(opcode: u8, a: i64, b: i64) -> Vec<u8> {
+        [
+            movei(Reg::A, a),
+            movei(Reg::B, b),
+            regs(opcode, Reg::A, Reg::B),
+        ]
+        .concat()
+    }
+
+    #[test]
+    fn div_overflow_wraps() {
+        let code = on(0xa3, i64::MIN, -1);
+        assert_eq!(
+            execute(&code, ArithmeticMode::Wrapping),
+            Termination::Exited(i64::MIN)
+        );
+        assert!(matches!(
+            execute(&code, ArithmeticMode::Trapping),
+            Termination::Panicked { reason, .. } if reason == "arithmetic overflow"
+        ));
+    }
+
+    #[test]
+    fn rem_overflow_is_zero() {
+        let code = on(0xa4, i64::MIN, -1);
+        assert_eq!(
+            execute(&code, ArithmeticMode::Wrapping),
+            Termination::Exited(0)
+        );
+        assert_eq!(
+            execute(&code, ArithmeticMode::Trapping),
+            Termination::Exited(0)
+        );
+    }
+
+    #[test]
+    fn division_by_zero_panics() {
+        for opcode in [0xa3, 0xa4, 0xa9, 0xaa] {
+            assert!(matches!(
+                execute(&on(opcode, 1, 0), ArithmeticMode::Wrapping),
+                Termination::Panicked { reason, .. } if reason == "division by zero"
+            ));
+        }
+    }
+
+    #[test]
+    fn write_only_supports_stdout_and_stderr() {
+        for (fd, event) in [
+            (1, VmEvent::Print(b"he".to_vec())),
+            (2, VmEvent::Log(b"he".to_vec())),
+        ] {
+            let code = [
+                movei(Reg::A, fd),
+                movei(Reg::B, 0),
+                movei(Reg::C, 2),
+                vec![0xf4, 7],
+            ]
+            .concat();
+            assert_eq!(stream(b"hello", &code), [event, VmEvent::Exit(2)]);
+        }
+        for fd in [0, 3, -1] {
+            let code = [
+                movei(Reg::A, fd),
+                movei(Reg::B, 0),
+                movei(Reg::C, 2),
+                vec![0xf4, 7],
+            ]
+            .concat();
+            assert_eq!(
+                execute(&code, ArithmeticMode::Wrapping),
+                Termination::Exited(0)
+            );
+        }
+    }
+
+    #[test]
+    fn print_writes_exactly_the_message() {
+        // print a b, where a is the offset and b the length of the message
+        let memory = b"garbage.hello.garbage";
+        let code = [movei(Reg::A, 8), movei(Reg::B, 5), vec![0xf4, 1]].concat();
+        assert_eq!(
+            stream(memory, &code),
+            [VmEvent::Print(b"hello".to_vec()), VmEvent::Exit(8)]
+        );
+    }
+
+    #[test]
+    fn breakpoints_are_ignored_when_embedded() {
+        let code = [movei(Reg::A, 3), vec![0xe1], movei(Reg::A, 4)].concat();
+        assert_eq!(
+            execute(&code, ArithmeticMode::Wrapping),
+            Termination::Exited(4)
+        );
+    }
+
+    #[test]
+    fn debugger_steps_and_continues() {
+        let binary = Binary {
+            memory: b"hello".to_vec(),
+            byte_code: vec![0xe1],
+            ..Default::default()
+        };
+        let mut vm = Vm::init(binary, &[], 1024).unwrap();
+        vm.stepping = true;
+        vm.debug_prompt_with(&mut "x 2 100\ns\n".as_bytes());
+        assert!(vm.stepping);
+        vm.debug_prompt_with(&mut "c\n".as_bytes());
+        assert!(!vm.stepping);
+        // After the breakpoint, ip is past the end of the byte code.
+        vm.ip = 1;
+        vm.stepping = true;
+        vm.debug_prompt_with(&mut "".as_bytes());
+        assert!(!vm.stepping);
+    }
+
+    // a = 0, b = 3, c = n, d = 1, and then until c is 0: a += b, a *= d,
+    // a ^= b, c -= d
+    fn arithmetic_loop(n: i64) -> Vec<u8> {
+        let start = 40;
+        [
+            movei(Reg::A, 0),
+            movei(Reg::B, 3),
+            movei(Reg::C, n),
+            movei(Reg::D, 1),
+            regs(0xa0, Reg::A, Reg::B),
+            regs(0xa2, Reg::A, Reg::D),
+            regs(0xb2, Reg::A, Reg::B),
+            regs(0xa1, Reg::C, Reg::D),
+            vec![0x90, Reg::C as u8, 0xf1],
+            (start as u64).to_le_bytes().to_vec(),
+        ]
+        .concat()
+    }
+
+    #[test]
+    fn decoded_instructions_compute_the_same_as_the_byte_code() {
+        let mut a: i64 = 0;
+        for _ in 0..1000 {
+            a = (a.wrapping_add(3).wrapping_mul(1)) ^ 3;
+        }
+        let code = arithmetic_loop(1000);
+        assert_eq!(
+            execute(&code, ArithmeticMode::Wrapping),
+            Termination::Exited(a)
+        );
+    }
+
+    #[test]
+    fn jumping_into_an_instruction_decodes_from_there() {
+        // jump 11, and then movei a with an immediate that is also the byte
+        // code of moveib a 5, syscall exit
+        let code = [
+            vec![0xf0, 11, 0, 0, 0, 0, 0, 0, 0],
+            vec![0xd1, Reg::A as u8, 0xd2, Reg::A as u8, 5, 0xf4, 0, 0, 0, 0],
+        ]
+        .concat();
+        assert_eq!(
+            execute(&code, ArithmeticMode::Wrapping),
+            Termination::Exited(5)
+        );
+    }
+
+    // Run with cargo test --release -- --ignored --nocapture to see how long
+    // a hot loop of 300 million instructions takes.
+    #[test]
+    #[ignore]
+    fn benchmark_arithmetic_loop() {
+        let code = arithmetic_loop(50_000_000);
+        let start = Instant::now();
+        execute(&code, ArithmeticMode::Wrapping);
+        println!("took {:?}", start.elapsed());
+    }
+
+    #[test]
+    fn running_off_the_end_panics() {
+        let binary = Binary {
+            byte_code: movei(Reg::A, 1),
+            ..Default::default()
+        };
+        assert!(matches!(
+            Vm::init(binary, &[], 1024).unwrap().execute(),
+            Termination::Panicked { reason, .. }
+                if reason == "ip a is outside of the byte code, which ends at a"
+        ));
+    }
+
+    #[test]
+    fn truncated_instructions_panic() {
+        for byte_code in [
+            vec![0xf5, 1, 0],
+            vec![0xd1, Reg::A as u8, 1],
+            vec![0xa0, 0x99],
+        ] {
+            let binary = Binary {
+                byte_code,
+                ..Default::default()
+            };
+            assert!(matches!(
+                Vm::init(binary, &[], 1024).unwrap().execute(),
+                Termination::Panicked { .. }
+            ));
+        }
+    }
+
+    #[test]
+    fn printing_outside_of_the_memory_panics() {
+        for (start, len) in [(-1, 2), (1020, 5), (0, -1), (i64::MAX, 1), (1, i64::MAX)] {
+            for number in [1, 2] {
+                let code = [movei(Reg::A, start), movei(Reg::B, len), vec![0xf4, number]].concat();
+                assert!(matches!(
+                    execute(&code, ArithmeticMode::Wrapping),
+                    Termination::Panicked { reason, .. } if reason.starts_with("segmentation fault")
+                ));
+            }
+        }
+    }
+
+    #[test]
+    fn only_signals_below_64_are_raised() {
+        let binary = Binary {
+            byte_code: vec![0xf4, 15, 0xf4, 0],
+            ..Default::default()
+        };
+        let mut vm = Vm::init(binary, &[], 1024).unwrap();
+        for signal in [3, 63, 64, 255] {
+            vm.signals().raise(signal);
+        }
+        assert_eq!(vm.execute(), Termination::Exited(1 << 3 | 1 << 63));
+    }
+
+    #[test]
+    fn checked_arithmetic_reports_overflows_in_st() {
+        let (min, max) = (i64::MIN, i64::MAX);
+        let cases = [
+            (0xab, max, 1, min, 1),
+            (0xab, min, -1, max, 1),
+            (0xab, max, -1, max - 1, 0),
+            (0xac, min, 1, max, 1),
+            (0xac, max, -1, min, 1),
+            (0xac, min, -1, min + 1, 0),
+            (0xad, max, 2, -2, 1),
+            (0xad, min, -1, min, 1),
+            (0xad, min, 1, min, 0),
+        ];
+        for (opcode, a, b, result, overflowed) in cases {
+            // The arithmetic mode doesn't matter, they never panic.
+            for mode in [ArithmeticMode::Wrapping, ArithmeticMode::Trapping] {
+                let code = [on(opcode, a, b), regs(0xd0, Reg::B, Reg::A)].concat();
+                assert_eq!(execute(&code, mode), Termination::Exited(result));
+                let code = [on(opcode, a, b), regs(0xd0, Reg::A, Reg::ST)].concat();
+                assert_eq!(execute(&code, mode), Termination::Exited(overflowed));
+            }
+        }
+    }
+
+    #[test]
+    fn tracks_the_peak_stack_usage() {
+        let push_a = [0xd7, Reg::A.index() as u8];
+        let pop_a = [0xd8, Reg::A.index() as u8];
+        let binary = Binary {
+            byte_code: [
+                &push_a[..],
+                &push_a,
+                &push_a,
+                &pop_a,
+                &pop_a,
+                &pop_a,
+                &[0xf4, 0],
+            ]
+            .concat(),
+            ..Default::default()
+        };
+        let mut vm = Vm::init(binary, &[], 1024).unwrap();
+        vm.track_memory();
+        assert!(matches!(vm.execute(), Termination::Exited(_)));
+        // Besides the three pushed words, the argument slice takes 16 bytes.
+        assert_eq!(vm.peak_stack(), 40);
+    }
+
+    #[test]
+    fn reading_a_register_before_writing_it_in_a_function_panics() {
+        let mut call = vec![0xf2];
+        call.extend_from_slice(&11u64.to_le_bytes());
+        let byte_code = [&call[..], &[0xf4, 0], &regs(0xd0, Reg::A, Reg::E), &[0xf3]].concat();
+        let binary = Binary {
+            byte_code: byte_code.clone(),
+            ..Default::default()
+        };
+        let mut vm = Vm::init(binary, &[], 1024).unwrap();
+        vm.check_uninitialized_reads();
+        match vm.execute() {
+            Termination::Panicked { reason, .. } => {
+                assert_eq!(reason, "read of uninitialized register e at b")
+            }
+            termination => panic!("expected a panic, got {:?}", termination),
+        }
+
+        // Without the check, the function reads whatever e contains.
+        let binary = Binary {
+            byte_code,
+            ..Default::default()
+        };
+        assert!(matches!(
+            Vm::init(binary, &[], 1024).unwrap().execute(),
+            Termination::Exited(0)
+        ));
+    }
+
+    #[test]
+    fn panics_use_the_panic_formatter() {
+        let binary = Binary {
+            byte_code: vec![0xe0],
+            labels: vec![(0, "main".to_string())],
+            ..Default::default()
+        };
+        let mut vm = Vm::init(binary, &[], 1024).unwrap();
+        assert!(vm
+            .format_panic("oh no")
+            .starts_with("oh no\nStack:\n       0 main\n"));
+
+        vm.set_panic_formatter(Box::new(|info| {
+            let frames: Vec<_> = info
+                .backtrace
+                .iter()
+                .map(|frame| format!("\"{}\"", frame.label.unwrap_or("?")))
+                .collect();
+            format!(
+                "{{\"reason\": \"{}\", \"ip\": {}, \"sp\": {}, \"backtrace\": [{}]}}",
+                info.reason,
+                info.ip,
+                info.registers.sp,
+                frames.join(", ")
+            )
+        }));
+        assert_eq!(
+            vm.format_panic("oh no"),
+            "{\"reason\": \"oh no\", \"ip\": 0, \"sp\": 1008, \"backtrace\": [\"main\"]}\n"
+        );
+    }
+
+    #[test]
+    fn pre_step_hooks_see_every_instruction() {
+        let count = std::rc::Rc::new(Cell::new(0));
+        let binary = Binary {
+            byte_code: [&on(0xa0, 1, 2)[..], &[0xf4, 0]].concat(),
+            ..Default::default()
+        };
+        let mut vm = Vm::init(binary, &[], 1024).unwrap();
+        let counter = count.clone();
+        vm.set_pre_step_hook(Box::new(move |_, _, _| {
+            counter.set(counter.get() + 1);
+            HookAction::Continue
+        }));
+        assert_eq!(vm.execute(), Termination::Exited(3));
+        assert_eq!(count.get(), 4);
+    }
+
+    #[test]
+    fn pre_step_hooks_can_skip_and_trap() {
+        let run = |action: fn() -> HookAction| {
+            let binary = Binary {
+                byte_code: [&on(0xa0, 1, 2)[..], &[0xf4, 0]].concat(),
+                ..Default::default()
+            };
+            let mut vm = Vm::init(binary, &[], 1024).unwrap();
+            vm.set_pre_step_hook(Box::new(move |_, instruction, _| match instruction {
+                Instruction::Add(_, _) => action(),
+                _ => HookAction::Continue,
+            }));
+            vm.execute()
+        };
+        assert_eq!(run(|| HookAction::Skip), Termination::Exited(1));
+        assert_eq!(
+            run(|| HookAction::Trap("no adding".to_string())),
+            Termination::Panicked {
+                reason: "no adding".to_string(),
+                backtrace: vec![20],
+            }
+        );
+    }
+
+    #[test]
+    fn custom_syscalls_take_precedence() {
+        let run = |outcome: fn() -> SyscallOutcome| {
+            let binary = Binary {
+                byte_code: [
+                    &movei(Reg::A, 8)[..],
+                    &movei(Reg::B, 2),
+                    &[0xf4, 1, 0xf4, 0],
+                ]
+                .concat(),
+                ..Default::default()
+            };
+            let mut vm = Vm::init(binary, &[], 1024).unwrap();
+            // Replaces print with a syscall that writes "hi" into the memory
+            // and returns its length.
+            vm.register_syscall(
+                1,
+                Box::new(move |memory, registers| {
+                    let start = registers.a as usize;
+                    memory[start..start + 2].copy_from_slice(b"hi");
+                    registers.a = registers.b;
+                    outcome()
+                }),
+            );
+            let termination = vm.execute();
+            assert_eq!(&vm.memory[8..10], b"hi");
+            termination
+        };
+        assert_eq!(run(|| SyscallOutcome::Continue), Termination::Exited(2));
+        assert_eq!(run(|| SyscallOutcome::Exit(7)), Termination::Exited(7));
+        assert!(matches!(
+            run(|| SyscallOutcome::Panic),
+            Termination::Panicked { reason, .. } if reason == "syscall 1 panicked"
+        ));
+    }
+
+    #[test]
+    fn signals_call_the_handler_on_backwards_jumps() {
+        let mut byte_code = movei(Reg::C, 0);
+        // loop: Exits once the handler set c.
+        byte_code.extend(regs(0xd0, Reg::ST, Reg::C));
+        byte_code.extend([&[0xf1][..], &30u64.to_le_bytes()].concat());
+        byte_code.extend([&[0xf0][..], &10u64.to_le_bytes()].concat());
+        // exit
+        byte_code.extend(regs(0xd0, Reg::A, Reg::C));
+        byte_code.extend([0xf4, 0]);
+        // handler: Polls the signals into c.
+        byte_code.extend([0xf4, 15]);
+        byte_code.extend(regs(0xd0, Reg::C, Reg::A));
+        byte_code.push(0xf3);
+
+        let binary = Binary {
+            byte_code,
+            ..Default::default()
+        };
+        let mut vm = Vm::init(binary, &[], 1024).unwrap();
+        vm.set_signal_handler(34);
+        vm.signals().raise(5);
+        assert_eq!(vm.execute(), Termination::Exited(1 << 5));
+        assert_eq!(vm.registers().c, 1 << 5);
+    }
+
+    #[test]
+    fn accesses_fail_in_memory_smaller_than_a_word() {
+        let binary = Binary {
+            byte_code: [
+                &movei(Reg::B, 0)[..],
+                &regs(0xd3, Reg::A, Reg::B),
+                &[0xf4, 0],
+            ]
+            .concat(),
+            ..Default::default()
+        };
+        let mut vm = Vm::init(binary, &[], 16).unwrap();
+        vm.memory.truncate(4);
+        assert!(matches!(vm.execute(), Termination::Panicked { .. }));
+    }
+
+    #[test]
+    fn backward_relative_jumps_behave_like_absolute_ones() {
+        // Sums 3 + 2 + 1 into b by counting a down.
+        let sum = |jump: &[u8]| {
+            let start = [movei(Reg::A, 3), movei(Reg::C, 1), movei(Reg::B, 0)].concat();
+            let body = [
+                regs(0xa0, Reg::B, Reg::A),
+                regs(0xa1, Reg::A, Reg::C),
+                regs(0xd0, Reg::ST, Reg::A),
+            ];
+            let end = regs(0xd0, Reg::A, Reg::B);
+            let binary = Binary {
+                byte_code: [start, body.concat(), jump.to_vec(), end, vec![0xf4, 0]].concat(),
+                ..Default::default()
+            };
+            let mut vm = Vm::init(binary, &[], 1024).unwrap();
+            let jump = vm.instruction_at(36).unwrap();
+            (vm.execute(), jump)
+        };
+        let absolute = [&[0xf1][..], &30u64.to_le_bytes()].concat();
+        let relative = [&[0xf6][..], &(-11i32).to_le_bytes()].concat();
+        assert_eq!(
+            sum(&absolute),
+            (Termination::Exited(6), Instruction::Cjump(30))
+        );
+        assert_eq!(
+            sum(&relative),
+            (Termination::Exited(6), Instruction::Cjumpr(30))
+        );
+    }
+
+    #[test]
+    fn infinite_loops_run_out_of_fuel() {
+        // loop: add a c, jump loop
+        let byte_code = [
+            movei(Reg::C, 1),
+            regs(0xa0, Reg::A, Reg::C),
+            [&[0xf0][..], &10u64.to_le_bytes()].concat(),
+        ]
+        .concat();
+        let binary = Binary {
+            byte_code,
+            ..Default::default()
+        };
+        let mut vm = Vm::init(binary, &[], 1024).unwrap();
+        assert_eq!(vm.run_with_fuel(201), RunOutcome::OutOfFuel);
+        assert_eq!(vm.registers().a, 100);
+        // Running again resumes where the program stopped.
+        assert_eq!(vm.run_with_fuel(20), RunOutcome::OutOfFuel);
+        assert_eq!(vm.registers().a, 110);
+    }
+
+    #[test]
+    fn comparisons_survive_instructions_before_the_is() {
+        let cases = [(0xc1, 0), (0xc2, 1), (0xc3, 0), (0xc4, 1), (0xc5, 0)];
+        for (opcode, result) in cases {
+            // cmp 1 2, then an add that would clobber native flags.
+            let code = [
+                on(0xc0, 1, 2),
+                movei(Reg::C, 5),
+                regs(0xa0, Reg::C, Reg::C),
+                vec![opcode],
+                regs(0xd0, Reg::A, Reg::ST),
+            ]
+            .concat();
+            assert_eq!(
+                execute(&code, ArithmeticMode::Wrapping),
+                Termination::Exited(result)
+            );
+        }
+    }
+
+    #[test]
+    fn loadb_clears_the_high_bytes() {
+        let byte_code = [
+            movei(Reg::A, -1),
+            movei(Reg::B, 0),
+            regs(0xd4, Reg::A, Reg::B),
+            vec![0xf4, 0],
+        ]
+        .concat();
+        let binary = Binary {
+            byte_code,
+            memory: vec![0xab],
+            ..Default::default()
+        };
+        assert_eq!(
+            Vm::init(binary, &[], 1024).unwrap().execute(),
+            Termination::Exited(0xab)
+        );
+    }
+
+    #[test]
+    fn swapping_twice_is_a_no_op() {
+        let swap = regs(0xda, Reg::A, Reg::B);
+        let once = on(0xda, 1, 2);
+        assert_eq!(
+            execute(&once, ArithmeticMode::Wrapping),
+            Termination::Exited(2)
+        );
+        let twice = [on(0xda, 1, 2), swap.clone()].concat();
+        assert_eq!(
+            execute(&twice, ArithmeticMode::Wrapping),
+            Termination::Exited(1)
+        );
+        let code = [on(0xda, 1, 2), swap, regs(0xd0, Reg::A, Reg::B)].concat();
+        assert_eq!(
+            execute(&code, ArithmeticMode::Wrapping),
+            Termination::Exited(2)
+        );
+    }
+
+    #[test]
+    fn rotations_only_use_the_low_six_bits_of_the_amount() {
+        let value = 0x0123_4567_89ab_cdef;
+        let cases = [
+            (0xb7, 4, 0x1234_5678_9abc_def0),
+            (0xb7, 68, 0x1234_5678_9abc_def0),
+            (0xb8, 4, 0xf012_3456_789a_bcdeu64 as i64),
+            (0xb8, 68, 0xf012_3456_789a_bcdeu64 as i64),
+        ];
+        for (opcode, amount, result) in cases {
+            let code = on(opcode, value, amount);
+            assert_eq!(
+                execute(&code, ArithmeticMode::Wrapping),
+                Termination::Exited(result)
+            );
+        }
+    }
+
+    #[test]
+    fn conditional_moves_only_move_when_taken() {
+        // (opcode, st, result)
+        let cases = [(0xdb, 0, 2), (0xdb, 1, 1), (0xdc, 1, 2), (0xdc, 0, 1)];
+        for (opcode, st, result) in cases {
+            let code = [movei(Reg::ST, st), on(opcode, 1, 2)].concat();
+            assert_eq!(
+                execute(&code, ArithmeticMode::Wrapping),
+                Termination::Exited(result)
+            );
+        }
+    }
+
+    #[test]
+    fn instants_dont_decrease() {
+        let code = [
+            vec![0xf4, 16],
+            regs(0xd0, Reg::B, Reg::A),
+            vec![0xf4, 16],
+            regs(0xc0, Reg::A, Reg::B),
+            vec![0xc5],
+            regs(0xd0, Reg::A, Reg::ST),
+        ]
+        .concat();
+        for _ in 0..100 {
+            assert_eq!(
+                execute(&code, ArithmeticMode::Wrapping),
+                Termination::Exited(1)
+            );
+        }
+    }
+
+    #[test]
+    fn popping_an_empty_stack_panics() {
+        // The VM starts with the 16 bytes of the main function's arguments on
+        // the stack.
+        let pop = vec![0xd8, Reg::A as u8];
+        let code = [pop.clone(), pop.clone()].concat();
+        assert_eq!(
+            execute(&code, ArithmeticMode::Wrapping),
+            Termination::Exited(0)
+        );
+        let code = [pop.clone(), pop.clone(), pop].concat();
+        match execute(&code, ArithmeticMode::Wrapping) {
+            Termination::Panicked { reason, .. } => assert!(reason.contains("stack underflow")),
+            termination => panic!("expected a panic, got {:?}", termination),
+        }
+    }
+
+    #[test]
+    fn init_fails_if_the_stack_doesnt_fit() {
+        let binary = Binary {
+            initial_registers: vec![(Reg::SP, 2048)],
+            ..Default::default()
+        };
+        assert_eq!(
+            Vm::init(binary, &[], 1024).unwrap_err(),
+            InitError::InitialSpOutsideOfMemory {
+                sp: 2048,
+                memory_size: 1024
+            }
+        );
+        let args = ["x".repeat(20)];
+        assert_eq!(
+            Vm::init(Binary::default(), &args, 32).unwrap_err(),
+            InitError::ArgumentsDontFit { len: 36, sp: 32 }
+        );
+        assert!(Vm::init(Binary::default(), &args, 64).is_ok());
+    }
+
+    #[test]
+    fn returning_from_main_panics() {
+        match execute(&[0xf3], ArithmeticMode::Wrapping) {
+            Termination::Panicked { reason, .. } => {
+                assert!(reason.contains("return with empty call stack"))
+            }
+            termination => panic!("expected a panic, got {:?}", termination),
+        }
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("soil-{}-{}", std::process::id(), name))
+    }
+
+    // Runs the byte code with the file name at the start of the memory.
+    fn execute_on_file(path: &std::path::Path, byte_code: &[u8]) -> (Termination, Vm) {
+        let name = path.to_str().unwrap().as_bytes();
+        let byte_code = [
+            movei(Reg::A, 0),
+            movei(Reg::B, name.len() as i64),
+            byte_code.to_vec(),
+            vec![0xf4, 0],
+        ]
+        .concat();
+        let binary = Binary {
+            byte_code,
+            memory: name.to_vec(),
+            ..Default::default()
+        };
+        let mut vm = Vm::init(binary, &[], 1024).unwrap();
+        (vm.execute(), vm)
+    }
+
+    // Writes "hi" from offset 512 to the file in d and closes it.
+    fn write_hi_and_close() -> Vec<u8> {
+        [
+            regs(0xd0, Reg::A, Reg::D),
+            movei(Reg::B, 512),
+            movei(Reg::C, 2),
+            vec![0xf4, 7],
+            regs(0xd0, Reg::A, Reg::D),
+            vec![0xf4, 8],
+        ]
+        .concat()
     }
 
-    fn syscall_open_reading(&self) {
-        todo!()
-        // char filename[REGB];
-        // for (int i = 0; i < REGB; i++) filename[i] = mem[REGA + i];
-        // filename[REGB] = 0;
-        // printf("opening filename %s\n", filename);
-        // REGA = (Word)fopen(filename, "r");
+    #[test]
+    fn create_creates_a_file() {
+        let path = temp_path("create");
+        let code = [
+            vec![0xf4, 3],
+            regs(0xd0, Reg::D, Reg::A),
+            movei(Reg::E, 512),
+            movei(Reg::F, 0x6968),
+            regs(0xd5, Reg::E, Reg::F),
+            write_hi_and_close(),
+        ]
+        .concat();
+        // Close sets a to one if it worked.
+        assert_eq!(execute_on_file(&path, &code).0, Termination::Exited(1));
+        assert_eq!(fs::read(&path).unwrap(), b"hi");
+        fs::remove_file(&path).unwrap();
     }
 
-    fn syscall_open_writing(&self) {
-        todo!()
-        // char filename[REGB];
-        // for (int i = 0; i < REGB; i++) filename[i] = mem[REGA + i];
-        // filename[REGB] = 0;
-        // REGA = (Word)fopen(filename, "w+");
+    #[test]
+    fn open_writing_truncates_the_file() {
+        let path = temp_path("open-writing");
+        fs::write(&path, "some old content").unwrap();
+        let code = [
+            vec![0xf4, 5],
+            regs(0xd0, Reg::D, Reg::A),
+            movei(Reg::E, 512),
+            movei(Reg::F, 0x6968),
+            regs(0xd5, Reg::E, Reg::F),
+            write_hi_and_close(),
+        ]
+        .concat();
+        assert_eq!(execute_on_file(&path, &code).0, Termination::Exited(1));
+        assert_eq!(fs::read(&path).unwrap(), b"hi");
+        fs::remove_file(&path).unwrap();
     }
 
-    fn syscall_read(&self) {
-        todo!()
-        // REGA = fread(mem + REGB, 1, REGC, (FILE*)REGA);
+    #[test]
+    fn open_reading_fails_for_missing_files() {
+        let path = temp_path("missing");
+        let code = vec![0xf4, 4];
+        assert_eq!(execute_on_file(&path, &code).0, Termination::Exited(0));
     }
 
-    fn syscall_write(&self) {
-        // TODO: assert that this worked
-        todo!()
-        // fwrite(mem + REGB, 1, REGC, (FILE*)REGA);
+    #[test]
+    fn read_fills_the_buffer() {
+        let path = temp_path("read");
+        fs::write(&path, "hello").unwrap();
+        let code = [
+            vec![0xf4, 4],
+            movei(Reg::B, 512),
+            movei(Reg::C, 16),
+            vec![0xf4, 6],
+        ]
+        .concat();
+        let (termination, vm) = execute_on_file(&path, &code);
+        assert_eq!(termination, Termination::Exited(5));
+        assert_eq!(&vm.memory[512..517], b"hello");
+        fs::remove_file(&path).unwrap();
     }
 
-    fn syscall_close(&self) {
-        // TODO: assert that this worked
-        todo!()
-        // fclose((FILE*)REGA);
+    #[test]
+    fn close_fails_for_files_that_arent_open() {
+        let path = temp_path("close");
+        fs::write(&path, "").unwrap();
+        // Closing the file twice.
+        let code = [
+            vec![0xf4, 4],
+            regs(0xd0, Reg::D, Reg::A),
+            vec![0xf4, 8],
+            regs(0xd0, Reg::A, Reg::D),
+            vec![0xf4, 8],
+        ]
+        .concat();
+        assert_eq!(execute_on_file(&path, &code).0, Termination::Exited(0));
+        fs::remove_file(&path).unwrap();
     }
 }
@@ -0,0 +1,18 @@
+// The soil binary format, the byte code decoder, and what other tools need to
+// work with soil code: relocating byte code and decoding the call stack of
+// native programs. They only need an allocator, so without the default std
+// feature, this library works in no_std environments. With std, it also
+// contains the interpreter, so that hosts can embed it. The compilers live in
+// the soil binary.
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
+pub mod binary;
+pub mod bytecode;
+pub mod call_stack;
+pub mod exit_status;
+#[cfg(feature = "std")]
+pub mod interpreter;
+pub mod relocate;
+pub mod utils;
@@ -1,23 +1,298 @@
-mod binary;
-mod utils;
-// mod interpreter;
+mod args;
+mod call_graph;
 mod compile;
+mod compile_aarch64;
+mod compile_wasm;
+#[cfg(any(test, feature = "run-fasm"))]
+mod conformance;
+mod diff;
+mod disassemble;
+mod reachability;
+#[cfg(feature = "run-fasm")]
+mod run;
 
-use std::io::Read;
-use binary::Binary;
-// use interpreter::Vm;
+use args::Args;
+use binary::{Binary, LoadError};
+use compile::MIN_MEMORY_SIZE;
+use interpreter::{RunOutcome, Vm};
+use soil::{binary, bytecode, call_stack, exit_status, interpreter};
+use std::{
+    fs,
+    io::{Read, Write},
+    path::Path,
+};
 
 fn main() {
-    let mut bytes = vec![];
-    std::io::stdin().lock().read_to_end(&mut bytes).unwrap();
+    let args: Vec<_> = std::env::args().skip(1).collect();
+    let args = match Args::parse(&args) {
+        Ok(args) => args,
+        Err(error) => {
+            eprintln!("error: {}", error);
+            std::process::exit(1);
+        }
+    };
 
-    let binary = Binary::parse(&bytes);
+    // Doesn't need a binary, it brings its own programs.
+    #[cfg(feature = "run-fasm")]
+    if args.check_backends {
+        let all_correct = conformance::check_backends();
+        std::process::exit(if all_correct { 0 } else { 1 });
+    }
 
-    // let args: Vec<_> = std::env::args().collect();
+    if args.diff {
+        let (Some(old), Some(new)) = (&args.path, &args.new_path) else {
+            eprintln!("error: diff needs the paths of two binaries");
+            std::process::exit(1);
+        };
+        let diff = diff::diff(&load(Some(old)), &load(Some(new)));
+        print!("{}", diff);
+        std::process::exit(if diff.is_empty() { 0 } else { 1 });
+    }
 
-    let asm = compile::compile(binary);
-    println!("{}", asm);
+    let binary = load(args.path.as_deref());
 
-    // let mut vm = Vm::init(binary);
-    // vm.run();
+    let mut options = compile::CompileOptions::default();
+    if let Some(alignment) = args.align_functions {
+        if !alignment.is_power_of_two() {
+            eprintln!(
+                "--align-functions needs a power of two, but got {}",
+                alignment
+            );
+            std::process::exit(1);
+        }
+        options.align_functions = Some(alignment);
+    }
+    if let Some(size) = args.memory_size {
+        if size < MIN_MEMORY_SIZE {
+            eprintln!(
+                "error: the memory size needs to be at least {} bytes, but got {}",
+                MIN_MEMORY_SIZE, size
+            );
+            std::process::exit(1);
+        }
+        options.memory_size = size;
+    }
+    options.stack_size = args.stack_size;
+    options.check_bounds = args.safe;
+    options.breakpoints = args.breakpoints;
+    if let Some(emit) = &args.emit {
+        options.emit = match emit.as_str() {
+            "exe" => compile::Emit::Executable,
+            "obj" => compile::Emit::Object,
+            _ => {
+                eprintln!("unknown --emit={}, expected exe or obj", emit);
+                std::process::exit(1);
+            }
+        };
+    }
+    // The interpreter could grow the memory, but it should behave like the
+    // compiled program.
+    if let Err(error) = compile::check_memory_size(&binary, &options) {
+        eprintln!("error: {}", error);
+        std::process::exit(1);
+    }
+    if let Err(error) = binary.check_initial_registers(options.memory_size) {
+        eprintln!("error: {}", error);
+        std::process::exit(1);
+    }
+
+    if args.check {
+        if let Err(error) = binary.validate(options.memory_size) {
+            match error.offset().and_then(|offset| binary.label_for(offset)) {
+                Some(label) => eprintln!("error: {} (in {})", error, label),
+                None => eprintln!("error: {}", error),
+            }
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if args.disasm {
+        if args.bytes {
+            print!("{}", disassemble::disassemble_with_bytes(&binary));
+        } else {
+            print!("{}", disassemble::disassemble(&binary));
+        }
+        return;
+    }
+
+    if args.call_graph {
+        println!("{}", call_graph::call_graph(&binary));
+        return;
+    }
+
+    if let Some(name) = &args.function {
+        match compile::compile_function(&binary, name, &options) {
+            Some(asm) => println!("{}", asm),
+            None => eprintln!("there's no function named {}", name),
+        }
+        return;
+    }
+
+    // --profile interprets the program even without --interpret.
+    if args.interpret || args.profile {
+        let trace_from = match &args.trace_from {
+            Some(label) => match binary.labels.iter().find(|(_, name)| name == label) {
+                Some((offset, _)) => Some(*offset),
+                None => {
+                    eprintln!("error: there's no label named {}", label);
+                    std::process::exit(1);
+                }
+            },
+            None => None,
+        };
+        let mut vm = match Vm::init(binary, &args.program_args, options.memory_size) {
+            Ok(vm) => vm,
+            Err(error) => {
+                eprintln!("error: {}", error);
+                std::process::exit(1);
+            }
+        };
+        if args.profile {
+            vm.profile();
+        }
+        if args.track_memory {
+            vm.track_memory();
+        }
+        if args.check_uninitialized_reads {
+            vm.check_uninitialized_reads();
+        }
+        if let Some(size) = options.stack_size {
+            vm.set_stack_size(size);
+        }
+        if args.trace || trace_from.is_some() {
+            vm.trace(trace_from);
+        }
+        if let Some(path) = &args.dump_memory_on_exit {
+            vm.dump_memory_on_exit(path);
+        }
+        match args.fuel {
+            Some(fuel) => {
+                if vm.run_with_fuel(fuel) == RunOutcome::OutOfFuel {
+                    eprintln!(
+                        "error: the program ran out of fuel after {} instructions",
+                        fuel
+                    );
+                    std::process::exit(1);
+                }
+            }
+            None => vm.run(),
+        }
+        return;
+    }
+
+    for range in reachability::unreachable_code(&binary.byte_code) {
+        let label = binary.label_for(range.start).unwrap_or("(no label)");
+        eprintln!(
+            "warning: unreachable code at {:x}..{:x} in {}",
+            range.start, range.end, label
+        );
+    }
+
+    if let Some(path) = &args.map {
+        if args.target.as_deref() == Some("wasm") {
+            eprintln!("--map is only supported for x86_64 and aarch64");
+            std::process::exit(1);
+        }
+        write_file(
+            Path::new(path),
+            compile::instruction_map(&binary).as_bytes(),
+        );
+    }
+
+    if let Some(target) = &args.target {
+        match target.as_str() {
+            "x86_64" => {}
+            "aarch64" => {
+                if options.emit == compile::Emit::Object {
+                    eprintln!("--emit=obj is only supported for x86_64");
+                    std::process::exit(1);
+                }
+                let asm = compiled(compile_aarch64::compile_aarch64(binary, &options));
+                write_output(&args, format!("{}\n", asm).as_bytes());
+                return;
+            }
+            "wasm" => {
+                if options.emit == compile::Emit::Object {
+                    eprintln!("--emit=obj is only supported for x86_64");
+                    std::process::exit(1);
+                }
+                write_output(
+                    &args,
+                    &compiled(compile_wasm::compile_wasm(binary, &options)),
+                );
+                return;
+            }
+            target => {
+                eprintln!(
+                    "unknown target {:?}, expected x86_64, aarch64, or wasm",
+                    target
+                );
+                std::process::exit(1);
+            }
+        }
+    }
+
+    let asm = compiled(compile::compile_with_options(binary, &options));
+
+    #[cfg(feature = "run-fasm")]
+    if args.run {
+        match run::run(&asm) {
+            Ok(output) => {
+                std::io::stdout().write_all(&output.stdout).unwrap();
+                std::io::stderr().write_all(&output.stderr).unwrap();
+                std::process::exit(output.status());
+            }
+            Err(error) => {
+                eprintln!("{}", error);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    write_output(&args, format!("{}\n", asm).as_bytes());
+}
+
+// Reads the binary from the path or, if there's none or it's "-", from stdin.
+fn load(path: Option<&str>) -> Binary {
+    let binary = match path {
+        None | Some("-") => {
+            let mut bytes = vec![];
+            std::io::stdin().lock().read_to_end(&mut bytes).unwrap();
+            Binary::parse(&bytes).map_err(LoadError::from)
+        }
+        Some(path) => Binary::from_file(path),
+    };
+    binary.unwrap_or_else(|error| {
+        eprintln!("error: {}", error);
+        std::process::exit(1);
+    })
+}
+
+// Writes the compiled program to the file given with -o, creating its
+// directory if needed, or to stdout if there's no -o.
+fn write_output(args: &Args, output: &[u8]) {
+    match &args.output {
+        Some(path) => write_file(Path::new(path), output),
+        None => std::io::stdout().write_all(output).unwrap(),
+    }
+}
+
+fn compiled<T>(result: Result<T, compile::CompileError>) -> T {
+    result.unwrap_or_else(|error| {
+        eprintln!("error: {}", error);
+        std::process::exit(1);
+    })
+}
+
+fn write_file(path: &Path, output: &[u8]) {
+    let result = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => fs::create_dir_all(dir),
+        _ => Ok(()),
+    }
+    .and_then(|_| fs::write(path, output));
+    if let Err(error) = result {
+        eprintln!("error: couldn't write {}: {}", path.display(), error);
+        std::process::exit(1);
+    }
 }
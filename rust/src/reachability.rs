@@ -0,0 +1,89 @@
+use std::{collections::HashMap, ops::Range};
+
+use crate::bytecode::{ByteCode, Instruction};
+
+// Finds instructions that can't be reached from the start of the byte code,
+// for example code after an unconditional jump that nothing jumps to.
+// Returns the byte ranges of consecutive unreachable instructions.
+pub fn unreachable_code(byte_code: &[u8]) -> Vec<Range<usize>> {
+    let mut instructions = vec![];
+    let mut parser = byte_code.byte_code();
+    loop {
+        let offset = parser.cursor;
+        match parser.next() {
+            Some(instruction) => instructions.push((offset, parser.cursor, instruction)),
+            None => break,
+        }
+    }
+    let index_of: HashMap<usize, usize> = instructions
+        .iter()
+        .enumerate()
+        .map(|(index, (offset, _, _))| (*offset, index))
+        .collect();
+
+    let mut reachable = vec![false; instructions.len()];
+    let mut worklist = vec![0];
+    while let Some(offset) = worklist.pop() {
+        let Some(&index) = index_of.get(&offset) else {
+            continue; // Jump into the middle of an instruction or out of the code.
+        };
+        if reachable[index] {
+            continue;
+        }
+        reachable[index] = true;
+        let (_, next, instruction) = &instructions[index];
+        match instruction {
+            Instruction::Jump(target) | Instruction::Jumpr(target) => worklist.push(*target),
+            Instruction::Cjump(target)
+            | Instruction::Call(target)
+            | Instruction::Cjumpr(target)
+            | Instruction::Callr(target) => {
+                worklist.push(*target);
+                worklist.push(*next);
+            }
+            Instruction::Ret | Instruction::Panic => {}
+            _ => worklist.push(*next),
+        }
+    }
+
+    let mut ranges: Vec<Range<usize>> = vec![];
+    for (index, (offset, next, _)) in instructions.iter().enumerate() {
+        if reachable[index] {
+            continue;
+        }
+        match ranges.last_mut() {
+            Some(range) if range.end == *offset => range.end = *next,
+            _ => ranges.push(*offset..*next),
+        }
+    }
+    ranges
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn with_target(opcode: u8, target: u64) -> Vec<u8> {
+        [&[opcode][..], &target.to_le_bytes()].concat()
+    }
+
+    #[test]
+    fn code_after_a_jump_is_unreachable() {
+        // jump 11, nop, nop, ret
+        let byte_code = [with_target(0xf0, 11), vec![0x00, 0x00, 0xf3]].concat();
+        assert_eq!(unreachable_code(&byte_code), [Range { start: 9, end: 11 }]);
+    }
+
+    #[test]
+    fn cjumps_and_calls_fall_through() {
+        // cjump 11, nop, nop, call 22, ret, nop, ret
+        let byte_code = [
+            with_target(0xf1, 11),
+            vec![0x00, 0x00],
+            with_target(0xf2, 22),
+            vec![0xf3, 0x00, 0xf3],
+        ]
+        .concat();
+        assert_eq!(unreachable_code(&byte_code), [Range { start: 21, end: 22 }]);
+    }
+}
@@ -0,0 +1,100 @@
+use alloc::vec::Vec;
+use core::fmt::{self, Display, Formatter};
+
+use crate::bytecode::{ByteCode, Instruction};
+
+#[derive(Debug)]
+pub enum RelocateError {
+    // The instruction at offset would jump to a negative (or overflowing)
+    // position after relocation.
+    InvalidTarget { offset: usize, target: usize },
+}
+
+impl Display for RelocateError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            RelocateError::InvalidTarget { offset, target } => write!(
+                f,
+                "relocating the jump at byte {} would move its target {} out of range",
+                offset, target
+            ),
+        }
+    }
+}
+
+// Returns a copy of the byte code with the targets of all Jump, Cjump and
+// Call instructions shifted by delta, so that the code still works when it's
+// placed delta bytes further into a larger byte code. Instruction lengths stay
+// the same, so everything else is copied verbatim. Relative jumps and calls
+// don't need to change.
+pub fn relocate(byte_code: &[u8], delta: i64) -> Result<Vec<u8>, RelocateError> {
+    let mut relocated = byte_code.to_vec();
+    let mut parser = byte_code.byte_code();
+    loop {
+        let offset = parser.cursor;
+        let target = match parser.next() {
+            Some(Instruction::Jump(target)) => target,
+            Some(Instruction::Cjump(target)) => target,
+            Some(Instruction::Call(target)) => target,
+            Some(_) => continue,
+            None => break,
+        };
+        let new_target = (target as i64)
+            .checked_add(delta)
+            .filter(|it| *it >= 0)
+            .ok_or(RelocateError::InvalidTarget { offset, target })?;
+        relocated[offset + 1..offset + 9].copy_from_slice(&new_target.to_le_bytes());
+    }
+    Ok(relocated)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Calls a function that returns 42, which the program then exits with.
+    fn program() -> Vec<u8> {
+        let mut byte_code = [&[0xf2][..], &11u64.to_le_bytes(), &[0xf4, 0]].concat();
+        byte_code.extend([&[0xd1, 2][..], &42i64.to_le_bytes(), &[0xf3]].concat());
+        byte_code
+    }
+
+    #[test]
+    fn shifts_absolute_targets() {
+        let relocated = relocate(&program(), 9).unwrap();
+        assert_eq!(relocated[..9], [&[0xf2][..], &20u64.to_le_bytes()].concat());
+        assert_eq!(relocated[9..], program()[9..]);
+        assert_eq!(relocate(&relocated, -9).unwrap(), program());
+    }
+
+    #[test]
+    fn rejects_negative_targets() {
+        assert!(matches!(
+            relocate(&program(), -12),
+            Err(RelocateError::InvalidTarget {
+                offset: 0,
+                target: 11
+            })
+        ));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn relocated_code_runs_at_its_new_offset() {
+        use crate::{
+            binary::Binary,
+            interpreter::{Termination, Vm},
+        };
+
+        // Jumps over itself to the relocated program.
+        let prefix = [&[0xf0][..], &9u64.to_le_bytes()].concat();
+        let binary = Binary {
+            byte_code: [prefix, relocate(&program(), 9).unwrap()].concat(),
+            ..Default::default()
+        };
+        assert_eq!(
+            Vm::init(binary, &[], 1024).unwrap().execute(),
+            Termination::Exited(42)
+        );
+    }
+}
@@ -0,0 +1,137 @@
+use std::{
+    fmt::{self, Display, Formatter},
+    fs, io,
+    os::unix::process::ExitStatusExt,
+    process::Command,
+};
+
+#[derive(Debug)]
+pub enum RunError {
+    // There's no fasm executable on the PATH.
+    FasmNotFound,
+    // fasm rejected the generated code. Contains what it printed.
+    Assemble(String),
+    Io(io::Error),
+}
+
+impl Display for RunError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            RunError::FasmNotFound => write!(f, "couldn't find fasm, is it installed?"),
+            RunError::Assemble(output) => write!(f, "fasm failed:\n{}", output),
+            RunError::Io(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl From<io::Error> for RunError {
+    fn from(error: io::Error) -> Self {
+        RunError::Io(error)
+    }
+}
+
+pub struct RunOutput {
+    // None if the program was killed by a signal.
+    pub exit_code: Option<i32>,
+    // The signal that killed the program, if any.
+    pub signal: Option<i32>,
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+}
+
+impl RunOutput {
+    // The status to exit with to pass on how the program ended. Like shells
+    // do, a program killed by a signal is reported as 128 plus the signal.
+    pub fn status(&self) -> i32 {
+        match (self.exit_code, self.signal) {
+            (Some(code), _) => code,
+            (None, Some(signal)) => 128 + signal,
+            (None, None) => 1,
+        }
+    }
+}
+
+// Assembles the output of compile using an installed fasm and runs the
+// resulting executable, capturing its output.
+pub fn run(asm: &str) -> Result<RunOutput, RunError> {
+    let dir = std::env::temp_dir().join(format!("soil-{}", std::process::id()));
+    fs::create_dir_all(&dir)?;
+    let source = dir.join("program.asm");
+    let executable = dir.join("program");
+    fs::write(&source, asm)?;
+
+    let assembled = match Command::new("fasm").arg(&source).arg(&executable).output() {
+        Ok(output) => output,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => {
+            return Err(RunError::FasmNotFound)
+        }
+        Err(error) => return Err(error.into()),
+    };
+    if !assembled.status.success() {
+        let mut message = String::from_utf8_lossy(&assembled.stdout).into_owned();
+        message.push_str(&String::from_utf8_lossy(&assembled.stderr));
+        return Err(RunError::Assemble(message));
+    }
+
+    let output = Command::new(&executable).output()?;
+    fs::remove_dir_all(&dir)?;
+    Ok(RunOutput {
+        exit_code: output.status.code(),
+        signal: output.status.signal(),
+        stdout: output.stdout,
+        stderr: output.stderr,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        binary::Binary,
+        compile::{compile_with_options, CompileOptions},
+    };
+
+    fn output(exit_code: Option<i32>, signal: Option<i32>) -> RunOutput {
+        RunOutput {
+            exit_code,
+            signal,
+            stdout: vec![],
+            stderr: vec![],
+        }
+    }
+
+    #[test]
+    fn status_passes_on_exit_codes_and_signals() {
+        assert_eq!(output(Some(3), None).status(), 3);
+        // SIGSEGV
+        assert_eq!(output(None, Some(11)).status(), 139);
+    }
+
+    #[test]
+    fn runs_compiled_programs() {
+        // print "hi", exit 0
+        let movei = |reg: u8, value: u64| [&[0xd1, reg][..], &value.to_le_bytes()].concat();
+        let byte_code = [
+            movei(2, 0),
+            movei(3, 2),
+            vec![0xf4, 1],
+            movei(2, 0),
+            vec![0xf4, 0],
+        ]
+        .concat();
+        let binary = Binary {
+            byte_code,
+            memory: b"hi".to_vec(),
+            ..Default::default()
+        };
+        let asm = compile_with_options(binary, &CompileOptions::default()).unwrap();
+        let output = match run(&asm) {
+            Ok(output) => output,
+            // Not every machine that runs the tests has fasm.
+            Err(RunError::FasmNotFound) => return,
+            Err(error) => panic!("{}", error),
+        };
+        assert_eq!(output.stdout, b"hi");
+        assert_eq!(output.status(), 0);
+    }
+}
@@ -1,20 +1,17 @@
-use std::mem;
-
 use extension_trait::extension_trait;
 
+// Words are stored in little-endian byte order at arbitrary (possibly
+// unaligned) positions.
 #[extension_trait]
 pub impl WordFromByteSlice for [u8] {
     fn word_at(&self, pos: usize) -> i64 {
-        if pos >= self.len() + 8 {
-            panic!("out of bounds");
-        }
-        let pointer: &i64 = unsafe { mem::transmute(&self[pos]) };
-        *pointer
+        let bytes = self.get(pos..pos.saturating_add(8)).expect("out of bounds");
+        i64::from_le_bytes(bytes.try_into().unwrap())
     }
-    fn word_at_mut(&mut self, pos: usize) -> &mut i64 {
-        if pos >= self.len() + 8 {
-            panic!("out of bounds");
-        }
-        unsafe { mem::transmute(&mut self[pos]) }
+    fn set_word_at(&mut self, pos: usize, word: i64) {
+        let bytes = self
+            .get_mut(pos..pos.saturating_add(8))
+            .expect("out of bounds");
+        bytes.copy_from_slice(&word.to_le_bytes());
     }
 }
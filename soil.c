@@ -202,7 +202,7 @@ void run_single(void) {
       mem[REG1] = REG2; ip += 2; break;
     }
     case 0xd7: SP -= 8; *(Word*)(mem + SP) = REG1; ip += 2; break; // push
-    case 0xd8: REG1 = *(Word*)(mem + SP); SP += 8; ip += 2; break; // pop
+    case 0xd8: { Word value = *(Word*)(mem + SP); SP += 8; REG1 = value; ip += 2; break; } // pop
     case 0xf0: ip = *(Word*)(byte_code + ip + 1); break; // jump
     case 0xf1: { // cjump
       if (ST != 0) ip = *(Word*)(byte_code + ip + 1); else ip += 9; break;
@@ -897,8 +897,10 @@ compile_push:   eat_reg_into_dil
 compile_pop:    eat_reg_into_dil
                 mov sil, 0 ; sp
                 emit_mov_soil_mem_of_rdp_plus_soil dil, sil ; mov <a>, [rbp + r8]
+                cmp dil, 0 ; popping into sp keeps the loaded value
+                je .done
                 emit_add_r8_8                 ; add r8, 8
-                instruction_end
+  .done:        instruction_end
 compile_jump:   eat_word r14
                 emit_jmp r14                  ; jmp <target>
                 instruction_end
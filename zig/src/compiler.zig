@@ -201,7 +201,8 @@ const Compiler = struct {
             0xd8 => { // pop
                 const reg = try self.parse_reg();
                 try machine_code.emit_mov_soil_mem_of_rbp_plus_soil(reg, .sp); // mov <to>, [rbp + r8]
-                try machine_code.emit_add_r8_8(); // add r8, 8
+                // Popping into sp keeps the loaded value.
+                if (reg != .sp) try machine_code.emit_add_r8_8(); // add r8, 8
             },
             0xf0 => { // jump
                 const target: usize = @intCast(try self.eat_word());